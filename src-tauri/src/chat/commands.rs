@@ -1,19 +1,30 @@
 use crate::chat::models::{MessageStatus, MessageType};
 // src-tauri/src/chat/commands.rs
 use crate::error::Error;
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use mongodb::Database;
 use uuid::Uuid;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tauri::{Manager, State};
-use tracing::{debug, info};
+use tauri::{AppHandle, Manager, State};
+use tracing::{debug, info, warn};
 
 use super::db::ChatDatabase;
+use super::keystore::KEYSTORE_FILE_NAME;
 use super::manager::ChatManager;
-use super::models::{Conversation, Message, NewConversation, NewMessage, ConversationType};
-use super::websocket::{WebSocketConfig, WebSocketState};
+use super::mentions::mentioned_user_ids;
+use super::preferences::ConversationFilter;
+use super::search::{SearchQuery, SearchResults};
+use super::models::{Conversation, ConversationPreferences, DeleteMode, Draft, GroupInfoUpdate, GroupInvite, GroupPolicy, GroupRole, KeyBundle, Message, MessageReceipt, MessagesPage, MessageThread, NewConversation, NewMessage, ConversationType, PinnedMessage, ReactionCount, SafetyNumber, ScheduledMessage};
+use super::websocket::{ChatEvent, ChatEventType, WebSocketConfig, WebSocketState};
 use crate::auth::commands::validate_token;
 
+/// 定时消息调度器的轮询间隔（秒）
+const SCHEDULED_DISPATCH_INTERVAL_SECS: u64 = 15;
+
+/// 过期消息清理的间隔（秒）
+const EXPIRED_SWEEP_INTERVAL_SECS: u64 = 30;
+
 /// 应用状态，包含聊天管理器
 pub struct ChatState {
     pub chat_manager: Arc<ChatManager>,
@@ -25,7 +36,23 @@ pub fn init(app: &mut tauri::App, db: Arc<Database>) -> Result<(), Box<dyn std::
     
     // 创建聊天数据库和管理器
     let chat_db = ChatDatabase::new(db.as_ref().clone());
-    let chat_manager = Arc::new(ChatManager::new(chat_db));
+    let mut chat_manager = ChatManager::new(chat_db);
+    
+    // 可通过环境变量配置消息撤回时限
+    if let Some(secs) = std::env::var("MESSAGE_RETRACT_WINDOW_SECS").ok().and_then(|v| v.parse::<i64>().ok()) {
+        chat_manager = chat_manager.with_retract_window(Duration::seconds(secs));
+    }
+    
+    // 身份密钥保存在应用数据目录下的加密密钥库中，由前端在登录后用口令解锁
+    match app.path().app_data_dir() {
+        Ok(dir) => chat_manager = chat_manager.with_keystore_path(dir.join(KEYSTORE_FILE_NAME)),
+        Err(e) => warn!("Failed to resolve app data directory, encrypted chats are unavailable: {}", e),
+    }
+    let chat_manager = Arc::new(chat_manager);
+    
+    spawn_legacy_receipt_migration(chat_manager.clone());
+    spawn_scheduled_message_dispatcher(app.handle().clone(), chat_manager.clone());
+    spawn_expired_message_sweeper(chat_manager.clone());
     
     // 创建并管理应用状态
     let chat_state = ChatState {
@@ -38,14 +65,277 @@ pub fn init(app: &mut tauri::App, db: Arc<Database>) -> Result<(), Box<dyn std::
     Ok(())
 }
 
+/// 通过WebSocket广播聊天事件，数据库已更新，推送失败时只记录日志
+async fn broadcast_chat_event(websocket_state: &WebSocketState, event: ChatEvent) {
+    if let Err(e) = websocket_state.send_chat_event(event).await {
+        warn!("Failed to broadcast chat event: {}", e);
+    }
+}
+
+/// 在后台为没有回执的旧消息补建回执
+///
+/// 补建完成前，旧消息的状态显示沿用原来的共享状态字段。
+fn spawn_legacy_receipt_migration(chat_manager: Arc<ChatManager>) {
+    tauri::async_runtime::spawn(async move {
+        match chat_manager.migrate_legacy_receipts().await {
+            Ok(0) => {}
+            Ok(count) => info!("Created receipts for {} legacy messages", count),
+            Err(e) => warn!("Failed to create receipts for legacy messages: {}", e),
+        }
+    });
+}
+
+/// 在后台定期清理已过期的阅后即焚消息
+///
+/// 读取消息时也会过滤已过期的内容，清理间隔只影响数据在数据库中保留的时间。
+fn spawn_expired_message_sweeper(chat_manager: Arc<ChatManager>) {
+    tauri::async_runtime::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(EXPIRED_SWEEP_INTERVAL_SECS));
+        loop {
+            ticker.tick().await;
+            
+            if let Err(e) = chat_manager.purge_expired_messages().await {
+                warn!("Failed to purge expired messages: {}", e);
+            }
+        }
+    });
+}
+
+/// 被提及的用户收到高优先级通知，即使会话已静音
+async fn broadcast_mentions(websocket_state: &WebSocketState, message: &Message) {
+    let mentioned = mentioned_user_ids(&message.mentions);
+    if mentioned.is_empty() {
+        return;
+    }
+    
+    let event = ChatEvent::new(
+        ChatEventType::Mention,
+        &message.sender_id,
+        &message.conversation_id,
+        Some(&message.id),
+        serde_json::json!({ "mentionedUserIds": mentioned, "priority": "high" }),
+    );
+    broadcast_chat_event(websocket_state, event).await;
+}
+
+/// 通知其他成员群聊已解散，会话数据已删除，事件中不带消息ID
+async fn broadcast_group_dissolved(websocket_state: &WebSocketState, user_id: &str, conversation_id: &str) {
+    let event = ChatEvent::new(
+        ChatEventType::GroupDissolved,
+        user_id,
+        conversation_id,
+        None,
+        serde_json::json!({}),
+    );
+    broadcast_chat_event(websocket_state, event).await;
+}
+
+/// 在后台定期发送到期的定时消息
+///
+/// 定时消息保存在数据库中，应用重启后调度器会继续发送；离线期间到期的消息在启动后立即发送。
+fn spawn_scheduled_message_dispatcher(app_handle: AppHandle, chat_manager: Arc<ChatManager>) {
+    tauri::async_runtime::spawn(async move {
+        match chat_manager.requeue_interrupted_scheduled_messages().await {
+            Ok(0) => {}
+            Ok(count) => info!("Requeued {} interrupted scheduled messages", count),
+            Err(e) => warn!("Failed to requeue interrupted scheduled messages: {}", e),
+        }
+        
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(SCHEDULED_DISPATCH_INTERVAL_SECS));
+        loop {
+            ticker.tick().await;
+            
+            let sent = match chat_manager.dispatch_due_scheduled_messages().await {
+                Ok(sent) => sent,
+                Err(e) => {
+                    warn!("Failed to dispatch scheduled messages: {}", e);
+                    continue;
+                }
+            };
+            
+            // WebSocket 状态在聊天模块初始化之后才注册
+            let Some(websocket_state) = app_handle.try_state::<WebSocketState>() else { continue };
+            for message in sent {
+                let event = ChatEvent::new(
+                    ChatEventType::ScheduledMessageSent,
+                    &message.sender_id,
+                    &message.conversation_id,
+                    Some(&message.id),
+                    serde_json::json!({}),
+                );
+                broadcast_chat_event(&websocket_state, event).await;
+                broadcast_mentions(&websocket_state, &message).await;
+            }
+        }
+    });
+}
+
+/// 用口令解锁本地密钥库，返回本设备的标识
+#[tauri::command]
+pub async fn unlock_keystore(
+    passphrase: String,
+    state: State<'_, ChatState>,
+) -> Result<String, Error> {
+    info!("Unlocking chat keystore");
+    state.chat_manager.unlock_keystore(&passphrase).await
+}
+
+/// 锁定本地密钥库，例如在用户退出登录时
+#[tauri::command]
+pub async fn lock_keystore(
+    state: State<'_, ChatState>,
+) -> Result<(), Error> {
+    info!("Locking chat keystore");
+    state.chat_manager.lock_keystore().await
+}
+
+/// 发布当前用户在本设备上的公钥包，登录并解锁密钥库后调用
+#[tauri::command]
+pub async fn publish_key_bundle(
+    token: String,
+    state: State<'_, ChatState>,
+) -> Result<KeyBundle, Error> {
+    let claims = validate_token(&token)
+        .map_err(|_| Error::Authentication("Invalid token".to_string()))?;
+    
+    info!("Publishing key bundle for user {}", claims.sub);
+    state.chat_manager.publish_key_bundle(&claims.sub).await
+}
+
+/// 获取用户最近发布的公钥包
+#[tauri::command]
+pub async fn get_key_bundle(
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<KeyBundle, Error> {
+    debug!("Getting key bundle for user {}", user_id);
+    state.chat_manager.get_key_bundle(&user_id).await
+}
+
+/// 获取用户与会话中另一成员的安全码（数字和二维码内容），用于当面或通过其他渠道比对
+#[tauri::command]
+pub async fn get_safety_number(
+    conversation_id: String,
+    user_id: String,
+    peer_id: String,
+    state: State<'_, ChatState>,
+) -> Result<SafetyNumber, Error> {
+    debug!("Getting safety number between {} and {} in conversation {}", user_id, peer_id, conversation_id);
+    
+    state.chat_manager.get_safety_number(&conversation_id, &user_id, &peer_id).await
+}
+
+/// 比对安全码一致后把对方标记为已验证，safety_number 为比对过的数字安全码或扫描到的二维码内容
+#[tauri::command]
+pub async fn mark_peer_verified(
+    conversation_id: String,
+    user_id: String,
+    peer_id: String,
+    safety_number: String,
+    state: State<'_, ChatState>,
+) -> Result<SafetyNumber, Error> {
+    info!("User {} marking peer {} as verified", user_id, peer_id);
+    
+    state.chat_manager.mark_peer_verified(&conversation_id, &user_id, &peer_id, &safety_number).await
+}
+
 /// 获取用户的所有会话
 #[tauri::command]
 pub async fn get_conversations(
     user_id: String,
+    filter: Option<ConversationFilter>,
     state: State<'_, ChatState>,
 ) -> Result<Vec<Conversation>, Error> {
     info!("Getting conversations for user: {}", user_id);
-    state.chat_manager.get_user_conversations(&user_id).await
+    state.chat_manager.get_user_conversations(&user_id, &filter.unwrap_or_default()).await
+}
+
+/// 归档或取消归档会话
+#[tauri::command]
+pub async fn set_conversation_archived(
+    conversation_id: String,
+    user_id: String,
+    archived: bool,
+    keep_archived: Option<bool>,
+    state: State<'_, ChatState>,
+) -> Result<ConversationPreferences, Error> {
+    debug!("Setting archived={} for conversation {}", archived, conversation_id);
+    
+    state.chat_manager.set_conversation_archived(&conversation_id, &user_id, archived, keep_archived).await
+}
+
+/// 静音会话，muted_until 为空表示一直静音
+#[tauri::command]
+pub async fn mute_conversation(
+    conversation_id: String,
+    user_id: String,
+    muted_until: Option<DateTime<Utc>>,
+    state: State<'_, ChatState>,
+) -> Result<ConversationPreferences, Error> {
+    debug!("Muting conversation {} until {:?}", conversation_id, muted_until);
+    
+    state.chat_manager.mute_conversation(&conversation_id, &user_id, muted_until).await
+}
+
+/// 取消会话静音
+#[tauri::command]
+pub async fn unmute_conversation(
+    conversation_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<ConversationPreferences, Error> {
+    debug!("Unmuting conversation {}", conversation_id);
+    
+    state.chat_manager.unmute_conversation(&conversation_id, &user_id).await
+}
+
+/// 把会话置顶到列表顶部或取消置顶
+#[tauri::command]
+pub async fn pin_conversation(
+    conversation_id: String,
+    user_id: String,
+    pinned: bool,
+    state: State<'_, ChatState>,
+) -> Result<ConversationPreferences, Error> {
+    debug!("Setting pinned={} for conversation {}", pinned, conversation_id);
+    
+    state.chat_manager.pin_conversation(&conversation_id, &user_id, pinned).await
+}
+
+/// 设置会话的自定义排序值
+#[tauri::command]
+pub async fn set_conversation_sort_order(
+    conversation_id: String,
+    user_id: String,
+    sort_order: Option<i64>,
+    state: State<'_, ChatState>,
+) -> Result<ConversationPreferences, Error> {
+    debug!("Setting sort order {:?} for conversation {}", sort_order, conversation_id);
+    
+    state.chat_manager.set_conversation_sort_order(&conversation_id, &user_id, sort_order).await
+}
+
+/// 开启或关闭会话的严格验证，开启后已验证成员的身份密钥变化时禁止发送
+#[tauri::command]
+pub async fn set_strict_verification(
+    conversation_id: String,
+    user_id: String,
+    enabled: bool,
+    state: State<'_, ChatState>,
+) -> Result<ConversationPreferences, Error> {
+    debug!("Setting strict verification={} for conversation {}", enabled, conversation_id);
+    
+    state.chat_manager.set_strict_verification(&conversation_id, &user_id, enabled).await
+}
+
+/// 判断收到的消息是否需要通知用户（静音会话中只有 @提及会通知）
+#[tauri::command]
+pub async fn should_notify_message(
+    message_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<bool, Error> {
+    state.chat_manager.should_notify(&message_id, &user_id).await
 }
 
 /// 创建新的会话
@@ -71,16 +361,40 @@ pub async fn create_conversation(
     
     info!("Creating new conversation with {} participants", all_participants.len());
     
+    // 群聊的创建者成为群主
+    let roles = if conversationType == ConversationType::Group {
+        HashMap::from([(user_id.clone(), GroupRole::Owner)])
+    } else {
+        HashMap::new()
+    };
+    
     let new_conversation = NewConversation {
         name,
         conversation_type: conversationType,
         participants: all_participants,
         encryption_enabled: encryptionEnabled,
+        roles,
     };
     
     state.chat_manager.create_conversation(new_conversation).await
 }
 
+/// 获取与另一用户的私聊，不存在时创建
+#[tauri::command]
+pub async fn get_or_create_direct_conversation(
+    token: String,
+    peer_id: String,
+    encryption_enabled: Option<bool>,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    let claims = validate_token(&token)
+        .map_err(|_| Error::Authentication("Invalid token".to_string()))?;
+    
+    debug!("Getting direct conversation between {} and {}", claims.sub, peer_id);
+    
+    state.chat_manager.get_or_create_direct_conversation(&claims.sub, &peer_id, encryption_enabled.unwrap_or(false)).await
+}
+
 /// 获取会话详情
 #[tauri::command]
 pub async fn get_conversation(
@@ -99,7 +413,10 @@ pub async fn send_message(
     sender_id: String,
     content_type: super::models::MessageType,
     media_url: Option<String>,
+    reply_to: Option<String>,
+    thread_root_id: Option<String>,
     state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
 ) -> Result<Message, Error> {
     debug!("Sending message from {} to conversation {}", sender_id, conversation_id);
     
@@ -110,9 +427,157 @@ pub async fn send_message(
         content_type,
         encrypted: false,
         media_url,
+        reply_to,
+        thread_root_id,
+        forwarded_from: None,
+        mentions: Vec::new(),
+        system_event: None,
+    };
+    
+    let message = state.chat_manager.send_message(new_message, &sender_id).await?;
+    
+    // 消息已发出，输入框中的草稿随之清除
+    if let Err(e) = state.chat_manager.clear_draft(&message.conversation_id, &sender_id).await {
+        warn!("Failed to clear draft after sending: {}", e);
+    }
+    
+    broadcast_mentions(&websocket_state, &message).await;
+    
+    Ok(message)
+}
+
+/// 保存输入框中未发送的草稿
+#[tauri::command]
+pub async fn save_draft(
+    conversation_id: String,
+    user_id: String,
+    content: String,
+    media_urls: Option<Vec<String>>,
+    state: State<'_, ChatState>,
+) -> Result<Option<Draft>, Error> {
+    debug!("Saving draft for user {} in conversation {}", user_id, conversation_id);
+    
+    state.chat_manager.save_draft(&conversation_id, &user_id, content, media_urls.unwrap_or_default()).await
+}
+
+/// 获取会话中的草稿
+#[tauri::command]
+pub async fn get_draft(
+    conversation_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Option<Draft>, Error> {
+    debug!("Getting draft for user {} in conversation {}", user_id, conversation_id);
+    
+    state.chat_manager.get_draft(&conversation_id, &user_id).await
+}
+
+/// 清除会话中的草稿
+#[tauri::command]
+pub async fn clear_draft(
+    conversation_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<(), Error> {
+    debug!("Clearing draft for user {} in conversation {}", user_id, conversation_id);
+    
+    state.chat_manager.clear_draft(&conversation_id, &user_id).await
+}
+
+/// 安排定时消息
+#[tauri::command]
+pub async fn schedule_message(
+    conversation_id: String,
+    content: String,
+    sender_id: String,
+    content_type: super::models::MessageType,
+    media_url: Option<String>,
+    reply_to: Option<String>,
+    thread_root_id: Option<String>,
+    send_at: DateTime<Utc>,
+    state: State<'_, ChatState>,
+) -> Result<ScheduledMessage, Error> {
+    debug!("Scheduling message from {} to conversation {} at {}", sender_id, conversation_id, send_at);
+    
+    let new_message = NewMessage {
+        conversation_id,
+        sender_id: sender_id.clone(),
+        content,
+        content_type,
+        encrypted: false,
+        media_url,
+        reply_to,
+        thread_root_id,
+        forwarded_from: None,
+        mentions: Vec::new(),
+        system_event: None,
     };
     
-    state.chat_manager.send_message(new_message, &sender_id).await
+    state.chat_manager.schedule_message(new_message, &sender_id, send_at).await
+}
+
+/// 获取用户尚未发送的定时消息，可按会话过滤
+#[tauri::command]
+pub async fn get_scheduled_messages(
+    user_id: String,
+    conversation_id: Option<String>,
+    state: State<'_, ChatState>,
+) -> Result<Vec<ScheduledMessage>, Error> {
+    debug!("Getting scheduled messages for user {}", user_id);
+    
+    state.chat_manager.get_scheduled_messages(&user_id, conversation_id.as_deref()).await
+}
+
+/// 修改定时消息的内容或发送时间
+#[tauri::command]
+pub async fn update_scheduled_message(
+    scheduled_id: String,
+    user_id: String,
+    content: Option<String>,
+    send_at: Option<DateTime<Utc>>,
+    state: State<'_, ChatState>,
+) -> Result<ScheduledMessage, Error> {
+    debug!("Updating scheduled message {} by user {}", scheduled_id, user_id);
+    
+    state.chat_manager.update_scheduled_message(&scheduled_id, &user_id, content, send_at).await
+}
+
+/// 取消定时消息
+#[tauri::command]
+pub async fn cancel_scheduled_message(
+    scheduled_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<(), Error> {
+    debug!("Cancelling scheduled message {} by user {}", scheduled_id, user_id);
+    
+    state.chat_manager.cancel_scheduled_message(&scheduled_id, &user_id).await
+}
+
+/// 设置会话的阅后即焚时长（秒），为空表示关闭
+#[tauri::command]
+pub async fn set_message_ttl(
+    conversation_id: String,
+    user_id: String,
+    message_ttl: Option<u64>,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    debug!("Setting message TTL of conversation {} to {:?}", conversation_id, message_ttl);
+    
+    state.chat_manager.set_message_ttl(&conversation_id, &user_id, message_ttl).await
+}
+
+/// 获取提及当前用户的消息
+#[tauri::command]
+pub async fn get_mentions(
+    user_id: String,
+    limit: Option<u32>,
+    before_id: Option<String>,
+    state: State<'_, ChatState>,
+) -> Result<Vec<Message>, Error> {
+    debug!("Getting mentions for user {}", user_id);
+    
+    state.chat_manager.get_mentions(&user_id, limit, before_id.as_deref()).await
 }
 
 /// 获取会话消息历史
@@ -123,7 +588,7 @@ pub async fn get_messages(
     limit: Option<u32>,
     before_id: Option<String>,
     state: State<'_, ChatState>,
-) -> Result<Vec<Message>, Error> {
+) -> Result<MessagesPage, Error> {
     debug!("Getting messages for conversation {} by user {}", conversation_id, user_id);
     
     state.chat_manager.get_messages(
@@ -134,6 +599,212 @@ pub async fn get_messages(
     ).await
 }
 
+/// 获取话题（根消息及回复）
+#[tauri::command]
+pub async fn get_thread(
+    root_id: String,
+    user_id: String,
+    limit: Option<u32>,
+    before_id: Option<String>,
+    state: State<'_, ChatState>,
+) -> Result<MessageThread, Error> {
+    debug!("Getting thread {} by user {}", root_id, user_id);
+    
+    state.chat_manager.get_thread(
+        &root_id,
+        &user_id,
+        limit,
+        before_id.as_deref(),
+    ).await
+}
+
+/// 将消息转发到调用者所在的其他会话
+#[tauri::command]
+pub async fn forward_messages(
+    user_id: String,
+    message_ids: Vec<String>,
+    target_conversation_ids: Vec<String>,
+    state: State<'_, ChatState>,
+) -> Result<Vec<Message>, Error> {
+    debug!("Forwarding {} messages to {} conversations by user {}",
+           message_ids.len(), target_conversation_ids.len(), user_id);
+    
+    state.chat_manager.forward_messages(&user_id, &message_ids, &target_conversation_ids).await
+}
+
+/// 在用户的会话中搜索消息
+#[tauri::command]
+pub async fn search_messages(
+    user_id: String,
+    query: SearchQuery,
+    state: State<'_, ChatState>,
+) -> Result<SearchResults, Error> {
+    debug!("Searching messages for user {}", user_id);
+    state.chat_manager.search_messages(&user_id, query).await
+}
+
+/// 对消息添加表情回应
+#[tauri::command]
+pub async fn react_to_message(
+    message_id: String,
+    user_id: String,
+    emoji: String,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<Vec<ReactionCount>, Error> {
+    debug!("User {} reacting to message {}", user_id, message_id);
+    
+    let message = state.chat_manager.react_to_message(&message_id, &user_id, &emoji).await?;
+    
+    let event = ChatEvent::new(
+        ChatEventType::MessageReaction,
+        &user_id,
+        &message.conversation_id,
+        Some(&message.id),
+        serde_json::json!({ "emoji": emoji, "action": "added", "reactionCounts": message.reaction_counts }),
+    );
+    broadcast_chat_event(&websocket_state, event).await;
+    
+    Ok(message.reaction_counts)
+}
+
+/// 移除表情回应
+#[tauri::command]
+pub async fn remove_reaction(
+    message_id: String,
+    user_id: String,
+    emoji: String,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<Vec<ReactionCount>, Error> {
+    debug!("User {} removing reaction from message {}", user_id, message_id);
+    
+    let message = state.chat_manager.remove_reaction(&message_id, &user_id, &emoji).await?;
+    
+    let event = ChatEvent::new(
+        ChatEventType::MessageReaction,
+        &user_id,
+        &message.conversation_id,
+        Some(&message.id),
+        serde_json::json!({ "emoji": emoji, "action": "removed", "reactionCounts": message.reaction_counts }),
+    );
+    broadcast_chat_event(&websocket_state, event).await;
+    
+    Ok(message.reaction_counts)
+}
+
+/// 置顶消息
+#[tauri::command]
+pub async fn pin_message(
+    message_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<PinnedMessage, Error> {
+    debug!("Pinning message {} by user {}", message_id, user_id);
+    
+    let (conversation, pin) = state.chat_manager.pin_message(&message_id, &user_id).await?;
+    
+    let event = ChatEvent::new(
+        ChatEventType::MessagePinned,
+        &user_id,
+        &conversation.id,
+        Some(&message_id),
+        serde_json::json!({ "pinnedBy": pin.pinned_by, "pinnedAt": pin.pinned_at }),
+    );
+    broadcast_chat_event(&websocket_state, event).await;
+    
+    Ok(pin)
+}
+
+/// 取消置顶消息
+#[tauri::command]
+pub async fn unpin_message(
+    message_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<(), Error> {
+    debug!("Unpinning message {} by user {}", message_id, user_id);
+    
+    let conversation = state.chat_manager.unpin_message(&message_id, &user_id).await?;
+    
+    let event = ChatEvent::new(
+        ChatEventType::MessageUnpinned,
+        &user_id,
+        &conversation.id,
+        Some(&message_id),
+        serde_json::json!({}),
+    );
+    broadcast_chat_event(&websocket_state, event).await;
+    
+    Ok(())
+}
+
+/// 获取会话中置顶的消息
+#[tauri::command]
+pub async fn get_pinned_messages(
+    conversation_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Vec<PinnedMessage>, Error> {
+    debug!("Getting pinned messages in conversation {}", conversation_id);
+    state.chat_manager.get_pinned_messages(&conversation_id, &user_id).await
+}
+
+/// 编辑消息
+#[tauri::command]
+pub async fn edit_message(
+    message_id: String,
+    user_id: String,
+    content: String,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<Message, Error> {
+    debug!("Editing message {} by user {}", message_id, user_id);
+    
+    let message = state.chat_manager.edit_message(&message_id, &user_id, content).await?;
+    
+    let event = ChatEvent::new(
+        ChatEventType::MessageEdited,
+        &user_id,
+        &message.conversation_id,
+        Some(&message.id),
+        serde_json::json!({ "editedAt": message.edited_at }),
+    );
+    broadcast_chat_event(&websocket_state, event).await;
+    
+    Ok(message)
+}
+
+/// 删除消息（仅对自己隐藏或为所有人撤回）
+#[tauri::command]
+pub async fn delete_message(
+    message_id: String,
+    user_id: String,
+    mode: DeleteMode,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<(), Error> {
+    debug!("Deleting message {} by user {} ({:?})", message_id, user_id, mode);
+    
+    let message = state.chat_manager.delete_message(&message_id, &user_id, mode).await?;
+    
+    // 仅对自己删除不需要通知其他参与者
+    if mode == DeleteMode::ForEveryone {
+        let event = ChatEvent::new(
+            ChatEventType::MessageRetracted,
+            &user_id,
+            &message.conversation_id,
+            Some(&message.id),
+            serde_json::json!({ "retractedAt": message.retracted_at }),
+        );
+        broadcast_chat_event(&websocket_state, event).await;
+    }
+    
+    Ok(())
+}
+
 /// 将消息标记为已读
 #[tauri::command]
 pub async fn mark_message_read(
@@ -161,6 +832,17 @@ pub async fn mark_conversation_read(
     ).await
 }
 
+/// 获取消息的送达/已读回执
+#[tauri::command]
+pub async fn get_message_receipts(
+    message_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Vec<MessageReceipt>, Error> {
+    debug!("Getting receipts for message {} by user {}", message_id, user_id);
+    state.chat_manager.get_message_receipts(&message_id, &user_id).await
+}
+
 /// 将会话中的消息标记为已送达
 #[tauri::command]
 pub async fn mark_conversation_delivered(
@@ -213,6 +895,94 @@ pub async fn add_group_member(
     ).await
 }
 
+/// 退出群聊，群主可以指定接任者
+#[tauri::command]
+pub async fn leave_group(
+    conversation_id: String,
+    user_id: String,
+    new_owner_id: Option<String>,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<(), Error> {
+    debug!("User {} leaving group {}", user_id, conversation_id);
+    
+    let dissolved = state.chat_manager.leave_group(&conversation_id, &user_id, new_owner_id.as_deref()).await?;
+    if dissolved {
+        broadcast_group_dissolved(&websocket_state, &user_id, &conversation_id).await;
+    }
+    
+    Ok(())
+}
+
+/// 删除会话：私聊对调用者隐藏，群聊由群主解散
+#[tauri::command]
+pub async fn delete_conversation(
+    conversation_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<(), Error> {
+    debug!("User {} deleting conversation {}", user_id, conversation_id);
+    
+    let dissolved = state.chat_manager.delete_conversation(&conversation_id, &user_id).await?;
+    if dissolved {
+        broadcast_group_dissolved(&websocket_state, &user_id, &conversation_id).await;
+    }
+    
+    Ok(())
+}
+
+/// 创建群邀请链接
+#[tauri::command]
+pub async fn create_group_invite(
+    conversation_id: String,
+    user_id: String,
+    expires_in_secs: Option<u64>,
+    max_uses: Option<u32>,
+    state: State<'_, ChatState>,
+) -> Result<GroupInvite, Error> {
+    debug!("Creating invite for group {}", conversation_id);
+    
+    state.chat_manager.create_group_invite(&conversation_id, &user_id, expires_in_secs, max_uses).await
+}
+
+/// 获取群聊中仍可使用的邀请
+#[tauri::command]
+pub async fn get_group_invites(
+    conversation_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Vec<GroupInvite>, Error> {
+    debug!("Getting invites for group {}", conversation_id);
+    
+    state.chat_manager.get_group_invites(&conversation_id, &user_id).await
+}
+
+/// 撤销群邀请
+#[tauri::command]
+pub async fn revoke_group_invite(
+    conversation_id: String,
+    invite_id: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<(), Error> {
+    debug!("Revoking invite {} of group {}", invite_id, conversation_id);
+    
+    state.chat_manager.revoke_group_invite(&conversation_id, &invite_id, &user_id).await
+}
+
+/// 通过邀请链接加入群聊
+#[tauri::command]
+pub async fn join_group_via_invite(
+    token: String,
+    user_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    debug!("User {} joining group via invite", user_id);
+    
+    state.chat_manager.join_group_via_invite(&token, &user_id).await
+}
+
 /// 从群聊中移除成员
 #[tauri::command]
 pub async fn remove_group_member(
@@ -230,6 +1000,87 @@ pub async fn remove_group_member(
     ).await
 }
 
+/// 把成员设为管理员
+#[tauri::command]
+pub async fn promote_member(
+    conversation_id: String,
+    user_id: String,
+    member_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    debug!("Promoting member {} in group {}", member_id, conversation_id);
+    
+    state.chat_manager.promote_member(&conversation_id, &user_id, &member_id).await
+}
+
+/// 取消成员的管理员身份
+#[tauri::command]
+pub async fn demote_member(
+    conversation_id: String,
+    user_id: String,
+    member_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    debug!("Demoting member {} in group {}", member_id, conversation_id);
+    
+    state.chat_manager.demote_member(&conversation_id, &user_id, &member_id).await
+}
+
+/// 转让群主
+#[tauri::command]
+pub async fn transfer_ownership(
+    conversation_id: String,
+    user_id: String,
+    new_owner_id: String,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    debug!("Transferring ownership of group {} to {}", conversation_id, new_owner_id);
+    
+    state.chat_manager.transfer_ownership(&conversation_id, &user_id, &new_owner_id).await
+}
+
+/// 修改群权限策略
+#[tauri::command]
+pub async fn update_group_policy(
+    conversation_id: String,
+    user_id: String,
+    policy: GroupPolicy,
+    state: State<'_, ChatState>,
+) -> Result<Conversation, Error> {
+    debug!("Updating policy of group {}", conversation_id);
+    
+    state.chat_manager.update_group_policy(&conversation_id, &user_id, policy).await
+}
+
+/// 修改群名称、简介和头像
+#[tauri::command]
+pub async fn update_group_info(
+    conversation_id: String,
+    user_id: String,
+    update: GroupInfoUpdate,
+    state: State<'_, ChatState>,
+    websocket_state: State<'_, WebSocketState>,
+) -> Result<Conversation, Error> {
+    debug!("Updating info of group {}", conversation_id);
+    
+    let conversation = state.chat_manager.update_group_info(&conversation_id, &user_id, update).await?;
+    
+    let event = ChatEvent::new(
+        ChatEventType::GroupInfoUpdated,
+        &user_id,
+        &conversation_id,
+        conversation.last_message.as_ref().map(|m| m.id.as_str()),
+        serde_json::json!({
+            "name": conversation.name,
+            "description": conversation.description,
+            "avatarUrl": conversation.avatar_url,
+        }),
+    );
+    broadcast_chat_event(&websocket_state, event).await;
+    
+    Ok(conversation)
+}
+
 /// 获取用户未读消息数
 #[tauri::command]
 pub async fn get_unread_count(
@@ -343,8 +1194,25 @@ pub async fn send_chat_message(
         content_type: message_type,
         timestamp: Utc::now(),
         status: Some(MessageStatus::Sent),
+        receipts: HashMap::new(),
         encrypted: encrypted.unwrap_or(false),
         media_url,
+        edited_at: None,
+        revisions: Vec::new(),
+        deleted_for: Vec::new(),
+        retracted_at: None,
+        reply_to: None,
+        thread_root_id: None,
+        thread_reply_count: 0,
+        reply_preview: None,
+        forwarded_from: None,
+        mentions: Vec::new(),
+        reactions: Vec::new(),
+        system_event: None,
+        expires_at: None,
+        reaction_counts: Vec::new(),
+        undecryptable: false,
+        key_changes: Vec::new(),
     };
     
     // 发送消息
@@ -1,20 +1,27 @@
 // db.rs
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use futures::TryStreamExt;
 use mongodb::{
-    bson::{doc, DateTime as BsonDateTime},
-    options::{FindOptions, UpdateOptions},
+    bson::{doc, Bson, Document},
+    options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReplaceOptions, ReturnDocument, UpdateOptions},
     Collection, Database,
 };
 use uuid::Uuid;
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
 
 use crate::error::Error;
-use super::models::{Conversation, Message, MessageStatus, NewConversation, NewMessage};
+use super::models::{Conversation, ConversationPreferences, ConversationType, Draft, GroupInvite, GroupPolicy, GroupRole, KeyBundle, Mention, Message, MessageRevision, MessageStatus, NewConversation, NewMessage, PinnedMessage, Reaction, ReceiptEntry, ScheduledMessage, ScheduledMessageStatus, SenderKeyDistribution};
+use super::search::SearchQuery;
 
 pub struct ChatDatabase {
     pub messages_collection: Collection<Message>,
     pub conversations_collection: Collection<Conversation>,
+    pub scheduled_messages_collection: Collection<ScheduledMessage>,
+    pub drafts_collection: Collection<Draft>,
+    pub preferences_collection: Collection<ConversationPreferences>,
+    pub invites_collection: Collection<GroupInvite>,
+    pub key_bundles_collection: Collection<KeyBundle>,
+    pub sender_keys_collection: Collection<SenderKeyDistribution>,
 }
 
 impl ChatDatabase {
@@ -22,23 +29,18 @@ impl ChatDatabase {
         Self {
             messages_collection: db.collection("messages"),
             conversations_collection: db.collection("conversations"),
+            scheduled_messages_collection: db.collection("scheduled_messages"),
+            drafts_collection: db.collection("drafts"),
+            preferences_collection: db.collection("conversation_preferences"),
+            invites_collection: db.collection("group_invites"),
+            key_bundles_collection: db.collection("key_bundles"),
+            sender_keys_collection: db.collection("sender_key_distributions"),
         }
     }
 
     // 会话相关方法
     pub async fn create_conversation(&self, new_conversation: NewConversation) -> Result<Conversation, Error> {
-        let now = Utc::now();
-        
-        let conversation = Conversation {
-            id: Uuid::new_v4().to_string(),
-            name: new_conversation.name,
-            conversation_type: new_conversation.conversation_type,
-            participants: new_conversation.participants,
-            created_at: now,
-            updated_at: now,
-            last_message: None,
-            encryption_enabled: new_conversation.encryption_enabled,
-        };
+        let conversation = build_conversation(new_conversation);
         
         self.conversations_collection
             .insert_one(&conversation, None)
@@ -59,10 +61,76 @@ impl ChatDatabase {
         Ok(conversation)
     }
 
+    /// 查找两个用户之间的私聊
+    ///
+    /// 去重之前创建的私聊没有 direct_key，按参与者查找最早的一个并补写标识，之后按标识查找。
+    pub async fn find_direct_conversation(&self, user_a: &str, user_b: &str) -> Result<Option<Conversation>, Error> {
+        let direct_key = Conversation::direct_key(user_a, user_b);
+        
+        if let Some(conversation) = self.conversations_collection
+            .find_one(doc! { "directKey": &direct_key }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to find direct conversation: {}", e)))? {
+            return Ok(Some(conversation));
+        }
+        
+        let filter = doc! {
+            "conversationType": "Direct",
+            "participants": { "$all": [user_a, user_b], "$size": 2 },
+            "directKey": { "$exists": false }
+        };
+        let options = FindOneOptions::builder()
+            .sort(doc! { "createdAt": 1 })
+            .build();
+        
+        let legacy = self.conversations_collection
+            .find_one(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to find direct conversation: {}", e)))?;
+        
+        let Some(mut conversation) = legacy else {
+            return Ok(None);
+        };
+        
+        let update = doc! { "$set": { "directKey": &direct_key } };
+        match self.conversations_collection.update_one(doc! { "id": &conversation.id }, update, None).await {
+            Ok(_) => {
+                conversation.direct_key = Some(direct_key);
+                Ok(Some(conversation))
+            }
+            // 并发补写时另一个私聊已经占用了标识
+            Err(e) if is_duplicate_key_error(&e) => {
+                self.conversations_collection
+                    .find_one(doc! { "directKey": &direct_key }, None)
+                    .await
+                    .map_err(|e| Error::Database(format!("Failed to find direct conversation: {}", e)))
+            }
+            Err(e) => Err(Error::Database(format!("Failed to update direct conversation: {}", e))),
+        }
+    }
+
+    /// 创建私聊，同一对用户的私聊已存在（并发创建）时返回已有的会话
+    pub async fn create_direct_conversation(&self, new_conversation: NewConversation) -> Result<Conversation, Error> {
+        let conversation = build_conversation(new_conversation);
+        let (user_a, user_b) = match (&conversation.direct_key, conversation.participants.as_slice()) {
+            (Some(_), [user_a, user_b]) => (user_a, user_b),
+            _ => return Err(Error::Validation("Direct conversations must have exactly 2 participants".to_string())),
+        };
+        
+        match self.conversations_collection.insert_one(&conversation, None).await {
+            Ok(_) => Ok(conversation),
+            Err(e) if is_duplicate_key_error(&e) => {
+                self.find_direct_conversation(user_a, user_b).await?
+                    .ok_or_else(|| Error::Database(format!("Failed to create conversation: {}", e)))
+            }
+            Err(e) => Err(Error::Database(format!("Failed to create conversation: {}", e))),
+        }
+    }
+
     pub async fn get_conversations_for_user(&self, user_id: &str) -> Result<Vec<Conversation>, Error> {
         let filter = doc! { "participants": { "$in": [user_id] } };
         let options = FindOptions::builder()
-            .sort(doc! { "updated_at": -1 })
+            .sort(doc! { "updatedAt": -1 })
             .build();
         
         let cursor = self.conversations_collection
@@ -81,14 +149,12 @@ impl ChatDatabase {
     pub async fn update_conversation_last_message(&self, conversation_id: &str, message: &Message) -> Result<(), Error> {
         let filter = doc! { "id": conversation_id };
         
-        // 使用辅助函数转换时间
-        let bson_now = chrono_to_bson_datetime(Utc::now());
-        
+        // Conversation 以 camelCase 序列化，字段名和时间格式需与之保持一致
         let update = doc! {
             "$set": {
-                "last_message": mongodb::bson::to_document(message)
+                "lastMessage": mongodb::bson::to_document(message)
                     .map_err(|e| Error::Database(format!("Failed to serialize message: {}", e)))?,
-                "updated_at": bson_now
+                "updatedAt": chrono_to_bson(Utc::now())?
             }
         };
         
@@ -100,6 +166,62 @@ impl ChatDatabase {
         Ok(())
     }
 
+    /// 如果会话的最后一条消息就是该消息，则用新内容替换（不改变会话排序）
+    pub async fn refresh_conversation_last_message(&self, conversation_id: &str, message: &Message) -> Result<bool, Error> {
+        let filter = doc! { "id": conversation_id, "lastMessage.id": &message.id };
+        let update = doc! {
+            "$set": {
+                "lastMessage": mongodb::bson::to_document(message)
+                    .map_err(|e| Error::Database(format!("Failed to serialize message: {}", e)))?
+            }
+        };
+        
+        let result = self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to refresh last message: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+
+    /// 置顶消息，已置顶或达到上限时不做修改，返回是否置顶成功
+    pub async fn pin_message(&self, conversation_id: &str, pin: &PinnedMessage, max_pins: usize) -> Result<bool, Error> {
+        // 用数组下标判断是否已达上限，保证并发置顶时也不会超出
+        let filter = doc! {
+            "id": conversation_id,
+            "pinnedMessages.messageId": { "$ne": &pin.message_id },
+            format!("pinnedMessages.{}", max_pins - 1): { "$exists": false }
+        };
+        let update = doc! {
+            "$push": {
+                "pinnedMessages": mongodb::bson::to_document(pin)
+                    .map_err(|e| Error::Database(format!("Failed to serialize pinned message: {}", e)))?
+            }
+        };
+        
+        let result = self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to pin message: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+
+    /// 取消置顶，返回消息之前是否处于置顶状态
+    pub async fn unpin_message(&self, conversation_id: &str, message_id: &str) -> Result<bool, Error> {
+        let filter = doc! { "id": conversation_id };
+        let update = doc! {
+            "$pull": { "pinnedMessages": { "messageId": message_id } }
+        };
+        
+        let result = self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to unpin message: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+
     // 消息相关方法
     pub async fn save_message(&self, new_message: NewMessage) -> Result<Message, Error> {
         // 首先检查会话是否存在
@@ -107,6 +229,13 @@ impl ChatDatabase {
             .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", new_message.conversation_id)))?;
         
         let now = Utc::now();
+        
+        // 为发送者以外的每个参与者建立回执
+        let receipts = conversation.participants.iter()
+            .filter(|p| **p != new_message.sender_id)
+            .map(|p| (p.clone(), ReceiptEntry { status: MessageStatus::Sent, updated_at: now }))
+            .collect();
+        
         let message = Message {
             id: Uuid::new_v4().to_string(),
             conversation_id: new_message.conversation_id,
@@ -115,8 +244,26 @@ impl ChatDatabase {
             content_type: new_message.content_type,
             timestamp: now,
             status: Some(MessageStatus::Sent),
+            receipts,
             encrypted: new_message.encrypted,
             media_url: new_message.media_url,
+            edited_at: None,
+            revisions: Vec::new(),
+            deleted_for: Vec::new(),
+            retracted_at: None,
+            reply_to: new_message.reply_to,
+            thread_root_id: new_message.thread_root_id,
+            thread_reply_count: 0,
+            reply_preview: None,
+            forwarded_from: new_message.forwarded_from,
+            mentions: new_message.mentions,
+            reactions: Vec::new(),
+            system_event: new_message.system_event,
+            // 会话之后修改保留时长不影响已发送的消息
+            expires_at: conversation.message_ttl.map(|ttl| now + Duration::seconds(ttl as i64)),
+            reaction_counts: Vec::new(),
+            undecryptable: false,
+            key_changes: Vec::new(),
         };
         
         self.messages_collection
@@ -127,9 +274,29 @@ impl ChatDatabase {
         Ok(message)
     }
 
-    pub async fn get_messages(&self, conversation_id: &str, limit: Option<u32>, before_id: Option<&str>) -> Result<Vec<Message>, Error> {
-        let mut filter = doc! { "conversation_id": conversation_id };
+    pub async fn get_messages(&self, conversation_id: &str, user_id: &str, limit: Option<u32>, before_id: Option<&str>) -> Result<Vec<Message>, Error> {
+        // 排除用户自己删除的消息和话题内的回复，撤回的消息作为墓碑保留以保证分页连续
+        let filter = doc! {
+            "conversation_id": conversation_id,
+            "deleted_for": { "$ne": user_id },
+            "thread_root_id": Bson::Null
+        };
+        
+        self.find_messages_page(filter, limit, before_id).await
+    }
+
+    /// 获取话题内的回复（分页）
+    pub async fn get_thread_replies(&self, root_id: &str, user_id: &str, limit: Option<u32>, before_id: Option<&str>) -> Result<Vec<Message>, Error> {
+        let filter = doc! {
+            "thread_root_id": root_id,
+            "deleted_for": { "$ne": user_id }
+        };
         
+        self.find_messages_page(filter, limit, before_id).await
+    }
+
+    /// 按时间倒序分页查询消息，返回结果按时间正序排列
+    async fn find_messages_page(&self, mut filter: Document, limit: Option<u32>, before_id: Option<&str>) -> Result<Vec<Message>, Error> {
         if let Some(before_id) = before_id {
             // 获取指定消息的时间戳
             let before_message = self.messages_collection
@@ -138,11 +305,12 @@ impl ChatDatabase {
                 .map_err(|e| Error::Database(format!("Failed to get reference message: {}", e)))?
                 .ok_or_else(|| Error::NotFound(format!("Reference message not found: {}", before_id)))?;
             
-            // 使用辅助函数转换时间
-            let bson_timestamp = chrono_to_bson_datetime(before_message.timestamp);
-            filter.insert("timestamp", doc! { "$lt": bson_timestamp });
+            filter.insert("timestamp", doc! { "$lt": chrono_to_bson(before_message.timestamp)? });
         }
         
+        // 已过期但尚未被清理的消息同样不返回
+        filter.insert("$or", not_expired_conditions()?);
+        
         let limit_value = limit.unwrap_or(50) as i64;
         let options = FindOptions::builder()
             .sort(doc! { "timestamp": -1 })
@@ -165,14 +333,159 @@ impl ChatDatabase {
         Ok(messages)
     }
 
-    pub async fn update_message_status(&self, message_id: &str, user_id: &str, status: MessageStatus) -> Result<(), Error> {
-        // 确保只有消息的接收者可以更新状态
+    /// 批量获取消息
+    pub async fn get_messages_by_ids(&self, message_ids: &[String]) -> Result<Vec<Message>, Error> {
+        let filter = doc! {
+            "id": { "$in": message_ids },
+            "$or": not_expired_conditions()?
+        };
+        
+        let cursor = self.messages_collection
+            .find(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get messages: {}", e)))?;
+        
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect messages: {}", e)))
+    }
+
+    /// 在未加密的会话中查找符合条件的候选消息，按时间倒序，最多返回 max_candidates 条
+    pub async fn search_messages(
+        &self,
+        conversation_ids: &[String],
+        user_id: &str,
+        query: &SearchQuery,
+        max_candidates: i64,
+    ) -> Result<Vec<Message>, Error> {
+        let mut filter = doc! {
+            "conversation_id": { "$in": conversation_ids },
+            "encrypted": false,
+            "deleted_for": { "$ne": user_id },
+            "retracted_at": Bson::Null,
+            "$or": not_expired_conditions()?
+        };
+        
+        // 每个关键词都需命中，使用不区分大小写的正则以支持中文
+        let term_filters: Vec<Document> = query.terms().iter()
+            .map(|term| doc! { "content": { "$regex": regex::escape(term), "$options": "i" } })
+            .collect();
+        if !term_filters.is_empty() {
+            filter.insert("$and", term_filters);
+        }
+        
+        if let Some(sender_id) = &query.sender_id {
+            filter.insert("sender_id", sender_id);
+        }
+        
+        if let Some(content_type) = &query.content_type {
+            filter.insert("content_type", mongodb::bson::to_bson(content_type)
+                .map_err(|e| Error::Database(format!("Failed to serialize content type: {}", e)))?);
+        }
+        
+        let mut time_range = Document::new();
+        if let Some(from) = query.from {
+            time_range.insert("$gte", chrono_to_bson(from)?);
+        }
+        if let Some(to) = query.to {
+            time_range.insert("$lte", chrono_to_bson(to)?);
+        }
+        if !time_range.is_empty() {
+            filter.insert("timestamp", time_range);
+        }
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "timestamp": -1 })
+            .limit(max_candidates)
+            .build();
+        
+        let cursor = self.messages_collection
+            .find(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to search messages: {}", e)))?;
+        
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect search results: {}", e)))
+    }
+
+    /// 获取指定会话中提及用户的消息（分页）
+    pub async fn get_mentions(&self, user_id: &str, conversation_ids: &[String], limit: Option<u32>, before_id: Option<&str>) -> Result<Vec<Message>, Error> {
+        let filter = doc! {
+            "mentions.user_id": user_id,
+            "conversation_id": { "$in": conversation_ids },
+            "deleted_for": { "$ne": user_id },
+            "retracted_at": Bson::Null
+        };
+        
+        self.find_messages_page(filter, limit, before_id).await
+    }
+
+    /// 话题根消息的回复数加一
+    pub async fn increment_thread_reply_count(&self, root_id: &str) -> Result<(), Error> {
+        self.messages_collection
+            .update_one(doc! { "id": root_id }, doc! { "$inc": { "thread_reply_count": 1 } }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update thread reply count: {}", e)))?;
+        
+        Ok(())
+    }
+
+    pub async fn get_message(&self, message_id: &str) -> Result<Option<Message>, Error> {
+        self.messages_collection
+            .find_one(doc! { "id": message_id }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get message: {}", e)))
+    }
+
+    /// 替换消息内容和提及，并把旧内容追加到修订历史中
+    pub async fn update_message_content(
+        &self,
+        message_id: &str,
+        content: &str,
+        mentions: &[Mention],
+        revision: &MessageRevision,
+    ) -> Result<(), Error> {
+        let filter = doc! { "id": message_id };
+        let update = doc! {
+            "$set": {
+                "content": content,
+                "mentions": mongodb::bson::to_bson(mentions)
+                    .map_err(|e| Error::Database(format!("Failed to serialize mentions: {}", e)))?,
+                "edited_at": chrono_to_bson(revision.edited_at)?,
+            },
+            "$push": {
+                "revisions": mongodb::bson::to_document(revision)
+                    .map_err(|e| Error::Database(format!("Failed to serialize revision: {}", e)))?
+            }
+        };
+        
+        let result = self.messages_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update message content: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(Error::NotFound(format!("Message not found: {}", message_id)));
+        }
+        
+        Ok(())
+    }
+
+    /// 获取消息及其会话，并确保用户是会话参与者
+    pub async fn get_message_for_participant(&self, message_id: &str, user_id: &str) -> Result<(Message, Conversation), Error> {
         let message = self.messages_collection
             .find_one(doc! { "id": message_id }, None)
             .await
             .map_err(|e| Error::Database(format!("Failed to find message: {}", e)))?
             .ok_or_else(|| Error::NotFound(format!("Message not found: {}", message_id)))?;
         
+        if message.is_expired(Utc::now()) {
+            return Err(Error::NotFound(format!("Message not found: {}", message_id)));
+        }
+        
         // 获取会话以检查用户是否是参与者
         let conversation = self.get_conversation(&message.conversation_id).await?
             .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", message.conversation_id)))?;
@@ -185,83 +498,1269 @@ impl ChatDatabase {
             )));
         }
         
+        Ok((message, conversation))
+    }
+
+    pub async fn update_message_status(&self, message_id: &str, user_id: &str, status: MessageStatus) -> Result<(), Error> {
+        // 确保只有消息的接收者可以更新状态
+        let (message, _) = self.get_message_for_participant(message_id, user_id).await?;
+        
         // 不允许发送者更改已读状态
         if message.sender_id == user_id && status == MessageStatus::Read {
             return Err(Error::Validation("Sender cannot mark their own message as read".to_string()));
         }
         
-        // 更新消息状态
-        let filter = doc! { "id": message_id };
+        // 只更新调用者自己的回执，且状态只能前进
+        let mut filter = receipt_progress_filter(user_id, status)?;
+        filter.insert("id", message_id);
+        
+        self.messages_collection
+            .update_one(filter, receipt_update(user_id, status)?, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update message status: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 获取消息的所有回执
+    pub async fn get_message_receipts(&self, message_id: &str) -> Result<HashMap<String, ReceiptEntry>, Error> {
+        let message = self.get_message(message_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Message not found: {}", message_id)))?;
+        
+        Ok(message.receipts)
+    }
+
+    /// 添加表情回应，同一用户对同一表情只记录一次
+    pub async fn add_reaction(&self, message_id: &str, reaction: &Reaction) -> Result<(), Error> {
+        let filter = doc! {
+            "id": message_id,
+            "reactions": {
+                "$not": { "$elemMatch": { "user_id": &reaction.user_id, "emoji": &reaction.emoji } }
+            }
+        };
         let update = doc! {
-            "$set": { "status": status.to_string() }
+            "$push": {
+                "reactions": mongodb::bson::to_document(reaction)
+                    .map_err(|e| Error::Database(format!("Failed to serialize reaction: {}", e)))?
+            }
         };
         
         self.messages_collection
             .update_one(filter, update, None)
             .await
-            .map_err(|e| Error::Database(format!("Failed to update message status: {}", e)))?;
+            .map_err(|e| Error::Database(format!("Failed to add reaction: {}", e)))?;
         
         Ok(())
     }
 
-    pub async fn delete_message(&self, message_id: &str) -> Result<(), Error> {
+    /// 移除用户的表情回应
+    pub async fn remove_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<bool, Error> {
         let filter = doc! { "id": message_id };
+        let update = doc! {
+            "$pull": { "reactions": { "user_id": user_id, "emoji": emoji } }
+        };
         
         let result = self.messages_collection
-            .delete_one(filter, None)
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to remove reaction: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+
+    /// 仅对指定用户隐藏消息
+    pub async fn hide_message_for_user(&self, message_id: &str, user_id: &str) -> Result<(), Error> {
+        let filter = doc! { "id": message_id };
+        let update = doc! {
+            "$addToSet": { "deleted_for": user_id }
+        };
+        
+        let result = self.messages_collection
+            .update_one(filter, update, None)
             .await
             .map_err(|e| Error::Database(format!("Failed to delete message: {}", e)))?;
         
-        if result.deleted_count == 0 {
+        if result.matched_count == 0 {
             return Err(Error::NotFound(format!("Message not found: {}", message_id)));
         }
         
         Ok(())
     }
-    
-    // 额外添加的实用方法
-    pub async fn mark_messages_as_delivered(&self, conversation_id: &str, user_id: &str) -> Result<u64, Error> {
-        // 标记所有发给用户但尚未标记为已送达的消息
-        let filter = doc! {
-            "conversation_id": conversation_id,
-            "sender_id": { "$ne": user_id }, // 非用户发送的消息
-            "status": { "$eq": "Sent" } // 仅更新已发送但未送达的消息
-        };
-        
+
+    /// 撤回消息：清空内容并保留墓碑记录，不做物理删除
+    pub async fn retract_message(&self, message_id: &str, retracted_at: chrono::DateTime<Utc>) -> Result<(), Error> {
+        let filter = doc! { "id": message_id, "retracted_at": Bson::Null };
         let update = doc! {
-            "$set": { "status": "Delivered" }
+            "$set": {
+                "content": "",
+                "media_url": Bson::Null,
+                "revisions": [],
+                "reactions": [],
+                "retracted_at": chrono_to_bson(retracted_at)?,
+            }
         };
         
-        let options = UpdateOptions::builder()
-            .build();
+        let result = self.messages_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to retract message: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(Error::NotFound(format!("Message not found or already retracted: {}", message_id)));
+        }
+        
+        Ok(())
+    }
+
+    /// 删除会话及其所有数据，用于解散群聊
+    pub async fn delete_conversation(&self, conversation_id: &str) -> Result<(), Error> {
+        let filter = doc! { "conversation_id": conversation_id };
+        
+        self.messages_collection
+            .delete_many(filter.clone(), None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete conversation messages: {}", e)))?;
+        self.scheduled_messages_collection
+            .delete_many(filter.clone(), None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete scheduled messages: {}", e)))?;
+        self.drafts_collection
+            .delete_many(filter.clone(), None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete drafts: {}", e)))?;
+        self.preferences_collection
+            .delete_many(filter.clone(), None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete conversation preferences: {}", e)))?;
+        self.invites_collection
+            .delete_many(filter.clone(), None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete group invites: {}", e)))?;
+        self.sender_keys_collection
+            .delete_many(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete sender key distributions: {}", e)))?;
+        
+        // 最后删除会话本身，中途失败时会话仍在，可以重试
+        self.conversations_collection
+            .delete_one(doc! { "id": conversation_id }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete conversation: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 对用户隐藏会话中现有的所有消息
+    pub async fn hide_conversation_messages_for_user(&self, conversation_id: &str, user_id: &str) -> Result<u64, Error> {
+        let filter = doc! { "conversation_id": conversation_id };
+        let update = doc! { "$addToSet": { "deleted_for": user_id } };
         
         let result = self.messages_collection
-            .update_many(filter, update, Some(options))
+            .update_many(filter, update, None)
             .await
-            .map_err(|e| Error::Database(format!("Failed to mark messages as delivered: {}", e)))?;
+            .map_err(|e| Error::Database(format!("Failed to hide conversation messages: {}", e)))?;
         
         Ok(result.modified_count)
     }
-    
-    pub async fn get_unread_message_count(&self, user_id: &str) -> Result<u64, Error> {
-        // 获取所有发给用户但尚未阅读的消息数量
-        let filter = doc! {
-            "receiver_id": user_id,
-            "status": { "$ne": "Read" }
+
+    /// 向会话添加参与者，返回是否新加入（用户已在会话中时不做修改）
+    pub async fn add_participant(&self, conversation_id: &str, user_id: &str) -> Result<bool, Error> {
+        // 成员检查放在过滤条件中，并发加入同一用户时只有一次会生效
+        let filter = doc! { "id": conversation_id, "participants": { "$ne": user_id } };
+        let update = doc! {
+            "$addToSet": { "participants": user_id },
+            "$set": { "updatedAt": chrono_to_bson(Utc::now())? }
         };
         
-        let count = self.messages_collection
-            .count_documents(filter, None)
+        let result = self.conversations_collection
+            .update_one(filter, update, None)
             .await
-            .map_err(|e| Error::Database(format!("Failed to count unread messages: {}", e)))?;
+            .map_err(|e| Error::Database(format!("Failed to update conversation participants: {}", e)))?;
         
-        Ok(count)
+        Ok(result.matched_count > 0)
     }
-}
 
-fn chrono_to_bson_datetime(dt: chrono::DateTime<Utc>) -> BsonDateTime {
-    let system_time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(dt.timestamp() as u64)
-        + std::time::Duration::from_nanos(dt.timestamp_subsec_nanos() as u64);
-    
-    BsonDateTime::from_system_time(system_time)
+    /// 从会话移除参与者，同时清除其群角色，并让其余成员轮换发送者密钥
+    pub async fn remove_participant(&self, conversation_id: &str, user_id: &str) -> Result<(), Error> {
+        let filter = doc! { "id": conversation_id };
+        let update = doc! {
+            "$pull": { "participants": user_id },
+            "$unset": { format!("roles.{}", user_id): "" },
+            "$inc": { "senderKeyEpoch": 1 },
+            "$set": { "updatedAt": chrono_to_bson(Utc::now())? }
+        };
+        
+        self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update conversation participants: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 设置成员的群角色，为空表示恢复为普通成员
+    pub async fn set_member_role(&self, conversation_id: &str, user_id: &str, role: Option<GroupRole>) -> Result<(), Error> {
+        let filter = doc! { "id": conversation_id };
+        let key = format!("roles.{}", user_id);
+        let update = match role {
+            Some(role) => doc! {
+                "$set": { key: mongodb::bson::to_bson(&role)
+                    .map_err(|e| Error::Database(format!("Failed to serialize role: {}", e)))? }
+            },
+            None => doc! { "$unset": { key: "" } },
+        };
+        
+        self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update member role: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 转让群主，原群主成为管理员
+    ///
+    /// 只有 old_owner_id 仍是群主，或者旧群聊中还没有群主时才会转让，
+    /// 避免并发转让时两人同时成为群主。
+    pub async fn transfer_ownership(&self, conversation_id: &str, old_owner_id: &str, new_owner_id: &str) -> Result<(), Error> {
+        let owner = mongodb::bson::to_bson(&GroupRole::Owner)
+            .map_err(|e| Error::Database(format!("Failed to serialize role: {}", e)))?;
+        
+        // 与 permissions::owner_of 一致，只有仍在群里的群主才算数
+        let has_owner = doc! {
+            "$anyElementTrue": [{
+                "$map": {
+                    "input": { "$objectToArray": { "$ifNull": ["$roles", {}] } },
+                    "in": { "$and": [
+                        { "$eq": ["$$this.v", owner.clone()] },
+                        { "$in": ["$$this.k", "$participants"] }
+                    ] }
+                }
+            }]
+        };
+        let filter = doc! {
+            "id": conversation_id,
+            "participants": new_owner_id,
+            "$or": [
+                { format!("roles.{}", old_owner_id): owner.clone() },
+                { "$expr": { "$not": [has_owner] } }
+            ]
+        };
+        let update = doc! {
+            "$set": {
+                format!("roles.{}", old_owner_id): mongodb::bson::to_bson(&GroupRole::Admin)
+                    .map_err(|e| Error::Database(format!("Failed to serialize role: {}", e)))?,
+                format!("roles.{}", new_owner_id): owner,
+            }
+        };
+        
+        let result = self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to transfer ownership: {}", e)))?;
+        
+        if result.matched_count == 0 {
+            return Err(Error::Authentication(format!(
+                "User {} is no longer the owner of group {}", old_owner_id, conversation_id
+            )));
+        }
+        
+        Ok(())
+    }
+
+    pub async fn set_group_policy(&self, conversation_id: &str, policy: &GroupPolicy) -> Result<(), Error> {
+        let filter = doc! { "id": conversation_id };
+        let update = doc! {
+            "$set": {
+                "groupPolicy": mongodb::bson::to_document(policy)
+                    .map_err(|e| Error::Database(format!("Failed to serialize group policy: {}", e)))?
+            }
+        };
+        
+        self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update group policy: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 更新群名称、简介和头像
+    pub async fn set_group_info(
+        &self,
+        conversation_id: &str,
+        name: Option<&str>,
+        description: Option<&str>,
+        avatar_url: Option<&str>,
+    ) -> Result<(), Error> {
+        let filter = doc! { "id": conversation_id };
+        let update = doc! {
+            "$set": {
+                "name": name,
+                "description": description,
+                "avatarUrl": avatar_url,
+                "updatedAt": chrono_to_bson(Utc::now())?
+            }
+        };
+        
+        self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update group info: {}", e)))?;
+        
+        Ok(())
+    }
+
+    // 群邀请相关方法
+    pub async fn save_group_invite(&self, invite: &GroupInvite) -> Result<(), Error> {
+        self.invites_collection
+            .insert_one(invite, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to save group invite: {}", e)))?;
+        
+        Ok(())
+    }
+
+    pub async fn get_group_invite_by_token(&self, token: &str) -> Result<Option<GroupInvite>, Error> {
+        let filter = doc! { "token": token };
+        
+        self.invites_collection
+            .find_one(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get group invite: {}", e)))
+    }
+
+    /// 获取群聊中未撤销且未过期的邀请，次数是否用完由调用方判断
+    pub async fn get_group_invites(&self, conversation_id: &str) -> Result<Vec<GroupInvite>, Error> {
+        let filter = doc! {
+            "conversation_id": conversation_id,
+            "revoked_at": Bson::Null,
+            "$or": not_expired_conditions()?
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .build();
+        
+        let cursor = self.invites_collection
+            .find(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get group invites: {}", e)))?;
+        
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect group invites: {}", e)))
+    }
+
+    /// 撤销邀请，返回是否撤销成功
+    pub async fn revoke_group_invite(&self, conversation_id: &str, invite_id: &str) -> Result<bool, Error> {
+        let filter = doc! {
+            "id": invite_id,
+            "conversation_id": conversation_id,
+            "revoked_at": Bson::Null
+        };
+        let update = doc! { "$set": { "revoked_at": chrono_to_bson(Utc::now())? } };
+        
+        let result = self.invites_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to revoke group invite: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+
+    /// 原子地占用邀请的一次使用次数，邀请已失效时返回 None
+    pub async fn claim_group_invite(&self, token: &str) -> Result<Option<GroupInvite>, Error> {
+        let filter = doc! {
+            "token": token,
+            "revoked_at": Bson::Null,
+            "$or": not_expired_conditions()?,
+            "$expr": { "$or": [
+                { "$eq": ["$max_uses", Bson::Null] },
+                { "$lt": ["$use_count", "$max_uses"] }
+            ] }
+        };
+        let update = doc! { "$inc": { "use_count": 1 } };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        
+        self.invites_collection
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to claim group invite: {}", e)))
+    }
+
+    /// 归还 claim_group_invite 占用的一次使用次数，加入群聊失败时调用
+    pub async fn release_group_invite(&self, token: &str) -> Result<(), Error> {
+        self.invites_collection
+            .update_one(
+                doc! { "token": token, "use_count": { "$gt": 0 } },
+                doc! { "$inc": { "use_count": -1 } },
+                None,
+            )
+            .await
+            .map_err(|e| Error::Database(format!("Failed to release group invite: {}", e)))?;
+        
+        Ok(())
+    }
+
+    // 公钥目录相关方法
+    /// 发布公钥包，同一用户的同一设备只保留最新的一份
+    pub async fn publish_key_bundle(&self, bundle: &KeyBundle) -> Result<(), Error> {
+        let filter = doc! { "user_id": &bundle.user_id, "device_id": &bundle.device_id };
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+        
+        self.key_bundles_collection
+            .replace_one(filter, bundle, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to publish key bundle: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 获取用户最近发布的公钥包
+    pub async fn get_key_bundle(&self, user_id: &str) -> Result<Option<KeyBundle>, Error> {
+        let filter = doc! { "user_id": user_id };
+        let options = FindOneOptions::builder()
+            .sort(doc! { "updated_at": -1 })
+            .build();
+        
+        self.key_bundles_collection
+            .find_one(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get key bundle: {}", e)))
+    }
+
+    /// 批量获取用户最近发布的公钥包，没有发布过的用户不在结果中
+    pub async fn get_key_bundles(&self, user_ids: &[String]) -> Result<HashMap<String, KeyBundle>, Error> {
+        if user_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        
+        let filter = doc! { "user_id": { "$in": user_ids } };
+        let options = FindOptions::builder()
+            .sort(doc! { "updated_at": -1 })
+            .build();
+        
+        let cursor = self.key_bundles_collection
+            .find(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get key bundles: {}", e)))?;
+        
+        let bundles: Vec<KeyBundle> = cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect key bundles: {}", e)))?;
+        
+        // 按发布时间倒序，每个用户保留第一个
+        let mut latest = HashMap::new();
+        for bundle in bundles {
+            latest.entry(bundle.user_id.clone()).or_insert(bundle);
+        }
+        
+        Ok(latest)
+    }
+
+    /// 保存发送者密钥的分发记录，重复分发时覆盖
+    pub async fn save_sender_key_distribution(&self, distribution: &SenderKeyDistribution) -> Result<(), Error> {
+        let filter = doc! {
+            "conversation_id": &distribution.conversation_id,
+            "sender_id": &distribution.sender_id,
+            "recipient_id": &distribution.recipient_id,
+            "key_id": distribution.key_id as i64
+        };
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+        
+        self.sender_keys_collection
+            .replace_one(filter, distribution, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to save sender key distribution: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 获取发送者分发给接收者的指定发送者密钥
+    pub async fn get_sender_key_distribution(
+        &self,
+        conversation_id: &str,
+        sender_id: &str,
+        recipient_id: &str,
+        key_id: u32,
+    ) -> Result<Option<SenderKeyDistribution>, Error> {
+        let filter = doc! {
+            "conversation_id": conversation_id,
+            "sender_id": sender_id,
+            "recipient_id": recipient_id,
+            "key_id": key_id as i64
+        };
+        
+        self.sender_keys_collection
+            .find_one(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get sender key distribution: {}", e)))
+    }
+
+    /// 设置会话的消息保留时长，为空表示关闭阅后即焚
+    pub async fn set_message_ttl(&self, conversation_id: &str, message_ttl: Option<u64>) -> Result<(), Error> {
+        let filter = doc! { "id": conversation_id };
+        let update = doc! {
+            "$set": {
+                "messageTtl": message_ttl.map(|ttl| ttl as i64),
+                "updatedAt": chrono_to_bson(Utc::now())?
+            }
+        };
+        
+        self.conversations_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update message TTL: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 删除一批已过期的消息，返回被删除的消息
+    ///
+    /// 过期的话题根消息连同其所有回复一并删除；单独过期的回复从根消息的回复数中扣除。
+    pub async fn delete_expired_messages(&self, now: chrono::DateTime<Utc>, batch_size: i64) -> Result<Vec<Message>, Error> {
+        let filter = doc! { "expires_at": { "$ne": Bson::Null, "$lte": chrono_to_bson(now)? } };
+        let options = FindOptions::builder()
+            .sort(doc! { "expires_at": 1 })
+            .limit(batch_size)
+            .build();
+        
+        let cursor = self.messages_collection
+            .find(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to find expired messages: {}", e)))?;
+        
+        let mut expired: Vec<Message> = cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect expired messages: {}", e)))?;
+        
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        
+        // 根消息还在的回复，按根消息统计要扣除的回复数
+        let expired_roots: HashSet<&str> = expired.iter().map(|m| m.id.as_str()).collect();
+        let mut removed_replies: HashMap<String, i64> = HashMap::new();
+        for root_id in expired.iter().filter_map(|m| m.thread_root_id.as_deref()) {
+            if !expired_roots.contains(root_id) {
+                *removed_replies.entry(root_id.to_string()).or_insert(0) += 1;
+            }
+        }
+        
+        // 过期根消息的回复随根消息一起删除
+        let root_ids: Vec<&str> = expired_roots.into_iter().collect();
+        let cursor = self.messages_collection
+            .find(doc! { "thread_root_id": { "$in": &root_ids }, "id": { "$nin": &root_ids } }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to find thread replies: {}", e)))?;
+        let orphaned: Vec<Message> = cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect thread replies: {}", e)))?;
+        expired.extend(orphaned);
+        
+        let expired_ids: Vec<&str> = expired.iter().map(|m| m.id.as_str()).collect();
+        
+        self.messages_collection
+            .delete_many(doc! { "id": { "$in": &expired_ids } }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete expired messages: {}", e)))?;
+        
+        for (root_id, count) in removed_replies {
+            self.messages_collection
+                .update_one(doc! { "id": &root_id }, doc! { "$inc": { "thread_reply_count": -count } }, None)
+                .await
+                .map_err(|e| Error::Database(format!("Failed to update thread reply count: {}", e)))?;
+        }
+        
+        // 同时取消这些消息的置顶
+        self.conversations_collection
+            .update_many(
+                doc! { "pinnedMessages.messageId": { "$in": &expired_ids } },
+                doc! { "$pull": { "pinnedMessages": { "messageId": { "$in": &expired_ids } } } },
+                None,
+            )
+            .await
+            .map_err(|e| Error::Database(format!("Failed to unpin expired messages: {}", e)))?;
+        
+        Ok(expired)
+    }
+
+    /// 重新计算会话的最后一条消息（忽略已撤回和已过期的消息，以及不出现在主时间线的话题回复）
+    pub async fn recalculate_conversation_last_message(&self, conversation_id: &str) -> Result<Option<Message>, Error> {
+        let options = FindOneOptions::builder()
+            .sort(doc! { "timestamp": -1 })
+            .build();
+        
+        let last_message = self.messages_collection
+            .find_one(doc! {
+                "conversation_id": conversation_id,
+                "thread_root_id": Bson::Null,
+                "retracted_at": Bson::Null,
+                "$or": not_expired_conditions()?
+            }, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to find last message: {}", e)))?;
+        
+        let last_message_bson = match &last_message {
+            Some(message) => Bson::Document(mongodb::bson::to_document(message)
+                .map_err(|e| Error::Database(format!("Failed to serialize message: {}", e)))?),
+            None => Bson::Null,
+        };
+        
+        self.conversations_collection
+            .update_one(
+                doc! { "id": conversation_id },
+                doc! { "$set": { "lastMessage": last_message_bson } },
+                None,
+            )
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update conversation: {}", e)))?;
+        
+        Ok(last_message)
+    }
+    
+    // 额外添加的实用方法
+    pub async fn mark_messages_as_delivered(&self, conversation_id: &str, user_id: &str) -> Result<u64, Error> {
+        self.advance_receipts(conversation_id, user_id, MessageStatus::Delivered).await
+    }
+
+    pub async fn mark_messages_as_read(&self, conversation_id: &str, user_id: &str) -> Result<u64, Error> {
+        self.advance_receipts(conversation_id, user_id, MessageStatus::Read).await
+    }
+
+    /// 将会话中用户尚未达到指定状态的回执批量推进到该状态
+    async fn advance_receipts(&self, conversation_id: &str, user_id: &str, status: MessageStatus) -> Result<u64, Error> {
+        // 回执只为接收者建立，因此不会匹配到用户自己发送的消息
+        let mut filter = receipt_progress_filter(user_id, status)?;
+        filter.insert("conversation_id", conversation_id);
+        
+        let options = UpdateOptions::builder()
+            .build();
+        
+        let result = self.messages_collection
+            .update_many(filter, receipt_update(user_id, status)?, Some(options))
+            .await
+            .map_err(|e| Error::Database(format!("Failed to mark messages as {}: {}", status, e)))?;
+        
+        Ok(result.modified_count)
+    }
+    
+    /// 统计用户在指定会话中的未读消息数（回执尚未标记为已读的消息）
+    pub async fn get_unread_counts(&self, user_id: &str, conversation_ids: &[String]) -> Result<HashMap<String, u64>, Error> {
+        if conversation_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        
+        // 回执只为消息发送时的接收者建立，因此自己发的消息和入群前的消息不计入
+        let mut filter = receipt_progress_filter(user_id, MessageStatus::Read)?;
+        filter.insert("conversation_id", doc! { "$in": conversation_ids });
+        filter.insert("deleted_for", doc! { "$ne": user_id });
+        filter.insert("retracted_at", Bson::Null);
+        filter.insert("$or", not_expired_conditions()?);
+        
+        let pipeline = vec![
+            doc! { "$match": filter },
+            doc! { "$group": { "_id": "$conversation_id", "count": { "$sum": 1 } } },
+        ];
+        
+        let cursor = self.messages_collection
+            .aggregate(pipeline, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to count unread messages: {}", e)))?;
+        
+        let groups: Vec<Document> = cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect unread counts: {}", e)))?;
+        
+        let counts = groups.into_iter()
+            .filter_map(|group| {
+                let conversation_id = group.get_str("_id").ok()?.to_string();
+                let count = match group.get("count")? {
+                    Bson::Int32(n) => *n as u64,
+                    Bson::Int64(n) => *n as u64,
+                    _ => return None,
+                };
+                Some((conversation_id, count))
+            })
+            .collect();
+        
+        Ok(counts)
+    }
+
+    /// 为没有回执的旧消息补建回执，返回补建的消息数
+    ///
+    /// 旧消息只有一个共享的 status 字段。按会话当前的参与者（发送者除外）建立回执，
+    /// 初始状态沿用原来的 status，之后已送达、已读和未读计数就能按用户生效。
+    /// 只处理缺少 receipts 字段的消息，重复执行不会产生影响。
+    pub async fn migrate_legacy_receipts(&self) -> Result<u64, Error> {
+        let conversation_ids = self.messages_collection
+            .distinct("conversation_id", doc! { "receipts": { "$exists": false } }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to find messages without receipts: {}", e)))?;
+        
+        let mut migrated = 0;
+        for conversation_id in conversation_ids.iter().filter_map(|id| id.as_str()) {
+            // 会话已不存在的消息无法确定接收者，保持原样
+            let Some(conversation) = self.get_conversation(conversation_id).await? else {
+                continue;
+            };
+            
+            let participants: Vec<String> = conversation.participants.into_iter()
+                .filter(|p| receipt_path(p).is_ok())
+                .collect();
+            
+            let filter = doc! {
+                "conversation_id": conversation_id,
+                "receipts": { "$exists": false },
+            };
+            let pipeline = vec![doc! {
+                "$set": {
+                    "receipts": {
+                        "$arrayToObject": {
+                            "$map": {
+                                "input": { "$setDifference": [participants, ["$sender_id"]] },
+                                "as": "participant",
+                                "in": {
+                                    "k": "$$participant",
+                                    "v": {
+                                        "status": { "$ifNull": ["$status", MessageStatus::Sent.to_string()] },
+                                        "updated_at": "$timestamp",
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            }];
+            
+            let result = self.messages_collection
+                .update_many(filter, pipeline, None)
+                .await
+                .map_err(|e| Error::Database(format!("Failed to migrate receipts: {}", e)))?;
+            
+            migrated += result.modified_count;
+        }
+        
+        Ok(migrated)
+    }
+
+    // 定时消息相关方法
+    pub async fn save_scheduled_message(&self, scheduled: &ScheduledMessage) -> Result<(), Error> {
+        self.scheduled_messages_collection
+            .insert_one(scheduled, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to save scheduled message: {}", e)))?;
+        
+        Ok(())
+    }
+
+    pub async fn get_scheduled_message(&self, scheduled_id: &str) -> Result<Option<ScheduledMessage>, Error> {
+        let filter = doc! { "id": scheduled_id };
+        
+        self.scheduled_messages_collection
+            .find_one(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get scheduled message: {}", e)))
+    }
+
+    /// 获取用户尚未发送（待发送或发送失败）的定时消息，按发送时间排序
+    pub async fn get_scheduled_messages_for_user(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+    ) -> Result<Vec<ScheduledMessage>, Error> {
+        let mut filter = doc! {
+            "sender_id": user_id,
+            "status": scheduled_status_filter(|status| !status.is_final())?
+        };
+        if let Some(conversation_id) = conversation_id {
+            filter.insert("conversation_id", conversation_id);
+        }
+        
+        let options = FindOptions::builder()
+            .sort(doc! { "send_at": 1 })
+            .build();
+        
+        let cursor = self.scheduled_messages_collection
+            .find(filter, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get scheduled messages: {}", e)))?;
+        
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect scheduled messages: {}", e)))
+    }
+
+    /// 修改仍在等待发送的定时消息，返回是否修改成功
+    pub async fn update_scheduled_message(
+        &self,
+        scheduled_id: &str,
+        content: &str,
+        send_at: chrono::DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let filter = doc! {
+            "id": scheduled_id,
+            "status": scheduled_status_filter(ScheduledMessageStatus::is_editable)?
+        };
+        let update = doc! {
+            "$set": {
+                "message.content": content,
+                "send_at": chrono_to_bson(send_at)?,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        let result = self.scheduled_messages_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update scheduled message: {}", e)))?;
+        
+        Ok(result.matched_count > 0)
+    }
+
+    /// 取消尚未发送的定时消息，返回是否取消成功
+    pub async fn cancel_scheduled_message(&self, scheduled_id: &str) -> Result<bool, Error> {
+        let filter = doc! {
+            "id": scheduled_id,
+            "status": scheduled_status_filter(|status| status.can_transition_to(ScheduledMessageStatus::Cancelled))?
+        };
+        let update = doc! {
+            "$set": {
+                "status": scheduled_status_bson(ScheduledMessageStatus::Cancelled)?,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        let result = self.scheduled_messages_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to cancel scheduled message: {}", e)))?;
+        
+        Ok(result.matched_count > 0)
+    }
+
+    /// 原子地取出一条到期的定时消息并标记为发送中，避免重复发送
+    pub async fn claim_due_scheduled_message(&self, now: chrono::DateTime<Utc>) -> Result<Option<ScheduledMessage>, Error> {
+        let filter = doc! {
+            "status": scheduled_status_filter(|status| status.can_transition_to(ScheduledMessageStatus::Sending))?,
+            "send_at": { "$lte": chrono_to_bson(now)? }
+        };
+        let update = doc! {
+            "$set": {
+                "status": scheduled_status_bson(ScheduledMessageStatus::Sending)?,
+                "updated_at": chrono_to_bson(now)?,
+            },
+            "$inc": { "attempts": 1 }
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "send_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        
+        self.scheduled_messages_collection
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to claim scheduled message: {}", e)))
+    }
+
+    pub async fn mark_scheduled_message_sent(&self, scheduled_id: &str, message_id: &str) -> Result<(), Error> {
+        let filter = doc! { "id": scheduled_id };
+        let update = doc! {
+            "$set": {
+                "status": scheduled_status_bson(ScheduledMessageStatus::Sent)?,
+                "sent_message_id": message_id,
+                "last_error": Bson::Null,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        self.scheduled_messages_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to mark scheduled message as sent: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 记录发送失败；给出重试时间时重新排队，否则标记为失败
+    pub async fn mark_scheduled_message_failed(
+        &self,
+        scheduled_id: &str,
+        error: &str,
+        retry_at: Option<chrono::DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        let filter = doc! { "id": scheduled_id };
+        let mut set = doc! {
+            "last_error": error,
+            "updated_at": chrono_to_bson(Utc::now())?,
+        };
+        match retry_at {
+            Some(retry_at) => {
+                set.insert("status", scheduled_status_bson(ScheduledMessageStatus::Pending)?);
+                set.insert("send_at", chrono_to_bson(retry_at)?);
+            }
+            None => {
+                set.insert("status", scheduled_status_bson(ScheduledMessageStatus::Failed)?);
+            }
+        }
+        
+        self.scheduled_messages_collection
+            .update_one(filter, doc! { "$set": set }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to mark scheduled message as failed: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 把上次运行中途中断的发送任务重新放回队列
+    pub async fn requeue_interrupted_scheduled_messages(&self) -> Result<u64, Error> {
+        let filter = doc! { "status": scheduled_status_bson(ScheduledMessageStatus::Sending)? };
+        let update = doc! {
+            "$set": {
+                "status": scheduled_status_bson(ScheduledMessageStatus::Pending)?,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        let result = self.scheduled_messages_collection
+            .update_many(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to requeue scheduled messages: {}", e)))?;
+        
+        Ok(result.modified_count)
+    }
+
+    // 草稿相关方法
+    /// 保存草稿，每个用户每个会话只保留最新的一份
+    pub async fn save_draft(&self, draft: &Draft) -> Result<(), Error> {
+        let filter = doc! { "user_id": &draft.user_id, "conversation_id": &draft.conversation_id };
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+        
+        self.drafts_collection
+            .replace_one(filter, draft, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to save draft: {}", e)))?;
+        
+        Ok(())
+    }
+
+    pub async fn get_draft(&self, user_id: &str, conversation_id: &str) -> Result<Option<Draft>, Error> {
+        let filter = doc! { "user_id": user_id, "conversation_id": conversation_id };
+        
+        self.drafts_collection
+            .find_one(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get draft: {}", e)))
+    }
+
+    pub async fn delete_draft(&self, user_id: &str, conversation_id: &str) -> Result<(), Error> {
+        let filter = doc! { "user_id": user_id, "conversation_id": conversation_id };
+        
+        self.drafts_collection
+            .delete_one(filter, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to delete draft: {}", e)))?;
+        
+        Ok(())
+    }
+
+    /// 获取用户有草稿的会话ID
+    pub async fn get_draft_conversation_ids(&self, user_id: &str) -> Result<HashSet<String>, Error> {
+        let ids = self.drafts_collection
+            .distinct("conversation_id", doc! { "user_id": user_id }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get draft conversations: {}", e)))?;
+        
+        Ok(ids.into_iter()
+            .filter_map(|id| id.as_str().map(|s| s.to_string()))
+            .collect())
+    }
+
+    // 会话偏好相关方法
+    /// 获取用户对所有会话的偏好设置，键为会话ID
+    pub async fn get_conversation_preferences(&self, user_id: &str) -> Result<HashMap<String, ConversationPreferences>, Error> {
+        let cursor = self.preferences_collection
+            .find(doc! { "user_id": user_id }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get conversation preferences: {}", e)))?;
+        
+        let preferences: Vec<ConversationPreferences> = cursor
+            .try_collect()
+            .await
+            .map_err(|e| Error::Database(format!("Failed to collect conversation preferences: {}", e)))?;
+        
+        Ok(preferences.into_iter()
+            .map(|p| (p.conversation_id.clone(), p))
+            .collect())
+    }
+
+    /// 获取用户对某个会话的偏好，没有记录时返回 None
+    pub async fn get_conversation_preference(&self, user_id: &str, conversation_id: &str) -> Result<Option<ConversationPreferences>, Error> {
+        self.preferences_collection
+            .find_one(doc! { "user_id": user_id, "conversation_id": conversation_id }, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to get conversation preferences: {}", e)))
+    }
+
+    /// 更新用户对会话的偏好，尚无记录时以默认值创建，返回更新后的偏好
+    pub async fn update_conversation_preferences(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        mut set: Document,
+    ) -> Result<ConversationPreferences, Error> {
+        set.insert("updated_at", chrono_to_bson(Utc::now())?);
+        
+        // 默认值只写入本次没有修改的字段，避免与 $set 冲突
+        let mut defaults = mongodb::bson::to_document(&ConversationPreferences::new(user_id, conversation_id))
+            .map_err(|e| Error::Database(format!("Failed to serialize conversation preferences: {}", e)))?;
+        for key in set.keys() {
+            defaults.remove(key);
+        }
+        
+        let filter = doc! { "user_id": user_id, "conversation_id": conversation_id };
+        let update = doc! { "$set": set, "$setOnInsert": defaults };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        
+        self.preferences_collection
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to update conversation preferences: {}", e)))?
+            .ok_or_else(|| Error::Internal("Conversation preferences missing after upsert".to_string()))
+    }
+
+    /// 会话收到新消息时，为没有选择保持归档的用户取消归档
+    pub async fn unarchive_conversation(&self, conversation_id: &str) -> Result<u64, Error> {
+        let filter = doc! {
+            "conversation_id": conversation_id,
+            "archived": true,
+            "keep_archived": { "$ne": true }
+        };
+        let update = doc! {
+            "$set": {
+                "archived": false,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        let result = self.preferences_collection
+            .update_many(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to unarchive conversation: {}", e)))?;
+        
+        Ok(result.modified_count)
+    }
+
+    /// 会话收到新消息时，让删除过该会话的用户重新看到它
+    pub async fn unhide_conversation(&self, conversation_id: &str) -> Result<u64, Error> {
+        let filter = doc! { "conversation_id": conversation_id, "hidden": true };
+        let update = doc! {
+            "$set": {
+                "hidden": false,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        let result = self.preferences_collection
+            .update_many(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to unhide conversation: {}", e)))?;
+        
+        Ok(result.modified_count)
+    }
+
+    /// 取消用户对会话的隐藏，没有隐藏时不做修改
+    pub async fn unhide_conversation_for_user(&self, conversation_id: &str, user_id: &str) -> Result<bool, Error> {
+        let filter = doc! { "user_id": user_id, "conversation_id": conversation_id, "hidden": true };
+        let update = doc! {
+            "$set": {
+                "hidden": false,
+                "updated_at": chrono_to_bson(Utc::now())?,
+            }
+        };
+        
+        let result = self.preferences_collection
+            .update_one(filter, update, None)
+            .await
+            .map_err(|e| Error::Database(format!("Failed to unhide conversation: {}", e)))?;
+        
+        Ok(result.modified_count > 0)
+    }
+}
+
+// 由创建请求生成新的会话记录，私聊附带双方的标识
+fn build_conversation(new_conversation: NewConversation) -> Conversation {
+    let now = Utc::now();
+    
+    let direct_key = match (&new_conversation.conversation_type, new_conversation.participants.as_slice()) {
+        (ConversationType::Direct, [user_a, user_b]) => Some(Conversation::direct_key(user_a, user_b)),
+        _ => None,
+    };
+    
+    Conversation {
+        id: Uuid::new_v4().to_string(),
+        name: new_conversation.name,
+        conversation_type: new_conversation.conversation_type,
+        participants: new_conversation.participants,
+        created_at: now,
+        updated_at: now,
+        last_message: None,
+        encryption_enabled: new_conversation.encryption_enabled,
+        unread_count: 0,
+        pinned_messages: Vec::new(),
+        message_ttl: None,
+        has_draft: false,
+        preferences: None,
+        roles: new_conversation.roles,
+        group_policy: GroupPolicy::default(),
+        description: None,
+        avatar_url: None,
+        direct_key,
+        sender_key_epoch: 0,
+    }
+}
+
+// 违反唯一索引的写入错误
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+// 未设置过期时间或尚未过期
+fn not_expired_conditions() -> Result<Vec<Document>, Error> {
+    Ok(vec![
+        doc! { "expires_at": Bson::Null },
+        doc! { "expires_at": { "$gt": chrono_to_bson(Utc::now())? } },
+    ])
+}
+
+// 定时消息状态在数据库中的表示
+fn scheduled_status_bson(status: ScheduledMessageStatus) -> Result<Bson, Error> {
+    mongodb::bson::to_bson(&status)
+        .map_err(|e| Error::Database(format!("Failed to serialize scheduled status: {}", e)))
+}
+
+// 匹配处于满足条件的状态的定时消息
+fn scheduled_status_filter(matches: impl Fn(ScheduledMessageStatus) -> bool) -> Result<Document, Error> {
+    let statuses = ScheduledMessageStatus::ALL
+        .into_iter()
+        .filter(|status| matches(*status))
+        .map(scheduled_status_bson)
+        .collect::<Result<Vec<Bson>, Error>>()?;
+    
+    Ok(doc! { "$in": statuses })
+}
+
+// 用户ID会作为字段路径的一部分，包含 '.' 或以 '$' 开头会被解析为嵌套字段或操作符
+fn receipt_path(user_id: &str) -> Result<String, Error> {
+    if user_id.is_empty() || user_id.contains('.') || user_id.starts_with('$') {
+        return Err(Error::Validation(format!("Invalid user id for receipt: {}", user_id)));
+    }
+    
+    Ok(format!("receipts.{}", user_id))
+}
+
+// 匹配用户回执状态落后于目标状态的消息
+fn receipt_progress_filter(user_id: &str, status: MessageStatus) -> Result<Document, Error> {
+    let lower: Vec<String> = [MessageStatus::Sent, MessageStatus::Delivered, MessageStatus::Read]
+        .into_iter()
+        .filter(|s| *s < status)
+        .map(|s| s.to_string())
+        .collect();
+    
+    Ok(doc! { format!("{}.status", receipt_path(user_id)?): { "$in": lower } })
+}
+
+// 更新用户回执的状态和时间
+fn receipt_update(user_id: &str, status: MessageStatus) -> Result<Document, Error> {
+    let entry = ReceiptEntry { status, updated_at: Utc::now() };
+    
+    Ok(doc! {
+        "$set": {
+            receipt_path(user_id)?: mongodb::bson::to_document(&entry)
+                .map_err(|e| Error::Database(format!("Failed to serialize receipt: {}", e)))?
+        }
+    })
+}
+
+// chrono 时间经 serde 存储为字符串，查询和更新时使用相同的表示
+fn chrono_to_bson(dt: chrono::DateTime<Utc>) -> Result<Bson, Error> {
+    mongodb::bson::to_bson(&dt)
+        .map_err(|e| Error::Database(format!("Failed to serialize timestamp: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 测试未过期条件同时匹配没有过期时间和过期时间晚于当前的消息
+    #[test]
+    fn test_not_expired_conditions() {
+        let conditions = not_expired_conditions().unwrap();
+
+        assert_eq!(conditions.len(), 2);
+        assert_eq!(conditions[0], doc! { "expires_at": Bson::Null });
+
+        let bound = conditions[1].get_document("expires_at").unwrap().get_str("$gt").unwrap();
+        let bound = chrono::DateTime::parse_from_rfc3339(bound).unwrap().with_timezone(&Utc);
+        assert!((Utc::now() - bound).num_seconds().abs() < 5);
+    }
+
+    // 测试回执只会前进：只匹配状态落后于目标状态的回执
+    #[test]
+    fn test_receipt_progress_filter() {
+        let statuses = |status| {
+            receipt_progress_filter("alice", status).unwrap()
+                .get_document("receipts.alice.status").unwrap()
+                .get_array("$in").unwrap()
+                .iter()
+                .map(|s| s.as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(statuses(MessageStatus::Sent).is_empty());
+        assert_eq!(statuses(MessageStatus::Delivered), vec!["Sent"]);
+        assert_eq!(statuses(MessageStatus::Read), vec!["Sent", "Delivered"]);
+    }
+
+    // 测试无法安全放入字段路径的用户ID会被拒绝
+    #[test]
+    fn test_receipt_path_rejects_unsafe_ids() {
+        assert_eq!(receipt_path("alice").unwrap(), "receipts.alice");
+
+        for user_id in ["", "a.b", "$where"] {
+            assert!(matches!(receipt_path(user_id), Err(Error::Validation(_))));
+            assert!(receipt_progress_filter(user_id, MessageStatus::Read).is_err());
+            assert!(receipt_update(user_id, MessageStatus::Read).is_err());
+        }
+    }
+
+    // 测试定时消息状态条件使用与存储一致的表示
+    #[test]
+    fn test_scheduled_status_filter() {
+        let cancellable = scheduled_status_filter(|s| s.can_transition_to(ScheduledMessageStatus::Cancelled)).unwrap();
+        assert_eq!(cancellable, doc! { "$in": ["pending", "failed"] });
+
+        let unsent = scheduled_status_filter(|s| !s.is_final()).unwrap();
+        assert_eq!(unsent, doc! { "$in": ["pending", "sending", "failed"] });
+    }
 }
@@ -1,14 +1,17 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     Aes256Gcm, Nonce,
 };
+use base64::engine::{general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use rand::RngCore;
+use ring::{digest, hkdf, hmac};
+use ring::signature::{self, Ed25519KeyPair, KeyPair as _, UnparsedPublicKey};
 use serde::{Deserialize, Serialize};
-use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
 use crate::error::Error;
 
 pub struct KeyPair {
-    pub private_key: EphemeralSecret,
+    pub private_key: StaticSecret,
     pub public_key: PublicKey,
 }
 
@@ -27,9 +30,9 @@ impl Encryption {
         Encryption { rng: OsRng }
     }
 
-    // 生成ECDH密钥对
+    // 生成ECDH密钥对，私钥可长期保存
     pub fn generate_key_pair(&mut self) -> Result<KeyPair, Error> {
-        let private_key = EphemeralSecret::random_from_rng(&mut self.rng);
+        let private_key = StaticSecret::random_from_rng(&mut self.rng);
         let public_key = PublicKey::from(&private_key);
         Ok(KeyPair {
             private_key,
@@ -37,10 +40,10 @@ impl Encryption {
         })
     }
 
-    // 计算共享密钥，消耗 private_key
+    // 计算共享密钥，同一对密钥每次得到相同的结果
     pub fn derive_shared_secret(
         &self,
-        private_key: EphemeralSecret,
+        private_key: &StaticSecret,
         public_key: &PublicKey,
     ) -> Result<SharedSecret, Error> {
         Ok(private_key.diffie_hellman(public_key))
@@ -86,6 +89,644 @@ impl Encryption {
     }
 }
 
+// 签名种子对应的 Ed25519 公钥，随密钥包一起发布
+pub fn signing_public_key(signing_seed: &[u8; 32]) -> Result<Vec<u8>, Error> {
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(signing_seed)
+        .map_err(|_| Error::KeyGeneration)?;
+    Ok(key_pair.public_key().as_ref().to_vec())
+}
+
+// 用身份签名密钥为预共享公钥签名，签名同时覆盖身份公钥和预共享公钥编号
+pub fn sign_prekey(
+    signing_seed: &[u8; 32],
+    identity_key: &PublicKey,
+    key_id: u32,
+    prekey: &PublicKey,
+) -> Result<Vec<u8>, Error> {
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(signing_seed)
+        .map_err(|_| Error::KeyGeneration)?;
+    let payload = prekey_signature_payload(identity_key.as_bytes(), key_id, prekey.as_bytes());
+    Ok(key_pair.sign(&payload).as_ref().to_vec())
+}
+
+// 验证密钥包中预共享公钥的签名
+pub fn verify_prekey_signature(
+    signing_key: &[u8],
+    identity_key: &[u8],
+    key_id: u32,
+    prekey: &[u8],
+    signature: &[u8],
+) -> Result<(), Error> {
+    let payload = prekey_signature_payload(identity_key, key_id, prekey);
+    UnparsedPublicKey::new(&signature::ED25519, signing_key)
+        .verify(&payload, signature)
+        .map_err(|_| Error::Encryption("Invalid signed prekey signature".to_string()))
+}
+
+fn prekey_signature_payload(identity_key: &[u8], key_id: u32, prekey: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(identity_key.len() + 4 + prekey.len());
+    payload.extend_from_slice(identity_key);
+    payload.extend_from_slice(&key_id.to_be_bytes());
+    payload.extend_from_slice(prekey);
+    payload
+}
+
+/// 安全码的版本号，写入二维码内容，计算方式变化时递增
+const SAFETY_NUMBER_VERSION: u16 = 0;
+
+/// 计算身份指纹时的哈希迭代次数，提高构造相同安全码的成本
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+// 由双方的用户 ID 和身份公钥计算安全码，返回 60 位数字（每 5 位一组）和二维码内容
+//
+// 两段指纹按用户 ID 排序后拼接，双方计算的结果相同。
+pub fn safety_number(
+    user_id: &str,
+    identity_key: &[u8],
+    peer_id: &str,
+    peer_identity_key: &[u8],
+) -> (String, String) {
+    let mut fingerprints = [
+        (user_id, identity_fingerprint(user_id, identity_key)),
+        (peer_id, identity_fingerprint(peer_id, peer_identity_key)),
+    ];
+    fingerprints.sort_by(|a, b| a.0.cmp(b.0));
+
+    // 每段指纹取前 30 字节，每 5 字节转成一组 5 位数字
+    let numeric_code = fingerprints.iter()
+        .flat_map(|(_, fingerprint)| fingerprint[..30].chunks(5))
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut payload = SAFETY_NUMBER_VERSION.to_be_bytes().to_vec();
+    for (_, fingerprint) in &fingerprints {
+        payload.extend_from_slice(&fingerprint[..32]);
+    }
+
+    (numeric_code, URL_SAFE_NO_PAD.encode(payload))
+}
+
+// 用户比对或扫描得到的安全码是否与计算结果一致，数字安全码忽略空白
+pub fn safety_number_matches(numeric_code: &str, qr_payload: &str, provided: &str) -> bool {
+    let digits = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    let provided = provided.trim();
+    provided == qr_payload || (!provided.is_empty() && digits(provided) == digits(numeric_code))
+}
+
+fn identity_fingerprint(user_id: &str, identity_key: &[u8]) -> Vec<u8> {
+    let mut hash = SAFETY_NUMBER_VERSION.to_be_bytes().to_vec();
+    hash.extend_from_slice(identity_key);
+    hash.extend_from_slice(user_id.as_bytes());
+
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        let mut context = digest::Context::new(&digest::SHA512);
+        context.update(&hash);
+        context.update(identity_key);
+        hash = context.finish().as_ref().to_vec();
+    }
+    hash
+}
+
+/// 两次 DH 棘轮之间最多跳过的消息数，超过时拒绝解密
+pub const MAX_SKIP: u32 = 1000;
+
+/// 每个会话最多保留的跳过消息密钥数，超出时丢弃最早的
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+const X3DH_INFO: &[u8] = b"SmartLink X3DH";
+const RATCHET_INFO: &[u8] = b"SmartLink Ratchet";
+
+// X3DH 发起方附在消息上的参数，接收方据此建立相同的会话
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct X3dhInit {
+    pub identity_key: Vec<u8>,
+    pub ephemeral_key: Vec<u8>,
+    pub signed_prekey_id: u32,
+}
+
+// Double Ratchet 消息头：发送方当前的棘轮公钥、上一条发送链的长度和本条在链中的序号
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RatchetHeader {
+    pub ratchet_key: Vec<u8>,
+    pub previous_chain_len: u32,
+    pub message_number: u32,
+}
+
+// 私聊中保存的加密消息
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RatchetEnvelope {
+    pub session_id: String,
+    // 发起方收到回复前每条消息都附带 X3DH 参数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prekey: Option<X3dhInit>,
+    pub header: RatchetHeader,
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+impl RatchetEnvelope {
+    /// 消息在会话中的唯一标识，用于查找已保存的消息密钥
+    pub fn message_ref(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.session_id,
+            URL_SAFE_NO_PAD.encode(&self.header.ratchet_key),
+            self.header.message_number
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedKey {
+    ratchet_key: [u8; 32],
+    message_number: u32,
+    message_key: [u8; 32],
+}
+
+/// Double Ratchet 会话状态，可序列化后保存在本地密钥库中
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatchetState {
+    root_key: [u8; 32],
+    sending_ratchet: [u8; 32],
+    remote_ratchet_key: Option<[u8; 32]>,
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    sending_number: u32,
+    receiving_number: u32,
+    previous_chain_len: u32,
+    skipped: Vec<SkippedKey>,
+    // 双方的身份公钥，作为每条消息的附加认证数据
+    associated_data: Vec<u8>,
+}
+
+impl RatchetState {
+    /// 发起方用 X3DH 得到的共享密钥和对端的签名预共享公钥初始化会话
+    pub fn initiate(shared_secret: [u8; 32], remote_ratchet_key: &PublicKey, associated_data: Vec<u8>) -> Self {
+        let sending_ratchet = StaticSecret::random_from_rng(OsRng);
+        let (root_key, sending_chain) = kdf_root(&shared_secret, &sending_ratchet.diffie_hellman(remote_ratchet_key));
+
+        Self {
+            root_key,
+            sending_ratchet: sending_ratchet.to_bytes(),
+            remote_ratchet_key: Some(remote_ratchet_key.to_bytes()),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sending_number: 0,
+            receiving_number: 0,
+            previous_chain_len: 0,
+            skipped: Vec::new(),
+            associated_data,
+        }
+    }
+
+    /// 接收方用 X3DH 得到的共享密钥和自己的签名预共享私钥初始化会话
+    pub fn respond(shared_secret: [u8; 32], signed_prekey: &StaticSecret, associated_data: Vec<u8>) -> Self {
+        Self {
+            root_key: shared_secret,
+            sending_ratchet: signed_prekey.to_bytes(),
+            remote_ratchet_key: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sending_number: 0,
+            receiving_number: 0,
+            previous_chain_len: 0,
+            skipped: Vec::new(),
+            associated_data,
+        }
+    }
+
+    /// 加密一条消息，消息密钥用后即丢弃
+    pub fn encrypt(&mut self, plaintext: &str) -> Result<(RatchetHeader, EncryptedMessage), Error> {
+        let chain_key = self.sending_chain
+            .ok_or_else(|| Error::Encrypt("Session has no sending chain yet".to_string()))?;
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+
+        let header = RatchetHeader {
+            ratchet_key: PublicKey::from(&StaticSecret::from(self.sending_ratchet)).as_bytes().to_vec(),
+            previous_chain_len: self.previous_chain_len,
+            message_number: self.sending_number,
+        };
+        let encrypted = seal(&message_key, &header, &self.associated_data, plaintext)?;
+
+        self.sending_chain = Some(next_chain);
+        self.sending_number += 1;
+        Ok((header, encrypted))
+    }
+
+    /// 解密一条消息，失败时会话状态保持不变
+    pub fn decrypt(&mut self, header: &RatchetHeader, encrypted: &EncryptedMessage) -> Result<String, Error> {
+        let ratchet_key: [u8; 32] = header.ratchet_key.as_slice().try_into()
+            .map_err(|_| Error::Decrypt("Invalid ratchet key".to_string()))?;
+
+        // 先在跳过的消息密钥中查找，乱序到达的消息由此解密
+        if let Some(index) = self.skipped.iter()
+            .position(|k| k.ratchet_key == ratchet_key && k.message_number == header.message_number)
+        {
+            let message_key = self.skipped[index].message_key;
+            let plaintext = open(&message_key, header, &self.associated_data, encrypted)?;
+            self.skipped.remove(index);
+            return Ok(plaintext);
+        }
+
+        // 在副本上推进棘轮，解密成功后才替换当前状态
+        let mut next = self.clone();
+        if next.remote_ratchet_key != Some(ratchet_key) {
+            next.skip_message_keys(header.previous_chain_len)?;
+            next.dh_ratchet(ratchet_key);
+        }
+        next.skip_message_keys(header.message_number)?;
+
+        let chain_key = next.receiving_chain
+            .ok_or_else(|| Error::Decrypt("Session has no receiving chain".to_string()))?;
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+        let plaintext = open(&message_key, header, &next.associated_data, encrypted)?;
+
+        next.receiving_chain = Some(next_chain);
+        next.receiving_number += 1;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    // 保存当前接收链上 until 之前尚未收到的消息密钥
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), Error> {
+        let (Some(mut chain_key), Some(ratchet_key)) = (self.receiving_chain, self.remote_ratchet_key) else {
+            return Ok(());
+        };
+        if until > self.receiving_number.saturating_add(MAX_SKIP) {
+            return Err(Error::Decrypt("Too many skipped messages".to_string()));
+        }
+
+        while self.receiving_number < until {
+            let (next_chain, message_key) = kdf_chain(&chain_key);
+            self.skipped.push(SkippedKey {
+                ratchet_key,
+                message_number: self.receiving_number,
+                message_key,
+            });
+            chain_key = next_chain;
+            self.receiving_number += 1;
+        }
+        self.receiving_chain = Some(chain_key);
+
+        let excess = self.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    // 收到对端新的棘轮公钥：先派生接收链，再换新的棘轮密钥对派生发送链
+    fn dh_ratchet(&mut self, remote_ratchet_key: [u8; 32]) {
+        let remote = PublicKey::from(remote_ratchet_key);
+
+        self.previous_chain_len = self.sending_number;
+        self.sending_number = 0;
+        self.receiving_number = 0;
+        self.remote_ratchet_key = Some(remote_ratchet_key);
+
+        let current = StaticSecret::from(self.sending_ratchet);
+        let (root_key, receiving_chain) = kdf_root(&self.root_key, &current.diffie_hellman(&remote));
+        self.receiving_chain = Some(receiving_chain);
+
+        let sending_ratchet = StaticSecret::random_from_rng(OsRng);
+        let (root_key, sending_chain) = kdf_root(&root_key, &sending_ratchet.diffie_hellman(&remote));
+        self.root_key = root_key;
+        self.sending_ratchet = sending_ratchet.to_bytes();
+        self.sending_chain = Some(sending_chain);
+    }
+}
+
+// 群成员分发给其他成员的发送者密钥：当前链密钥、链上位置和验证消息签名的公钥
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SenderKeyShare {
+    pub key_id: u32,
+    pub chain_key: [u8; 32],
+    pub iteration: u32,
+    pub signing_key: Vec<u8>,
+}
+
+// 群聊中保存的加密消息，签名防止持有同一发送者密钥的其他成员冒充发送者
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SenderKeyMessage {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SenderKeyMessage {
+    /// 消息在群聊中的唯一标识，用于查找已保存的消息密钥
+    pub fn message_ref(&self, sender_id: &str) -> String {
+        format!("{}:{}:{}", sender_id, self.key_id, self.iteration)
+    }
+}
+
+/// 群聊中一位成员的发送者密钥链
+///
+/// 每发一条消息链密钥向前推进一次，只持有当前链密钥无法解密之前的消息。
+/// 只有发送者本人保存签名种子，其他成员只能验证签名。
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SenderKeyState {
+    key_id: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+    signing_key: Vec<u8>,
+    #[serde(default)]
+    signing_seed: Option<[u8; 32]>,
+    #[serde(default)]
+    skipped: Vec<(u32, [u8; 32])>,
+}
+
+impl SenderKeyState {
+    /// 生成自己的新发送者密钥
+    pub fn generate() -> Result<Self, Error> {
+        let mut chain_key = [0u8; 32];
+        let mut signing_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        OsRng.fill_bytes(&mut signing_seed);
+
+        Ok(Self {
+            key_id: OsRng.next_u32(),
+            chain_key,
+            iteration: 0,
+            signing_key: signing_public_key(&signing_seed)?,
+            signing_seed: Some(signing_seed),
+            skipped: Vec::new(),
+        })
+    }
+
+    /// 由其他成员分发的发送者密钥建立接收链
+    pub fn from_share(share: SenderKeyShare) -> Self {
+        Self {
+            key_id: share.key_id,
+            chain_key: share.chain_key,
+            iteration: share.iteration,
+            signing_key: share.signing_key,
+            signing_seed: None,
+            skipped: Vec::new(),
+        }
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// 消息是否早于本链的起点，例如加入群聊之前发送的消息，这类消息无法解密
+    pub fn precedes(&self, message: &SenderKeyMessage) -> bool {
+        message.iteration < self.iteration
+            && !self.skipped.iter().any(|(iteration, _)| *iteration == message.iteration)
+    }
+
+    /// 分发给其他成员的内容，从当前位置开始，接收方无法解密之前的消息
+    pub fn share(&self) -> SenderKeyShare {
+        SenderKeyShare {
+            key_id: self.key_id,
+            chain_key: self.chain_key,
+            iteration: self.iteration,
+            signing_key: self.signing_key.clone(),
+        }
+    }
+
+    /// 加密并签名一条消息，消息密钥用后即丢弃
+    pub fn encrypt(&mut self, plaintext: &str) -> Result<SenderKeyMessage, Error> {
+        let signing_seed = self.signing_seed
+            .ok_or_else(|| Error::Encrypt("Cannot send with another member's sender key".to_string()))?;
+        let (next_chain, message_key) = kdf_chain(&self.chain_key);
+
+        let encrypted = seal_sender_message(&message_key, self.key_id, self.iteration, plaintext)?;
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&signing_seed)
+            .map_err(|_| Error::KeyGeneration)?;
+        let signature = key_pair
+            .sign(&sender_signature_payload(self.key_id, self.iteration, &encrypted))
+            .as_ref()
+            .to_vec();
+
+        let message = SenderKeyMessage {
+            key_id: self.key_id,
+            iteration: self.iteration,
+            ciphertext: encrypted.ciphertext,
+            nonce: encrypted.nonce,
+            signature,
+        };
+        self.chain_key = next_chain;
+        self.iteration += 1;
+        Ok(message)
+    }
+
+    /// 验证签名并解密一条消息，失败时状态保持不变
+    pub fn decrypt(&mut self, message: &SenderKeyMessage) -> Result<String, Error> {
+        if message.key_id != self.key_id {
+            return Err(Error::Decrypt("Sender key does not match".to_string()));
+        }
+        self.verify(message)?;
+
+        if let Some(index) = self.skipped.iter().position(|(iteration, _)| *iteration == message.iteration) {
+            let message_key = self.skipped[index].1;
+            let plaintext = open_sender_message(&message_key, message)?;
+            self.skipped.remove(index);
+            return Ok(plaintext);
+        }
+
+        if message.iteration < self.iteration {
+            return Err(Error::Decrypt("Message key already used".to_string()));
+        }
+        if message.iteration - self.iteration > MAX_SKIP {
+            return Err(Error::Decrypt("Too many skipped messages".to_string()));
+        }
+
+        let mut chain_key = self.chain_key;
+        let mut skipped = Vec::new();
+        for iteration in self.iteration..message.iteration {
+            let (next_chain, message_key) = kdf_chain(&chain_key);
+            skipped.push((iteration, message_key));
+            chain_key = next_chain;
+        }
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+        let plaintext = open_sender_message(&message_key, message)?;
+
+        self.skipped.extend(skipped);
+        let excess = self.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        self.chain_key = next_chain;
+        self.iteration = message.iteration + 1;
+        Ok(plaintext)
+    }
+
+    fn verify(&self, message: &SenderKeyMessage) -> Result<(), Error> {
+        let encrypted = EncryptedMessage {
+            ciphertext: message.ciphertext.clone(),
+            nonce: message.nonce.clone(),
+        };
+        UnparsedPublicKey::new(&signature::ED25519, &self.signing_key)
+            .verify(&sender_signature_payload(message.key_id, message.iteration, &encrypted), &message.signature)
+            .map_err(|_| Error::Decrypt("Invalid sender signature".to_string()))
+    }
+}
+
+fn sender_message_aad(key_id: u32, iteration: u32) -> [u8; 8] {
+    let mut aad = [0u8; 8];
+    aad[..4].copy_from_slice(&key_id.to_be_bytes());
+    aad[4..].copy_from_slice(&iteration.to_be_bytes());
+    aad
+}
+
+fn sender_signature_payload(key_id: u32, iteration: u32, encrypted: &EncryptedMessage) -> Vec<u8> {
+    let mut payload = sender_message_aad(key_id, iteration).to_vec();
+    payload.extend_from_slice(&encrypted.nonce);
+    payload.extend_from_slice(&encrypted.ciphertext);
+    payload
+}
+
+fn seal_sender_message(message_key: &[u8; 32], key_id: u32, iteration: u32, plaintext: &str) -> Result<EncryptedMessage, Error> {
+    let key = Aes256Gcm::new_from_slice(message_key)
+        .map_err(|e| Error::Encrypt(e.to_string()))?;
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let aad = sender_message_aad(key_id, iteration);
+    let ciphertext = key
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: plaintext.as_bytes(), aad: &aad })
+        .map_err(|e| Error::Encrypt(e.to_string()))?;
+    Ok(EncryptedMessage {
+        ciphertext,
+        nonce: nonce_bytes.to_vec(),
+    })
+}
+
+fn open_sender_message(message_key: &[u8; 32], message: &SenderKeyMessage) -> Result<String, Error> {
+    if message.nonce.len() != 12 {
+        return Err(Error::InvalidNonce);
+    }
+    let key = Aes256Gcm::new_from_slice(message_key)
+        .map_err(|e| Error::Decrypt(e.to_string()))?;
+    let aad = sender_message_aad(message.key_id, message.iteration);
+    let plaintext = key
+        .decrypt(Nonce::from_slice(&message.nonce), Payload { msg: message.ciphertext.as_ref(), aad: &aad })
+        .map_err(|e| Error::Decrypt(e.to_string()))?;
+    String::from_utf8(plaintext)
+        .map_err(|e| Error::Decrypt(e.to_string()))
+}
+
+// X3DH 发起方：用自己的身份私钥、新的临时密钥和对端的身份公钥、签名预共享公钥计算共享密钥，返回共享密钥和临时公钥
+pub fn x3dh_initiate(
+    identity: &StaticSecret,
+    peer_identity_key: &PublicKey,
+    peer_signed_prekey: &PublicKey,
+) -> ([u8; 32], PublicKey) {
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let shared_secret = kdf_x3dh(&[
+        identity.diffie_hellman(peer_signed_prekey),
+        ephemeral.diffie_hellman(peer_identity_key),
+        ephemeral.diffie_hellman(peer_signed_prekey),
+    ]);
+    (shared_secret, PublicKey::from(&ephemeral))
+}
+
+// X3DH 接收方：用自己的身份私钥、签名预共享私钥和发起方的身份公钥、临时公钥计算相同的共享密钥
+pub fn x3dh_respond(
+    identity: &StaticSecret,
+    signed_prekey: &StaticSecret,
+    peer_identity_key: &PublicKey,
+    peer_ephemeral_key: &PublicKey,
+) -> [u8; 32] {
+    kdf_x3dh(&[
+        signed_prekey.diffie_hellman(peer_identity_key),
+        identity.diffie_hellman(peer_ephemeral_key),
+        signed_prekey.diffie_hellman(peer_ephemeral_key),
+    ])
+}
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_expand(salt: &[u8], input: &[u8], info: &[u8], output: &mut [u8]) {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(input)
+        .expand(&[info], OutputLen(output.len()))
+        .and_then(|okm| okm.fill(output))
+        .expect("HKDF output length is within limits");
+}
+
+fn kdf_x3dh(dh_outputs: &[SharedSecret]) -> [u8; 32] {
+    // 前缀 32 个 0xFF 字节，与 X3DH 规范一致
+    let mut input = vec![0xFFu8; 32];
+    for dh in dh_outputs {
+        input.extend_from_slice(dh.as_bytes());
+    }
+
+    let mut shared_secret = [0u8; 32];
+    hkdf_expand(&[0u8; 32], &input, X3DH_INFO, &mut shared_secret);
+    shared_secret
+}
+
+// 根链：由当前根密钥和 DH 输出派生新的根密钥和链密钥
+fn kdf_root(root_key: &[u8; 32], dh_output: &SharedSecret) -> ([u8; 32], [u8; 32]) {
+    let mut output = [0u8; 64];
+    hkdf_expand(root_key, dh_output.as_bytes(), RATCHET_INFO, &mut output);
+
+    let mut next_root = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    next_root.copy_from_slice(&output[..32]);
+    chain_key.copy_from_slice(&output[32..]);
+    (next_root, chain_key)
+}
+
+// 对称链：由链密钥派生下一个链密钥和本条消息的消息密钥
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, chain_key);
+
+    let mut message_key = [0u8; 32];
+    let mut next_chain = [0u8; 32];
+    message_key.copy_from_slice(hmac::sign(&key, &[0x01]).as_ref());
+    next_chain.copy_from_slice(hmac::sign(&key, &[0x02]).as_ref());
+    (next_chain, message_key)
+}
+
+// 附加认证数据：双方身份公钥加上消息头，消息头被篡改时解密失败
+fn ratchet_aad(associated_data: &[u8], header: &RatchetHeader) -> Vec<u8> {
+    let mut aad = associated_data.to_vec();
+    aad.extend_from_slice(&header.ratchet_key);
+    aad.extend_from_slice(&header.previous_chain_len.to_be_bytes());
+    aad.extend_from_slice(&header.message_number.to_be_bytes());
+    aad
+}
+
+fn seal(message_key: &[u8; 32], header: &RatchetHeader, associated_data: &[u8], plaintext: &str) -> Result<EncryptedMessage, Error> {
+    let key = Aes256Gcm::new_from_slice(message_key)
+        .map_err(|e| Error::Encrypt(e.to_string()))?;
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let aad = ratchet_aad(associated_data, header);
+    let ciphertext = key
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: plaintext.as_bytes(), aad: &aad })
+        .map_err(|e| Error::Encrypt(e.to_string()))?;
+    Ok(EncryptedMessage {
+        ciphertext,
+        nonce: nonce_bytes.to_vec(),
+    })
+}
+
+fn open(message_key: &[u8; 32], header: &RatchetHeader, associated_data: &[u8], encrypted: &EncryptedMessage) -> Result<String, Error> {
+    if encrypted.nonce.len() != 12 {
+        return Err(Error::InvalidNonce);
+    }
+    let key = Aes256Gcm::new_from_slice(message_key)
+        .map_err(|e| Error::Decrypt(e.to_string()))?;
+    let aad = ratchet_aad(associated_data, header);
+    let plaintext = key
+        .decrypt(Nonce::from_slice(&encrypted.nonce), Payload { msg: encrypted.ciphertext.as_ref(), aad: &aad })
+        .map_err(|e| Error::Decrypt(e.to_string()))?;
+    String::from_utf8(plaintext)
+        .map_err(|e| Error::Decrypt(e.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,11 +747,11 @@ mod tests {
 
         // Alice计算共享密钥
         let alice_shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, bob_public)
+            .derive_shared_secret(&alice_keys.private_key, bob_public)
             .expect("Alice shared secret derivation failed");
         // Bob计算共享密钥
         let bob_shared_secret = encryption
-            .derive_shared_secret(bob_keys.private_key, alice_public)
+            .derive_shared_secret(&bob_keys.private_key, alice_public)
             .expect("Bob shared secret derivation failed");
 
         // 验证共享密钥是否相同
@@ -152,6 +793,27 @@ mod tests {
         println!("Decrypted message: {}", decrypted);
     }
 
+    // 测试共享密钥可以重复派生，重启后无需保存会话密钥本身
+    #[test]
+    fn test_shared_secret_is_rederivable() {
+        let mut encryption = Encryption::new();
+
+        let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
+        let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
+
+        // 模拟从密钥库恢复 Alice 的私钥
+        let restored = StaticSecret::from(alice_keys.private_key.to_bytes());
+
+        let first = encryption
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
+            .expect("First derivation failed");
+        let second = encryption
+            .derive_shared_secret(&restored, &bob_keys.public_key)
+            .expect("Second derivation failed");
+
+        assert_eq!(first.as_bytes(), second.as_bytes(), "Re-derived secret should match");
+    }
+
     // 测试使用错误密钥解密
     #[test]
     fn test_decryption_with_wrong_key() {
@@ -164,7 +826,7 @@ mod tests {
         // Alice加密消息
         let plaintext = "我的银行密码是123456";
         let alice_shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Alice shared secret derivation failed");
         let encrypted_message = encryption
             .encrypt_message(plaintext, &alice_shared_secret)
@@ -176,7 +838,7 @@ mod tests {
             .generate_key_pair()
             .expect("Wrong key generation failed");
         let wrong_shared_secret = wrong_encryption
-            .derive_shared_secret(wrong_keys.private_key, &alice_keys.public_key)
+            .derive_shared_secret(&wrong_keys.private_key, &alice_keys.public_key)
             .expect("Wrong shared secret derivation failed");
 
         let result = encryption.decrypt_message(&encrypted_message, &wrong_shared_secret);
@@ -199,7 +861,7 @@ mod tests {
         let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
         let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
         let shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Shared secret derivation failed");
 
         // 创建一个无效的EncryptedMessage（Nonce长度不对）
@@ -229,7 +891,7 @@ mod tests {
         let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
         let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
         let shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Shared secret derivation failed");
 
         // 加密空消息
@@ -259,7 +921,7 @@ mod tests {
         let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
         let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
         let shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Shared secret derivation failed");
 
         // 创建一个超长消息（10KB）
@@ -292,7 +954,7 @@ mod tests {
         let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
         let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
         let shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Shared secret derivation failed");
 
         // 多次加密同一消息，检查Nonce是否不同
@@ -335,7 +997,7 @@ mod tests {
         let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
         let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
         let shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Shared secret derivation failed");
 
         // 加密消息
@@ -370,7 +1032,7 @@ mod tests {
         let alice_keys = encryption.generate_key_pair().expect("Alice key generation failed");
         let bob_keys = encryption.generate_key_pair().expect("Bob key generation failed");
         let shared_secret = encryption
-            .derive_shared_secret(alice_keys.private_key, &bob_keys.public_key)
+            .derive_shared_secret(&alice_keys.private_key, &bob_keys.public_key)
             .expect("Shared secret derivation failed");
 
         // 加密消息
@@ -395,4 +1057,258 @@ mod tests {
             );
         }
     }
-}
\ No newline at end of file
+
+    // 测试预共享公钥的签名验证，任何字段被替换都应失败
+    #[test]
+    fn test_signed_prekey() {
+        let mut encryption = Encryption::new();
+
+        let identity = encryption.generate_key_pair().expect("Identity key generation failed");
+        let prekey = encryption.generate_key_pair().expect("Prekey generation failed");
+        let other = encryption.generate_key_pair().expect("Other key generation failed");
+
+        let mut signing_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut signing_seed);
+        let signing_key = signing_public_key(&signing_seed).expect("Signing key derivation failed");
+
+        let signature = sign_prekey(&signing_seed, &identity.public_key, 1, &prekey.public_key)
+            .expect("Signing failed");
+
+        let identity_key = identity.public_key.as_bytes();
+        let prekey_bytes = prekey.public_key.as_bytes();
+        assert!(verify_prekey_signature(&signing_key, identity_key, 1, prekey_bytes, &signature).is_ok());
+
+        // 替换预共享公钥、编号或身份公钥都应验证失败
+        assert!(verify_prekey_signature(&signing_key, identity_key, 1, other.public_key.as_bytes(), &signature).is_err());
+        assert!(verify_prekey_signature(&signing_key, identity_key, 2, prekey_bytes, &signature).is_err());
+        assert!(verify_prekey_signature(&signing_key, other.public_key.as_bytes(), 1, prekey_bytes, &signature).is_err());
+
+        let mut tampered = signature.clone();
+        tampered[0] ^= 0xFF;
+        assert!(verify_prekey_signature(&signing_key, identity_key, 1, prekey_bytes, &tampered).is_err());
+    }
+
+    // 测试双方计算的安全码相同，任何一方的身份公钥变化后安全码随之变化
+    #[test]
+    fn test_safety_number() {
+        let mut encryption = Encryption::new();
+        let alice = encryption.generate_key_pair().expect("Alice key generation failed");
+        let bob = encryption.generate_key_pair().expect("Bob key generation failed");
+        let bob_new = encryption.generate_key_pair().expect("Bob new key generation failed");
+
+        let alice_key = alice.public_key.as_bytes();
+        let bob_key = bob.public_key.as_bytes();
+
+        let (code, payload) = safety_number("alice", alice_key, "bob", bob_key);
+        assert_eq!(safety_number("bob", bob_key, "alice", alice_key), (code.clone(), payload.clone()));
+
+        let groups: Vec<&str> = code.split(' ').collect();
+        assert_eq!(groups.len(), 12);
+        assert!(groups.iter().all(|g| g.len() == 5 && g.chars().all(|c| c.is_ascii_digit())));
+
+        // 对方更换身份密钥后安全码不同
+        let (changed_code, changed_payload) = safety_number("alice", alice_key, "bob", bob_new.public_key.as_bytes());
+        assert_ne!(changed_code, code);
+        assert_ne!(changed_payload, payload);
+
+        assert!(safety_number_matches(&code, &payload, &code.replace(' ', "")));
+        assert!(safety_number_matches(&code, &payload, &payload));
+        assert!(!safety_number_matches(&code, &payload, &changed_code));
+        assert!(!safety_number_matches(&code, &payload, ""));
+    }
+
+    // 通过 X3DH 建立 Alice（发起方）和 Bob（接收方）的 Double Ratchet 会话
+    fn ratchet_pair() -> (RatchetState, RatchetState) {
+        let mut encryption = Encryption::new();
+        let alice = encryption.generate_key_pair().expect("Alice key generation failed");
+        let bob = encryption.generate_key_pair().expect("Bob key generation failed");
+        let bob_prekey = encryption.generate_key_pair().expect("Bob prekey generation failed");
+
+        let (alice_secret, ephemeral_key) = x3dh_initiate(&alice.private_key, &bob.public_key, &bob_prekey.public_key);
+        let bob_secret = x3dh_respond(&bob.private_key, &bob_prekey.private_key, &alice.public_key, &ephemeral_key);
+        assert_eq!(alice_secret, bob_secret, "X3DH shared secrets do not match");
+
+        let mut associated_data = alice.public_key.as_bytes().to_vec();
+        associated_data.extend_from_slice(bob.public_key.as_bytes());
+
+        (
+            RatchetState::initiate(alice_secret, &bob_prekey.public_key, associated_data.clone()),
+            RatchetState::respond(bob_secret, &bob_prekey.private_key, associated_data),
+        )
+    }
+
+    // 测试 X3DH 握手后双方往来收发消息，每次回复都更换棘轮公钥
+    #[test]
+    fn test_ratchet_end_to_end() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let plaintext = "我的银行密码是123456";
+        let (header, encrypted) = alice.encrypt(plaintext).expect("Alice encryption failed");
+        assert_ne!(String::from_utf8_lossy(&encrypted.ciphertext), plaintext);
+        let decrypted = bob.decrypt(&header, &encrypted).expect("Bob decryption failed");
+        assert_eq!(decrypted, plaintext);
+
+        let (reply_header, reply) = bob.encrypt("收到").expect("Bob encryption failed");
+        assert_eq!(alice.decrypt(&reply_header, &reply).expect("Alice decryption failed"), "收到");
+
+        let (next_header, next) = alice.encrypt("好的").expect("Alice encryption failed");
+        assert_ne!(next_header.ratchet_key, header.ratchet_key, "Ratchet key should change after a reply");
+        assert_eq!(bob.decrypt(&next_header, &next).expect("Bob decryption failed"), "好的");
+    }
+
+    // 测试同一条链内和跨越 DH 棘轮的乱序消息都能解密
+    #[test]
+    fn test_ratchet_out_of_order() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let messages: Vec<_> = (0..3)
+            .map(|i| alice.encrypt(&format!("message {}", i)).expect("Encryption failed"))
+            .collect();
+        for i in [2, 0] {
+            let (header, encrypted) = &messages[i];
+            assert_eq!(bob.decrypt(header, encrypted).expect("Decryption failed"), format!("message {}", i));
+        }
+
+        // Bob 回复后 Alice 换了新的棘轮密钥，旧链上迟到的消息仍可解密
+        let (reply_header, reply) = bob.encrypt("reply").unwrap();
+        alice.decrypt(&reply_header, &reply).unwrap();
+        let (late_header, late) = alice.encrypt("new chain").unwrap();
+        assert_eq!(bob.decrypt(&late_header, &late).expect("New chain decryption failed"), "new chain");
+
+        let (header, encrypted) = &messages[1];
+        assert_eq!(bob.decrypt(header, encrypted).expect("Skipped message decryption failed"), "message 1");
+    }
+
+    // 测试消息密钥用后即丢弃，重复解密同一条消息失败
+    #[test]
+    fn test_ratchet_replay() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let (header, encrypted) = alice.encrypt("Sensitive data").unwrap();
+        assert_eq!(bob.decrypt(&header, &encrypted).unwrap(), "Sensitive data");
+        assert!(bob.decrypt(&header, &encrypted).is_err(), "Replayed message should not decrypt");
+    }
+
+    // 测试篡改密文、Nonce 或消息头时解密失败，且不破坏会话状态
+    #[test]
+    fn test_ratchet_tampered_message() {
+        let (mut alice, mut bob) = ratchet_pair();
+        let (header, encrypted) = alice.encrypt("Sensitive data").unwrap();
+
+        let mut tampered = EncryptedMessage { ciphertext: encrypted.ciphertext.clone(), nonce: encrypted.nonce.clone() };
+        tampered.ciphertext[0] ^= 0xFF;
+        assert!(bob.decrypt(&header, &tampered).is_err(), "Tampered ciphertext should fail");
+
+        let mut tampered = EncryptedMessage { ciphertext: encrypted.ciphertext.clone(), nonce: encrypted.nonce.clone() };
+        tampered.nonce[0] ^= 0xFF;
+        assert!(bob.decrypt(&header, &tampered).is_err(), "Tampered nonce should fail");
+
+        let invalid = EncryptedMessage { ciphertext: encrypted.ciphertext.clone(), nonce: vec![0; 8] };
+        assert!(matches!(bob.decrypt(&header, &invalid), Err(Error::InvalidNonce)));
+
+        let mut tampered_header = header.clone();
+        tampered_header.previous_chain_len += 1;
+        assert!(bob.decrypt(&tampered_header, &encrypted).is_err(), "Tampered header should fail");
+
+        assert_eq!(bob.decrypt(&header, &encrypted).expect("Genuine message should still decrypt"), "Sensitive data");
+    }
+
+    // 测试其他会话的密钥无法解密
+    #[test]
+    fn test_ratchet_wrong_session() {
+        let (mut alice, _) = ratchet_pair();
+        let (_, mut other_bob) = ratchet_pair();
+
+        let (header, encrypted) = alice.encrypt("Sensitive data").unwrap();
+        let result = other_bob.decrypt(&header, &encrypted);
+        assert!(result.is_err(), "Decryption with another session should fail");
+        assert!(result.err().unwrap().to_string().contains("Decrypt"));
+    }
+
+    // 测试空消息、超长消息，以及同一内容每次加密的 Nonce 和密文都不同
+    #[test]
+    fn test_ratchet_message_sizes_and_nonces() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let long = "A".repeat(10 * 1024);
+        for plaintext in ["", long.as_str()] {
+            let (header, encrypted) = alice.encrypt(plaintext).unwrap();
+            assert!(encrypted.ciphertext.len() >= plaintext.len() + 16, "Ciphertext should include the tag");
+            assert_eq!(bob.decrypt(&header, &encrypted).unwrap(), plaintext);
+        }
+
+        let (_, first) = alice.encrypt("Test nonce uniqueness").unwrap();
+        let (_, second) = alice.encrypt("Test nonce uniqueness").unwrap();
+        assert_ne!(first.nonce, second.nonce);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    // 测试跳过的消息数超过上限时拒绝解密，保存的跳过密钥数量有上限
+    #[test]
+    fn test_ratchet_skip_limits() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let (first_header, first) = alice.encrypt("first").unwrap();
+        for _ in 0..MAX_SKIP {
+            alice.encrypt("skipped").unwrap();
+        }
+        let (far_header, far) = alice.encrypt("too far").unwrap();
+        assert!(bob.decrypt(&far_header, &far).is_err(), "Exceeding MAX_SKIP should fail");
+        assert_eq!(bob.decrypt(&first_header, &first).unwrap(), "first");
+
+        for _ in 0..3 {
+            let until = bob.receiving_number + MAX_SKIP;
+            bob.skip_message_keys(until).unwrap();
+        }
+        assert_eq!(bob.skipped.len(), MAX_SKIPPED_KEYS);
+    }
+
+    // 测试群成员收到分发的发送者密钥后解密，签名被替换时拒绝
+    #[test]
+    fn test_sender_key_end_to_end() {
+        let mut alice = SenderKeyState::generate().expect("Sender key generation failed");
+        let mut bob = SenderKeyState::from_share(alice.share());
+
+        let plaintext = "我的银行密码是123456";
+        let message = alice.encrypt(plaintext).expect("Encryption failed");
+        assert_ne!(String::from_utf8_lossy(&message.ciphertext), plaintext);
+        assert_eq!(bob.decrypt(&message).expect("Decryption failed"), plaintext);
+
+        // 消息密钥用后即丢弃，重复解密失败
+        assert!(bob.decrypt(&message).is_err());
+
+        // 接收方不能用分发得到的密钥发送消息
+        assert!(bob.encrypt("forged").is_err());
+
+        let mut forged = alice.encrypt("Sensitive data").unwrap();
+        forged.signature[0] ^= 0xFF;
+        assert!(bob.decrypt(&forged).is_err(), "Invalid signature should fail");
+    }
+
+    // 测试乱序到达、篡改的消息，以及轮换后的新密钥
+    #[test]
+    fn test_sender_key_out_of_order_and_rotation() {
+        let mut alice = SenderKeyState::generate().unwrap();
+        let mut bob = SenderKeyState::from_share(alice.share());
+
+        let messages: Vec<_> = (0..3).map(|i| alice.encrypt(&format!("message {}", i)).unwrap()).collect();
+        assert_eq!(bob.decrypt(&messages[2]).unwrap(), "message 2");
+
+        let mut tampered = messages[0].clone();
+        tampered.ciphertext[0] ^= 0xFF;
+        assert!(bob.decrypt(&tampered).is_err(), "Tampered ciphertext should fail");
+
+        assert_eq!(bob.decrypt(&messages[0]).unwrap(), "message 0");
+        assert_eq!(bob.decrypt(&messages[1]).unwrap(), "message 1");
+
+        // 轮换后的消息无法用旧的发送者密钥解密；新成员拿到的是当前位置，之前的消息无法解密
+        let mut rotated = SenderKeyState::generate().unwrap();
+        let new_message = rotated.encrypt("after rotation").unwrap();
+        assert!(bob.decrypt(&new_message).is_err(), "Old sender key should not decrypt new messages");
+
+        let mut late_member = SenderKeyState::from_share(alice.share());
+        let next = alice.encrypt("next").unwrap();
+        assert!(late_member.decrypt(&messages[0]).is_err());
+        assert_eq!(late_member.decrypt(&next).unwrap(), "next");
+    }
+}
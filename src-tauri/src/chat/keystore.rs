@@ -0,0 +1,741 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::Error;
+use super::encryption::{RatchetState, SenderKeyState, X3dhInit};
+use super::plaintexts::PlaintextCache;
+
+/// 密钥库在应用数据目录下的文件名
+pub const KEYSTORE_FILE_NAME: &str = "chat_keystore.json";
+
+/// 密钥库文件格式版本
+const KEYSTORE_VERSION: u32 = 1;
+
+/// 由口令派生加密密钥的 PBKDF2-HMAC-SHA256 迭代次数
+const KDF_ITERATIONS: u32 = 600_000;
+
+/// 签名预共享密钥的轮换周期（天）
+const SIGNED_PREKEY_MAX_AGE_DAYS: i64 = 7;
+
+/// 轮换后仍保留的旧签名预共享密钥数量，供尚未取到新密钥包的对端使用
+const MAX_RETAINED_SIGNED_PREKEYS: usize = 3;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// 密钥库文件：KDF 参数以明文保存，密钥数据整体加密
+#[derive(Serialize, Deserialize)]
+struct KeyStoreFile {
+    version: u32,
+    salt: Vec<u8>,
+    iterations: u32,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+// 用户在本设备上的长期身份密钥
+#[derive(Serialize, Deserialize, Clone)]
+struct IdentityRecord {
+    secret: [u8; 32],
+    // 为预共享公钥签名的 Ed25519 种子，旧密钥库中没有时首次发布密钥包时生成
+    #[serde(default)]
+    signing_seed: Option<[u8; 32]>,
+    // 按创建时间排列，最后一个是当前发布的签名预共享密钥
+    #[serde(default)]
+    signed_prekeys: Vec<SignedPreKeyRecord>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SignedPreKeyRecord {
+    key_id: u32,
+    secret: [u8; 32],
+    created_at: DateTime<Utc>,
+}
+
+/// 会话密钥的派生关系：用户在会话中与哪位对端协商密钥，重启后据此重新派生
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SessionBinding {
+    pub conversation_id: String,
+    pub user_id: String,
+    pub peer_id: String,
+}
+
+/// 用户在私聊中与对端的一个 Double Ratchet 会话
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatchetSession {
+    pub conversation_id: String,
+    pub user_id: String,
+    pub peer_id: String,
+    pub session_id: String,
+    pub state: RatchetState,
+    // 发起方收到对端回复前，每条消息都附带 X3DH 参数
+    #[serde(default)]
+    pub pending_prekey: Option<X3dhInit>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// 群聊中的发送者密钥：sender_id 与 user_id 相同时是用户自己的密钥，否则是其他成员分发来的
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SenderKeyRecord {
+    pub conversation_id: String,
+    pub user_id: String,
+    pub sender_id: String,
+    // 生成密钥时会话的发送者密钥代数，落后于会话时需要轮换
+    pub epoch: u32,
+    pub state: SenderKeyState,
+    // 自己的密钥已分发给哪些成员
+    #[serde(default)]
+    pub distributed_to: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// 用户通过安全码验证过的对端身份公钥
+#[derive(Serialize, Deserialize)]
+struct VerifiedIdentity {
+    user_id: String,
+    peer_id: String,
+    identity_key: Vec<u8>,
+    verified_at: DateTime<Utc>,
+}
+
+// 解密后的密钥库内容
+#[derive(Serialize, Deserialize)]
+struct KeyStoreData {
+    device_id: String,
+    #[serde(default)]
+    identities: HashMap<String, IdentityRecord>,
+    #[serde(default)]
+    sessions: Vec<SessionBinding>,
+    #[serde(default)]
+    ratchet_sessions: Vec<RatchetSession>,
+    #[serde(default)]
+    sender_keys: Vec<SenderKeyRecord>,
+    #[serde(default)]
+    verified_identities: Vec<VerifiedIdentity>,
+}
+
+/// 本地加密密钥库
+///
+/// 保存本设备上各用户的长期身份密钥（X25519 StaticSecret）和会话密钥的派生关系。
+/// 文件内容用口令经 PBKDF2 派生的密钥以 AES-256-GCM 加密，格式与操作系统无关。
+/// 每次修改后写回文件，先写临时文件再替换，避免写到一半时损坏；
+/// 批量修改期间（begin_batch 到 end_batch）只在内存中修改，结束时写回一次。
+/// 消息明文缓存可能很大，单独保存在旁边的日志文件中，见 PlaintextCache。
+pub struct KeyStore {
+    path: PathBuf,
+    key: [u8; 32],
+    salt: Vec<u8>,
+    iterations: u32,
+    data: KeyStoreData,
+    plaintexts: PlaintextCache,
+    batch_depth: usize,
+    dirty: bool,
+}
+
+impl KeyStore {
+    /// 用口令打开密钥库，文件不存在时创建新的密钥库
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        if passphrase.is_empty() {
+            return Err(Error::Validation("Keystore passphrase must not be empty".to_string()));
+        }
+
+        if !path.exists() {
+            return Self::create(path, passphrase);
+        }
+
+        let raw = fs::read(path)?;
+        let file: KeyStoreFile = serde_json::from_slice(&raw)
+            .map_err(|e| Error::Internal(format!("Failed to parse keystore: {}", e)))?;
+
+        if file.version != KEYSTORE_VERSION {
+            return Err(Error::Internal(format!("Unsupported keystore version: {}", file.version)));
+        }
+        if file.nonce.len() != NONCE_LEN {
+            return Err(Error::InvalidNonce);
+        }
+
+        let key = derive_key(passphrase, &file.salt, file.iterations)?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|e| Error::Decrypt(e.to_string()))?;
+
+        // 口令错误和文件被篡改都表现为认证失败
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&file.nonce), file.ciphertext.as_ref())
+            .map_err(|_| Error::Authentication("Invalid keystore passphrase".to_string()))?;
+        let data: KeyStoreData = serde_json::from_slice(&plaintext)
+            .map_err(|e| Error::Internal(format!("Failed to parse keystore data: {}", e)))?;
+        let plaintexts = PlaintextCache::open(&plaintexts_path(path), key)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            key,
+            salt: file.salt,
+            iterations: file.iterations,
+            data,
+            plaintexts,
+            batch_depth: 0,
+            dirty: false,
+        })
+    }
+
+    fn create(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive_key(passphrase, &salt, KDF_ITERATIONS)?;
+
+        let mut key_store = Self {
+            path: path.to_path_buf(),
+            key,
+            salt,
+            iterations: KDF_ITERATIONS,
+            data: KeyStoreData {
+                device_id: Uuid::new_v4().to_string(),
+                identities: HashMap::new(),
+                sessions: Vec::new(),
+                ratchet_sessions: Vec::new(),
+                sender_keys: Vec::new(),
+                verified_identities: Vec::new(),
+            },
+            plaintexts: PlaintextCache::create(&plaintexts_path(path), key)?,
+            batch_depth: 0,
+            dirty: false,
+        };
+        key_store.save()?;
+
+        Ok(key_store)
+    }
+
+    /// 本设备的标识，创建密钥库时生成
+    pub fn device_id(&self) -> &str {
+        &self.data.device_id
+    }
+
+    /// 用户的身份私钥
+    pub fn identity(&self, user_id: &str) -> Option<StaticSecret> {
+        self.data.identities.get(user_id).map(|record| StaticSecret::from(record.secret))
+    }
+
+    /// 获取用户的身份公钥，没有时生成并保存新的身份密钥；第二个返回值表示是否新生成
+    pub fn get_or_create_identity(&mut self, user_id: &str) -> Result<(PublicKey, bool), Error> {
+        if let Some(secret) = self.identity(user_id) {
+            return Ok((PublicKey::from(&secret), false));
+        }
+
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        self.data.identities.insert(user_id.to_string(), IdentityRecord {
+            secret: secret.to_bytes(),
+            signing_seed: None,
+            signed_prekeys: Vec::new(),
+            created_at: Utc::now(),
+        });
+        self.save()?;
+
+        Ok((public_key, true))
+    }
+
+    /// 用户的 Ed25519 签名种子，没有时生成并保存
+    pub fn get_or_create_signing_seed(&mut self, user_id: &str) -> Result<[u8; 32], Error> {
+        let record = self.identity_record_mut(user_id)?;
+        if let Some(seed) = record.signing_seed {
+            return Ok(seed);
+        }
+
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        record.signing_seed = Some(seed);
+        self.save()?;
+
+        Ok(seed)
+    }
+
+    /// 用户当前的签名预共享密钥，没有或已超过轮换周期时生成新的，返回编号和公钥
+    pub fn current_signed_prekey(&mut self, user_id: &str) -> Result<(u32, PublicKey), Error> {
+        let now = Utc::now();
+        let record = self.identity_record_mut(user_id)?;
+
+        if let Some(current) = record.signed_prekeys.last() {
+            if now - current.created_at < Duration::days(SIGNED_PREKEY_MAX_AGE_DAYS) {
+                return Ok((current.key_id, PublicKey::from(&StaticSecret::from(current.secret))));
+            }
+        }
+
+        let key_id = record.signed_prekeys.last().map_or(1, |current| current.key_id.wrapping_add(1));
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        record.signed_prekeys.push(SignedPreKeyRecord {
+            key_id,
+            secret: secret.to_bytes(),
+            created_at: now,
+        });
+
+        let excess = record.signed_prekeys.len().saturating_sub(MAX_RETAINED_SIGNED_PREKEYS);
+        record.signed_prekeys.drain(..excess);
+        self.save()?;
+
+        Ok((key_id, public_key))
+    }
+
+    /// 按编号查找用户仍保留的签名预共享私钥
+    pub fn signed_prekey(&self, user_id: &str, key_id: u32) -> Option<StaticSecret> {
+        self.data.identities.get(user_id)?
+            .signed_prekeys.iter()
+            .find(|prekey| prekey.key_id == key_id)
+            .map(|prekey| StaticSecret::from(prekey.secret))
+    }
+
+    fn identity_record_mut(&mut self, user_id: &str) -> Result<&mut IdentityRecord, Error> {
+        self.data.identities.get_mut(user_id)
+            .ok_or_else(|| Error::Encryption(format!("No identity key for user {}", user_id)))
+    }
+
+    pub fn sessions(&self) -> &[SessionBinding] {
+        &self.data.sessions
+    }
+
+    /// 记录用户在会话中的密钥派生关系，替换该用户在会话中已有的记录
+    pub fn bind_session(&mut self, binding: SessionBinding) -> Result<(), Error> {
+        if self.data.sessions.contains(&binding) {
+            return Ok(());
+        }
+
+        self.data.sessions.retain(|s| {
+            !(s.conversation_id == binding.conversation_id && s.user_id == binding.user_id)
+        });
+        self.data.sessions.push(binding);
+        self.save()
+    }
+
+    /// 查找用户在会话中的 Double Ratchet 会话，session_id 为空时返回最近使用的一个
+    pub fn ratchet_session(&self, conversation_id: &str, user_id: &str, session_id: Option<&str>) -> Option<&RatchetSession> {
+        self.data.ratchet_sessions.iter()
+            .filter(|s| s.conversation_id == conversation_id && s.user_id == user_id)
+            .filter(|s| session_id.map_or(true, |id| s.session_id == id))
+            .max_by_key(|s| s.updated_at)
+    }
+
+    /// 保存 Double Ratchet 会话的最新状态
+    pub fn save_ratchet_session(&mut self, session: RatchetSession) -> Result<(), Error> {
+        self.data.ratchet_sessions.retain(|s| {
+            !(s.conversation_id == session.conversation_id && s.user_id == session.user_id && s.session_id == session.session_id)
+        });
+        self.data.ratchet_sessions.push(session);
+        self.save()
+    }
+
+    /// 查找用户在群聊中持有的发送者密钥，key_id 为空时返回最新的一个
+    pub fn sender_key(&self, conversation_id: &str, user_id: &str, sender_id: &str, key_id: Option<u32>) -> Option<&SenderKeyRecord> {
+        self.data.sender_keys.iter()
+            .filter(|k| k.conversation_id == conversation_id && k.user_id == user_id && k.sender_id == sender_id)
+            .filter(|k| key_id.map_or(true, |id| k.state.key_id() == id))
+            .max_by_key(|k| k.created_at)
+    }
+
+    /// 保存发送者密钥的最新状态
+    pub fn save_sender_key(&mut self, record: SenderKeyRecord) -> Result<(), Error> {
+        self.data.sender_keys.retain(|k| {
+            !(k.conversation_id == record.conversation_id
+                && k.user_id == record.user_id
+                && k.sender_id == record.sender_id
+                && k.state.key_id() == record.state.key_id())
+        });
+        self.data.sender_keys.push(record);
+        self.save()
+    }
+
+    /// 用户验证对端时对端的身份公钥，未验证时为空
+    pub fn verified_identity(&self, user_id: &str, peer_id: &str) -> Option<&[u8]> {
+        self.data.verified_identities.iter()
+            .find(|v| v.user_id == user_id && v.peer_id == peer_id)
+            .map(|v| v.identity_key.as_slice())
+    }
+
+    /// 记录用户已验证对端的身份公钥，重新验证时覆盖之前的记录
+    pub fn set_verified_identity(&mut self, user_id: &str, peer_id: &str, identity_key: &[u8]) -> Result<(), Error> {
+        self.data.verified_identities.retain(|v| !(v.user_id == user_id && v.peer_id == peer_id));
+        self.data.verified_identities.push(VerifiedIdentity {
+            user_id: user_id.to_string(),
+            peer_id: peer_id.to_string(),
+            identity_key: identity_key.to_vec(),
+            verified_at: Utc::now(),
+        });
+        self.save()
+    }
+
+    /// 用户之前发送或读过的消息明文，已过期的不返回
+    pub fn cached_plaintext(&self, user_id: &str, message_ref: &str) -> Option<&str> {
+        self.plaintexts.get(user_id, message_ref)
+    }
+
+    /// 缓存用户发送或读过的消息明文，保留到消息过期、撤回或会话密钥被删除
+    pub fn cache_plaintext(
+        &mut self,
+        conversation_id: &str,
+        user_id: &str,
+        message_id: &str,
+        message_ref: &str,
+        plaintext: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        self.plaintexts.insert(conversation_id, user_id, message_id, message_ref, plaintext, expires_at);
+        self.save_plaintexts()
+    }
+
+    /// 删除消息的所有缓存明文（包括历史版本），用于撤回
+    pub fn forget_plaintexts(&mut self, message_id: &str) -> Result<(), Error> {
+        if !self.plaintexts.forget_message(message_id) {
+            return Ok(());
+        }
+        self.save_plaintexts()
+    }
+
+    /// 删除用户在会话中缓存的所有明文，会话密钥保留
+    pub fn forget_conversation_plaintexts(&mut self, conversation_id: &str, user_id: &str) -> Result<(), Error> {
+        if !self.plaintexts.forget_conversation(conversation_id, Some(user_id)) {
+            return Ok(());
+        }
+        self.save_plaintexts()
+    }
+
+    /// 开始批量修改，可以嵌套；最外层的 end_batch 之前的修改只在内存中进行
+    pub fn begin_batch(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    /// 结束批量修改，最外层结束时把期间的修改一次写回
+    pub fn end_batch(&mut self) -> Result<(), Error> {
+        self.batch_depth = self.batch_depth.saturating_sub(1);
+        if self.batch_depth > 0 {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    /// 立即写回尚未保存的修改，锁定密钥库前调用
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            self.write()?;
+        }
+        self.plaintexts.flush()
+    }
+
+    /// 删除会话中的密钥派生关系、Double Ratchet 会话、发送者密钥和缓存的明文，user_id 为空时删除整个会话的记录
+    pub fn remove_sessions(&mut self, conversation_id: &str, user_id: Option<&str>) -> Result<(), Error> {
+        let matches = |c: &str, u: &str| c == conversation_id && user_id.map_or(true, |id| u == id);
+        let before = self.record_count();
+
+        self.data.sessions.retain(|s| !matches(&s.conversation_id, &s.user_id));
+        self.data.ratchet_sessions.retain(|s| !matches(&s.conversation_id, &s.user_id));
+        self.data.sender_keys.retain(|k| !matches(&k.conversation_id, &k.user_id));
+        if self.plaintexts.forget_conversation(conversation_id, user_id) {
+            self.save_plaintexts()?;
+        }
+
+        if self.record_count() == before {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    fn record_count(&self) -> usize {
+        self.data.sessions.len()
+            + self.data.ratchet_sessions.len()
+            + self.data.sender_keys.len()
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        if self.batch_depth > 0 {
+            self.dirty = true;
+            return Ok(());
+        }
+        self.write()
+    }
+
+    // 明文缓存只追加新记录，批量修改期间留到 end_batch 一起追加
+    fn save_plaintexts(&mut self) -> Result<(), Error> {
+        if self.batch_depth > 0 {
+            return Ok(());
+        }
+        self.plaintexts.flush()
+    }
+
+    /// 加密并写回文件，每次写入使用新的随机 nonce
+    fn write(&mut self) -> Result<(), Error> {
+        let plaintext = serde_json::to_vec(&self.data)
+            .map_err(|e| Error::Internal(format!("Failed to serialize keystore: {}", e)))?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = Aes256Gcm::new_from_slice(&self.key)
+            .map_err(|e| Error::Encrypt(e.to_string()))?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|e| Error::Encrypt(e.to_string()))?;
+
+        let file = KeyStoreFile {
+            version: KEYSTORE_VERSION,
+            salt: self.salt.clone(),
+            iterations: self.iterations,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        };
+        let serialized = serde_json::to_vec(&file)
+            .map_err(|e| Error::Internal(format!("Failed to serialize keystore: {}", e)))?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serialized)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// 消息明文缓存的日志文件与密钥库放在一起，例如 chat_keystore.plaintexts
+fn plaintexts_path(keystore_path: &Path) -> PathBuf {
+    keystore_path.with_extension("plaintexts")
+}
+
+// 由口令派生密钥库的加密密钥
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<[u8; 32], Error> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| Error::Internal("Invalid keystore KDF iterations".to_string()))?;
+
+    let mut key = [0u8; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("keystore-test-{}.json", Uuid::new_v4()))
+    }
+
+    fn remove_files(path: &Path) {
+        fs::remove_file(path).ok();
+        fs::remove_file(plaintexts_path(path)).ok();
+    }
+
+    // 测试身份密钥和派生关系在重新打开后保持不变
+    #[test]
+    fn test_reopen_keeps_identities() {
+        let path = temp_path();
+
+        let mut key_store = KeyStore::open(&path, "correct horse").expect("Failed to create keystore");
+        let (public_key, created) = key_store.get_or_create_identity("alice").unwrap();
+        assert!(created);
+        key_store.bind_session(SessionBinding {
+            conversation_id: "c1".to_string(),
+            user_id: "alice".to_string(),
+            peer_id: "bob".to_string(),
+        }).unwrap();
+        let device_id = key_store.device_id().to_string();
+        drop(key_store);
+
+        let mut reopened = KeyStore::open(&path, "correct horse").expect("Failed to reopen keystore");
+        let (reopened_key, created) = reopened.get_or_create_identity("alice").unwrap();
+        assert!(!created);
+        assert_eq!(reopened_key.as_bytes(), public_key.as_bytes());
+        assert_eq!(reopened.device_id(), device_id);
+        assert_eq!(reopened.sessions().len(), 1);
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试签名预共享密钥在轮换周期内保持不变，过期后轮换且只保留有限个旧密钥
+    #[test]
+    fn test_signed_prekey_rotation() {
+        let path = temp_path();
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+
+        assert!(key_store.current_signed_prekey("alice").is_err());
+        key_store.get_or_create_identity("alice").unwrap();
+
+        let (key_id, public_key) = key_store.current_signed_prekey("alice").unwrap();
+        let (same_id, same_key) = key_store.current_signed_prekey("alice").unwrap();
+        assert_eq!(key_id, same_id);
+        assert_eq!(public_key.as_bytes(), same_key.as_bytes());
+
+        for _ in 0..MAX_RETAINED_SIGNED_PREKEYS + 1 {
+            let record = key_store.data.identities.get_mut("alice").unwrap();
+            record.signed_prekeys.last_mut().unwrap().created_at -= Duration::days(SIGNED_PREKEY_MAX_AGE_DAYS);
+            key_store.current_signed_prekey("alice").unwrap();
+        }
+
+        let (rotated_id, rotated_key) = key_store.current_signed_prekey("alice").unwrap();
+        assert_eq!(rotated_id, key_id + MAX_RETAINED_SIGNED_PREKEYS as u32 + 1);
+        assert_ne!(rotated_key.as_bytes(), public_key.as_bytes());
+        assert_eq!(key_store.data.identities["alice"].signed_prekeys.len(), MAX_RETAINED_SIGNED_PREKEYS);
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试 Double Ratchet 会话和缓存的明文在重新打开后保持不变，删除会话时一并删除
+    #[test]
+    fn test_ratchet_sessions_persist() {
+        let path = temp_path();
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+
+        let prekey = StaticSecret::random_from_rng(OsRng);
+        key_store.save_ratchet_session(RatchetSession {
+            conversation_id: "c1".to_string(),
+            user_id: "bob".to_string(),
+            peer_id: "alice".to_string(),
+            session_id: "s1".to_string(),
+            state: RatchetState::respond([7u8; 32], &prekey, Vec::new()),
+            pending_prekey: None,
+            updated_at: Utc::now(),
+        }).unwrap();
+        key_store.cache_plaintext("c1", "bob", "m1", "s1:key:0", "hello", None).unwrap();
+        drop(key_store);
+
+        let mut reopened = KeyStore::open(&path, "correct horse").unwrap();
+        assert_eq!(reopened.ratchet_session("c1", "bob", None).unwrap().session_id, "s1");
+        assert!(reopened.ratchet_session("c1", "bob", Some("s2")).is_none());
+        assert_eq!(reopened.cached_plaintext("bob", "s1:key:0"), Some("hello"));
+        assert!(reopened.cached_plaintext("alice", "s1:key:0").is_none());
+
+        reopened.remove_sessions("c1", Some("bob")).unwrap();
+        assert!(reopened.ratchet_session("c1", "bob", None).is_none());
+        assert!(reopened.cached_plaintext("bob", "s1:key:0").is_none());
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试缓存明文的过期和撤回，明文不写入密钥库文件，批量修改期间不写文件
+    #[test]
+    fn test_plaintext_cache() {
+        let path = temp_path();
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+
+        key_store.cache_plaintext("c1", "bob", "m1", "s1:key:0", "v1", None).unwrap();
+        key_store.cache_plaintext("c1", "bob", "m1", "s1:key:1", "v2", None).unwrap();
+        key_store.cache_plaintext("c1", "bob", "m2", "s1:key:2", "gone", Some(Utc::now() - Duration::seconds(1))).unwrap();
+        assert!(key_store.cached_plaintext("bob", "s1:key:2").is_none(), "Expired plaintext should not be returned");
+
+        key_store.forget_plaintexts("m1").unwrap();
+        assert!(key_store.cached_plaintext("bob", "s1:key:0").is_none());
+        assert!(key_store.cached_plaintext("bob", "s1:key:1").is_none());
+
+        let raw = fs::read(&path).unwrap();
+        let raw_plaintexts = fs::read(plaintexts_path(&path)).unwrap();
+        key_store.begin_batch();
+        key_store.cache_plaintext("c1", "bob", "m3", "s2:key:0", "batched", None).unwrap();
+        key_store.bind_session(SessionBinding {
+            conversation_id: "c1".to_string(),
+            user_id: "bob".to_string(),
+            peer_id: "alice".to_string(),
+        }).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), raw, "Batched changes should not be written yet");
+        assert_eq!(fs::read(plaintexts_path(&path)).unwrap(), raw_plaintexts);
+        key_store.end_batch().unwrap();
+        drop(key_store);
+
+        let reopened = KeyStore::open(&path, "correct horse").unwrap();
+        assert_eq!(reopened.cached_plaintext("bob", "s2:key:0"), Some("batched"));
+        assert_eq!(reopened.sessions().len(), 1);
+        let data = serde_json::to_string(&reopened.data).unwrap();
+        assert!(!data.contains("batched"), "Plaintexts should not be stored in the keystore file");
+
+        remove_files(&path);
+    }
+
+    // 测试隐藏会话只删除该用户在该会话中的缓存明文，会话密钥保留
+    #[test]
+    fn test_forget_conversation_plaintexts() {
+        let path = temp_path();
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+
+        key_store.bind_session(SessionBinding {
+            conversation_id: "c1".to_string(),
+            user_id: "alice".to_string(),
+            peer_id: "bob".to_string(),
+        }).unwrap();
+        key_store.cache_plaintext("c1", "alice", "m1", "s1:key:0", "hidden", None).unwrap();
+        key_store.cache_plaintext("c1", "bob", "m1", "s2:key:0", "kept", None).unwrap();
+        key_store.cache_plaintext("c2", "alice", "m2", "s3:key:0", "kept", None).unwrap();
+
+        key_store.forget_conversation_plaintexts("c1", "alice").unwrap();
+        assert!(key_store.cached_plaintext("alice", "s1:key:0").is_none());
+        assert_eq!(key_store.cached_plaintext("bob", "s2:key:0"), Some("kept"));
+        assert_eq!(key_store.cached_plaintext("alice", "s3:key:0"), Some("kept"));
+        assert_eq!(key_store.sessions().len(), 1);
+
+        remove_files(&path);
+    }
+
+    // 测试验证记录在重新打开后保持不变，重新验证时覆盖旧的公钥
+    #[test]
+    fn test_verified_identities_persist() {
+        let path = temp_path();
+
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+        assert!(key_store.verified_identity("alice", "bob").is_none());
+        key_store.set_verified_identity("alice", "bob", &[1u8; 32]).unwrap();
+        key_store.set_verified_identity("alice", "bob", &[2u8; 32]).unwrap();
+        drop(key_store);
+
+        let reopened = KeyStore::open(&path, "correct horse").unwrap();
+        assert_eq!(reopened.verified_identity("alice", "bob"), Some(&[2u8; 32][..]));
+        assert!(reopened.verified_identity("bob", "alice").is_none());
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试错误口令无法打开，文件中没有明文私钥
+    #[test]
+    fn test_wrong_passphrase() {
+        let path = temp_path();
+
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+        key_store.get_or_create_identity("alice").unwrap();
+        let secret = key_store.identity("alice").unwrap().to_bytes();
+
+        let raw = fs::read(&path).unwrap();
+        assert!(!raw.windows(secret.len()).any(|w| w == secret));
+
+        let result = KeyStore::open(&path, "battery staple");
+        assert!(matches!(result, Err(Error::Authentication(_))));
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试按会话和用户删除派生关系
+    #[test]
+    fn test_remove_sessions() {
+        let path = temp_path();
+        let mut key_store = KeyStore::open(&path, "correct horse").unwrap();
+
+        for (conversation_id, user_id, peer_id) in [("c1", "alice", "bob"), ("c1", "bob", "alice"), ("c2", "alice", "carol")] {
+            key_store.bind_session(SessionBinding {
+                conversation_id: conversation_id.to_string(),
+                user_id: user_id.to_string(),
+                peer_id: peer_id.to_string(),
+            }).unwrap();
+        }
+
+        key_store.remove_sessions("c1", Some("bob")).unwrap();
+        assert_eq!(key_store.sessions().len(), 2);
+        key_store.remove_sessions("c1", None).unwrap();
+        assert_eq!(key_store.sessions().len(), 1);
+        assert_eq!(key_store.sessions()[0].conversation_id, "c2");
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -1,19 +1,28 @@
 // manager.rs
 use super::{
     db::ChatDatabase,
-    models::{Conversation, Message, MessageStatus, NewConversation, NewMessage, ConversationType},
-    encryption::{Encryption, EncryptedMessage, KeyPair},
+    models::{Conversation, ConversationPreferences, DeleteMode, Draft, GroupInfoUpdate, GroupInvite, GroupPolicy, GroupRole, ForwardedFrom, Message, MessageReceipt, MessageRevision, MessageStatus, MessagesPage, MessageThread, MessageType, NewConversation, NewMessage, ConversationType, KeyBundle, PinnedMessage, Reaction, ReactionCount, ReplyPreview, SafetyNumber, ScheduledMessage, ScheduledMessageStatus, SenderKeyDistribution, SignedPreKey, SystemAction, SystemEvent},
+    encryption::{self, Encryption, EncryptedMessage, RatchetEnvelope, RatchetState, SenderKeyMessage, SenderKeyShare, SenderKeyState, X3dhInit},
+    keystore::{KeyStore, RatchetSession, SenderKeyRecord, SessionBinding},
+    mentions,
+    permissions::{self, GroupAction},
+    preferences::{self, ConversationFilter},
+    search::{self, LocalSearchIndex, SearchQuery, SearchResults},
 };
 use crate::error::Error;
-use std::{collections::HashMap, time::SystemTime};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
-use chrono::Utc;
-use mongodb::{bson::{doc, DateTime}, options::UpdateOptions};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use mongodb::bson::doc;
 use rand::rngs::OsRng;
+use rand::RngCore;
+use base64::engine::{general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use tokio::sync::Mutex as TokioMutex;
-use tracing::debug;
+use tracing::{debug, warn};
 use serde_json;
-use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use x25519_dalek::{PublicKey, SharedSecret};
 
 /// 安全的会话密钥存储
 pub struct SessionKeyStore {
@@ -58,6 +67,14 @@ impl SessionKeyStore {
         Ok(())
     }
 
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut keys = self.keys.write().map_err(|_| 
+            Error::Internal("Failed to acquire write lock on session keys".to_string()))?;
+        
+        keys.clear();
+        Ok(())
+    }
+
     pub fn remove_user_keys(&self, conversation_id: &str, user_id: &str) -> Result<(), Error> {
         let mut keys = self.keys.write().map_err(|_| 
             Error::Internal("Failed to acquire write lock on session keys".to_string()))?;
@@ -71,38 +88,114 @@ impl SessionKeyStore {
 }
 
 /// 密钥管理器
+///
+/// 用户的长期身份密钥保存在本地加密密钥库中，密钥库解锁前无法建立或恢复加密会话。
 pub struct KeyManager {
     encryption: Arc<Mutex<Encryption>>,
-    key_store: Arc<TokioMutex<HashMap<String, KeyPair>>>,
+    key_store: Arc<TokioMutex<Option<KeyStore>>>,
 }
 
 impl KeyManager {
     pub fn new() -> Self {
         Self {
             encryption: Arc::new(Mutex::new(Encryption::new())),
-            key_store: Arc::new(TokioMutex::new(HashMap::new())),
+            key_store: Arc::new(TokioMutex::new(None)),
         }
     }
 
-    pub async fn get_or_create_key_pair(&self, user_id: &str) -> Result<(PublicKey, bool), Error> {
-        let mut store = self.key_store.lock().await;
+    /// 用口令解锁密钥库，文件不存在时创建，返回本设备的标识
+    pub async fn unlock(&self, path: &Path, passphrase: &str) -> Result<String, Error> {
+        let key_store = KeyStore::open(path, passphrase)?;
+        let device_id = key_store.device_id().to_string();
         
-        // 检查是否已有密钥对
-        if let Some(key_pair) = store.get(user_id) {
-            return Ok((key_pair.public_key.clone(), false));
+        *self.key_store.lock().await = Some(key_store);
+        Ok(device_id)
+    }
+
+    /// 写回尚未保存的修改后锁定密钥库，之后需要重新输入口令
+    pub async fn lock(&self) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        if let Some(key_store) = store.as_mut() {
+            key_store.flush()?;
+        }
+        *store = None;
+        Ok(())
+    }
+
+    /// 开始批量修改密钥库，end_batch 时一次写回
+    pub async fn begin_batch(&self) {
+        if let Some(key_store) = self.key_store.lock().await.as_mut() {
+            key_store.begin_batch();
+        }
+    }
+
+    /// 结束批量修改并写回
+    pub async fn end_batch(&self) -> Result<(), Error> {
+        match self.key_store.lock().await.as_mut() {
+            Some(key_store) => key_store.end_batch(),
+            None => Ok(()),
         }
+    }
+
+    /// 用户之前发送或读过的消息明文，content 为消息（或历史版本）的密文
+    pub async fn cached_plaintext(&self, user_id: &str, message: &Message, content: &str) -> Result<Option<String>, Error> {
+        let Some(message_ref) = one_time_message_ref(content, &message.sender_id) else {
+            return Ok(None);
+        };
         
-        // 生成新密钥对
-        let mut encryption = self.encryption.lock().map_err(|_| 
-            Error::Internal("Failed to lock encryption".to_string()))?;
+        let store = self.key_store.lock().await;
+        let key_store = store.as_ref().ok_or_else(keystore_locked)?;
+        
+        Ok(key_store.cached_plaintext(user_id, &message_ref).map(|p| p.to_string()))
+    }
+
+    /// 缓存用户发送或读过的消息明文；只缓存使用一次性消息密钥的密文，会话密钥加密的内容可以重新解密
+    pub async fn cache_plaintext(&self, user_id: &str, message: &Message, content: &str, plaintext: &str) -> Result<(), Error> {
+        let Some(message_ref) = one_time_message_ref(content, &message.sender_id) else {
+            return Ok(());
+        };
+        
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        key_store.cache_plaintext(
+            &message.conversation_id, 
+            user_id, 
+            &message.id, 
+            &message_ref, 
+            plaintext, 
+            message.expires_at
+        )
+    }
+
+    /// 删除撤回消息的缓存明文
+    pub async fn forget_plaintexts(&self, message_id: &str) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
         
-        let key_pair = encryption.generate_key_pair()
-            .map_err(|e| Error::Encryption(format!("Failed to generate key pair: {:?}", e)))?;
+        key_store.forget_plaintexts(message_id)
+    }
+
+    /// 删除用户在会话中缓存的所有明文，用于隐藏私聊；密钥库未解锁时无法读取缓存，直接跳过
+    pub async fn forget_conversation_plaintexts(&self, conversation_id: &str, user_id: &str) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
         
-        let public_key = key_pair.public_key.clone();
-        store.insert(user_id.to_string(), key_pair);
+        match store.as_mut() {
+            Some(key_store) => key_store.forget_conversation_plaintexts(conversation_id, user_id),
+            None => Ok(()),
+        }
+    }
+
+    /// 密钥库未解锁时返回错误
+    pub async fn ensure_unlocked(&self) -> Result<(), Error> {
+        self.key_store.lock().await.as_ref().map(|_| ()).ok_or_else(keystore_locked)
+    }
+
+    pub async fn get_or_create_key_pair(&self, user_id: &str) -> Result<(PublicKey, bool), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
         
-        Ok((public_key, true))
+        key_store.get_or_create_identity(user_id)
     }
 
     pub async fn derive_shared_secret(
@@ -110,20 +203,334 @@ impl KeyManager {
         user_id: &str, 
         peer_public_key: &PublicKey
     ) -> Result<SharedSecret, Error> {
-        let mut store = self.key_store.lock().await;
+        let store = self.key_store.lock().await;
+        let key_store = store.as_ref().ok_or_else(keystore_locked)?;
         
-        let key_pair = store.get_mut(user_id).ok_or_else(|| 
+        let private_key = key_store.identity(user_id).ok_or_else(|| 
             Error::Internal(format!("No key pair found for user {}", user_id)))?;
         
         let encryption = self.encryption.lock().map_err(|_| 
             Error::Internal("Failed to lock encryption".to_string()))?;
         
-        let private_key = std::mem::replace(&mut key_pair.private_key, EphemeralSecret::random_from_rng(&mut OsRng));
-        
-        encryption.derive_shared_secret(private_key, peer_public_key)
+        encryption.derive_shared_secret(&private_key, peer_public_key)
             .map_err(|e| Error::Encryption(format!("Failed to derive shared secret: {:?}", e)))
     }
 
+    /// 生成用户在本设备上的公钥包，身份密钥、签名密钥和签名预共享密钥缺失时一并生成
+    pub async fn create_key_bundle(&self, user_id: &str) -> Result<KeyBundle, Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let (identity_key, _) = key_store.get_or_create_identity(user_id)?;
+        let signing_seed = key_store.get_or_create_signing_seed(user_id)?;
+        let (key_id, prekey) = key_store.current_signed_prekey(user_id)?;
+        let signature = encryption::sign_prekey(&signing_seed, &identity_key, key_id, &prekey)?;
+        
+        Ok(KeyBundle {
+            user_id: user_id.to_string(),
+            device_id: key_store.device_id().to_string(),
+            identity_key: identity_key.as_bytes().to_vec(),
+            signing_key: encryption::signing_public_key(&signing_seed)?,
+            signed_prekey: SignedPreKey {
+                key_id,
+                public_key: prekey.as_bytes().to_vec(),
+                signature,
+            },
+            updated_at: Utc::now(),
+        })
+    }
+
+    /// 用户在会话中协商密钥的对端，没有记录时返回 None
+    pub async fn session_peer(&self, conversation_id: &str, user_id: &str) -> Result<Option<String>, Error> {
+        let store = self.key_store.lock().await;
+        let key_store = store.as_ref().ok_or_else(keystore_locked)?;
+        
+        Ok(key_store.sessions().iter()
+            .find(|s| s.conversation_id == conversation_id && s.user_id == user_id)
+            .map(|s| s.peer_id.clone()))
+    }
+
+    /// 记录用户在会话中与哪位对端协商了密钥
+    pub async fn bind_session(&self, conversation_id: &str, user_id: &str, peer_id: &str) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        key_store.bind_session(SessionBinding {
+            conversation_id: conversation_id.to_string(),
+            user_id: user_id.to_string(),
+            peer_id: peer_id.to_string(),
+        })
+    }
+
+    /// 用户验证对端时对端的身份公钥，未验证时为空
+    pub async fn verified_identity(&self, user_id: &str, peer_id: &str) -> Result<Option<Vec<u8>>, Error> {
+        let store = self.key_store.lock().await;
+        let key_store = store.as_ref().ok_or_else(keystore_locked)?;
+        
+        Ok(key_store.verified_identity(user_id, peer_id).map(|key| key.to_vec()))
+    }
+
+    /// 记录用户已验证对端的身份公钥
+    pub async fn set_verified_identity(&self, user_id: &str, peer_id: &str, identity_key: &[u8]) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        key_store.set_verified_identity(user_id, peer_id, identity_key)
+    }
+
+    /// 删除会话中的密钥派生关系，user_id 为空时删除整个会话的记录
+    pub async fn remove_sessions(&self, conversation_id: &str, user_id: Option<&str>) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        
+        // 密钥库未解锁时本来就无法恢复会话密钥
+        match store.as_mut() {
+            Some(key_store) => key_store.remove_sessions(conversation_id, user_id),
+            None => Ok(()),
+        }
+    }
+
+    /// 用户在会话中是否已有 Double Ratchet 会话，session_id 为空时检查任意一个
+    pub async fn has_ratchet_session(&self, conversation_id: &str, user_id: &str, session_id: Option<&str>) -> Result<bool, Error> {
+        let store = self.key_store.lock().await;
+        let key_store = store.as_ref().ok_or_else(keystore_locked)?;
+        
+        Ok(key_store.ratchet_session(conversation_id, user_id, session_id).is_some())
+    }
+
+    /// 以发起方身份与对端建立 Double Ratchet 会话，只使用对端公钥包中的公钥
+    pub async fn start_ratchet_session(&self, conversation_id: &str, user_id: &str, peer_bundle: &KeyBundle) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let identity = key_store.identity(user_id).ok_or_else(|| 
+            Error::Internal(format!("No key pair found for user {}", user_id)))?;
+        let identity_key = PublicKey::from(&identity);
+        let peer_identity_key = public_key_from_bytes(&peer_bundle.identity_key)?;
+        let peer_prekey = public_key_from_bytes(&peer_bundle.signed_prekey.public_key)?;
+        
+        let (shared_secret, ephemeral_key) = encryption::x3dh_initiate(&identity, &peer_identity_key, &peer_prekey);
+        let associated_data = [identity_key.as_bytes().as_slice(), peer_identity_key.as_bytes()].concat();
+        
+        key_store.save_ratchet_session(RatchetSession {
+            conversation_id: conversation_id.to_string(),
+            user_id: user_id.to_string(),
+            peer_id: peer_bundle.user_id.clone(),
+            session_id: URL_SAFE_NO_PAD.encode(ephemeral_key.as_bytes()),
+            state: RatchetState::initiate(shared_secret, &peer_prekey, associated_data),
+            pending_prekey: Some(X3dhInit {
+                identity_key: identity_key.as_bytes().to_vec(),
+                ephemeral_key: ephemeral_key.as_bytes().to_vec(),
+                signed_prekey_id: peer_bundle.signed_prekey.key_id,
+            }),
+            updated_at: Utc::now(),
+        })
+    }
+
+    /// 用最近使用的 Double Ratchet 会话加密，返回序列化后的密文
+    pub async fn ratchet_encrypt(&self, conversation_id: &str, user_id: &str, content: &str) -> Result<String, Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let mut session = key_store.ratchet_session(conversation_id, user_id, None).cloned()
+            .ok_or_else(|| Error::Encryption(format!(
+                "No ratchet session for user {} in conversation {}", user_id, conversation_id
+            )))?;
+        
+        let (header, encrypted) = session.state.encrypt(content)
+            .map_err(|e| Error::Encryption(format!("Failed to encrypt message: {:?}", e)))?;
+        let envelope = RatchetEnvelope {
+            session_id: session.session_id.clone(),
+            prekey: session.pending_prekey.clone(),
+            header,
+            ciphertext: encrypted.ciphertext,
+            nonce: encrypted.nonce,
+        };
+        
+        session.updated_at = Utc::now();
+        key_store.save_ratchet_session(session)?;
+        
+        serde_json::to_string(&envelope)
+            .map_err(|e| Error::Internal(format!("Failed to serialize encrypted message: {}", e)))
+    }
+
+    /// 解密私聊消息
+    ///
+    /// 对端的新消息推进棘轮，首次收到附带 X3DH 参数的消息时以接收方身份建立会话。
+    /// 消息密钥用后即丢弃，读过的消息和自己发出的消息由调用方从缓存的明文中读取。
+    pub async fn ratchet_decrypt(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        sender_id: &str, 
+        envelope: &RatchetEnvelope
+    ) -> Result<String, Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let encrypted = EncryptedMessage {
+            ciphertext: envelope.ciphertext.clone(),
+            nonce: envelope.nonce.clone(),
+        };
+        let existing = key_store.ratchet_session(conversation_id, user_id, Some(&envelope.session_id)).cloned();
+        
+        if sender_id == user_id {
+            return Err(Error::Encryption("Sent message is not cached on this device".to_string()));
+        }
+        
+        let mut session = match (existing, &envelope.prekey) {
+            (Some(session), _) => session,
+            (None, Some(init)) => accept_ratchet_session(key_store, conversation_id, user_id, sender_id, &envelope.session_id, init)?,
+            (None, None) => return Err(Error::Encryption(format!(
+                "Unknown ratchet session {}", envelope.session_id
+            ))),
+        };
+        
+        let plaintext = session.state.decrypt(&envelope.header, &encrypted)
+            .map_err(|e| Error::Encryption(format!("Failed to decrypt message: {:?}", e)))?;
+        
+        // 收到对端的消息说明对端已建立会话，之后不再附带 X3DH 参数
+        session.pending_prekey = None;
+        session.updated_at = Utc::now();
+        key_store.save_ratchet_session(session)?;
+        
+        Ok(plaintext)
+    }
+
+    /// 准备用户在群聊中的发送者密钥，没有或代数落后于会话时生成新的
+    ///
+    /// 返回当前的分发内容，以及还没有收到该密钥的成员。
+    pub async fn prepare_sender_key(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        epoch: u32, 
+        participants: &[String]
+    ) -> Result<(SenderKeyShare, Vec<String>), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let record = match key_store.sender_key(conversation_id, user_id, user_id, None) {
+            Some(record) if record.epoch >= epoch => record.clone(),
+            _ => {
+                debug!("Rotating sender key for user {} in conversation {}", user_id, conversation_id);
+                let record = SenderKeyRecord {
+                    conversation_id: conversation_id.to_string(),
+                    user_id: user_id.to_string(),
+                    sender_id: user_id.to_string(),
+                    epoch,
+                    state: SenderKeyState::generate()?,
+                    distributed_to: Vec::new(),
+                    created_at: Utc::now(),
+                };
+                key_store.save_sender_key(record.clone())?;
+                record
+            }
+        };
+        
+        let pending = participants.iter()
+            .filter(|p| p.as_str() != user_id && !record.distributed_to.contains(p))
+            .cloned()
+            .collect();
+        
+        Ok((record.state.share(), pending))
+    }
+
+    /// 记录自己的发送者密钥已分发给哪些成员
+    pub async fn mark_sender_key_distributed(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        key_id: u32, 
+        recipients: &[String]
+    ) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let Some(mut record) = key_store.sender_key(conversation_id, user_id, user_id, Some(key_id)).cloned() else {
+            return Ok(());
+        };
+        record.distributed_to.extend(recipients.iter().cloned());
+        key_store.save_sender_key(record)
+    }
+
+    /// 用自己最新的发送者密钥加密群聊消息，返回序列化后的密文
+    pub async fn sender_key_encrypt(&self, conversation_id: &str, user_id: &str, content: &str) -> Result<String, Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let mut record = key_store.sender_key(conversation_id, user_id, user_id, None).cloned()
+            .ok_or_else(|| Error::Encryption(format!(
+                "No sender key for user {} in conversation {}", user_id, conversation_id
+            )))?;
+        
+        let message = record.state.encrypt(content)
+            .map_err(|e| Error::Encryption(format!("Failed to encrypt message: {:?}", e)))?;
+        key_store.save_sender_key(record)?;
+        
+        serde_json::to_string(&message)
+            .map_err(|e| Error::Internal(format!("Failed to serialize encrypted message: {}", e)))
+    }
+
+    /// 用户是否持有某位成员的指定发送者密钥
+    pub async fn has_sender_key(&self, conversation_id: &str, user_id: &str, sender_id: &str, key_id: u32) -> Result<bool, Error> {
+        let store = self.key_store.lock().await;
+        let key_store = store.as_ref().ok_or_else(keystore_locked)?;
+        
+        Ok(key_store.sender_key(conversation_id, user_id, sender_id, Some(key_id)).is_some())
+    }
+
+    /// 保存其他成员分发来的发送者密钥
+    pub async fn accept_sender_key(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        sender_id: &str, 
+        share: SenderKeyShare
+    ) -> Result<(), Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        key_store.save_sender_key(SenderKeyRecord {
+            conversation_id: conversation_id.to_string(),
+            user_id: user_id.to_string(),
+            sender_id: sender_id.to_string(),
+            epoch: 0,
+            state: SenderKeyState::from_share(share),
+            distributed_to: Vec::new(),
+            created_at: Utc::now(),
+        })
+    }
+
+    /// 解密群聊消息，消息早于用户拿到的发送者密钥时返回 None
+    pub async fn sender_key_decrypt(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        sender_id: &str, 
+        message: &SenderKeyMessage
+    ) -> Result<Option<String>, Error> {
+        let mut store = self.key_store.lock().await;
+        let key_store = store.as_mut().ok_or_else(keystore_locked)?;
+        
+        let mut record = key_store.sender_key(conversation_id, user_id, sender_id, Some(message.key_id)).cloned()
+            .ok_or_else(|| Error::Encryption(format!(
+                "No sender key {} from user {}", message.key_id, sender_id
+            )))?;
+        
+        if sender_id == user_id {
+            return Err(Error::Encryption("Sent message is not cached on this device".to_string()));
+        }
+        if record.state.precedes(message) {
+            return Ok(None);
+        }
+        
+        let plaintext = record.state.decrypt(message)
+            .map_err(|e| Error::Encryption(format!("Failed to decrypt message: {:?}", e)))?;
+        key_store.save_sender_key(record)?;
+        
+        Ok(Some(plaintext))
+    }
+
     pub fn encrypt_message(
         &self, 
         content: &str, 
@@ -149,11 +556,56 @@ impl KeyManager {
     }
 }
 
+/// 默认的消息撤回时限（秒）
+pub const DEFAULT_RETRACT_WINDOW_SECS: i64 = 2 * 60 * 60;
+
+/// 引用预览保留的最大字符数
+const REPLY_PREVIEW_MAX_CHARS: usize = 100;
+
+/// 表情回应允许的最大字符数（组合表情由多个字符组成）
+const MAX_REACTION_CHARS: usize = 16;
+
+/// 搜索时从数据库取出的最大候选消息数
+const MAX_SEARCH_CANDIDATES: i64 = 500;
+
+/// 单次最多转发的消息数
+const MAX_FORWARD_MESSAGES: usize = 100;
+
+/// 定时消息最多尝试发送的次数
+const MAX_SCHEDULED_SEND_ATTEMPTS: u32 = 3;
+
+/// 定时消息发送失败后重试的间隔（秒），按尝试次数递增
+const SCHEDULED_RETRY_DELAY_SECS: i64 = 60;
+
+/// 阅后即焚允许设置的最长保留时长（秒）
+pub const MAX_MESSAGE_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// 每轮清理最多删除的过期消息数
+const EXPIRED_PURGE_BATCH_SIZE: i64 = 500;
+
+/// 群名称的最大长度（字符）
+const MAX_GROUP_NAME_CHARS: usize = 64;
+
+/// 群简介的最大长度（字符）
+const MAX_GROUP_DESCRIPTION_CHARS: usize = 500;
+
+/// 邀请链接允许设置的最长有效期（秒）
+const MAX_INVITE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// 邀请 token 的随机字节数
+const INVITE_TOKEN_BYTES: usize = 24;
+
+/// 每个会话最多置顶的消息数
+pub const MAX_PINNED_MESSAGES: usize = 10;
+
 /// 聊天会话管理器
 pub struct ChatManager {
     db: ChatDatabase,
     key_manager: Arc<KeyManager>,
     session_keys: Arc<SessionKeyStore>,
+    search_index: LocalSearchIndex,
+    retract_window: Duration,
+    keystore_path: Option<PathBuf>,
 }
 
 impl ChatManager {
@@ -162,7 +614,167 @@ impl ChatManager {
             db,
             key_manager: Arc::new(KeyManager::new()),
             session_keys: Arc::new(SessionKeyStore::new()),
+            search_index: LocalSearchIndex::new(),
+            retract_window: Duration::seconds(DEFAULT_RETRACT_WINDOW_SECS),
+            keystore_path: None,
+        }
+    }
+
+    /// 设置发送者为所有人撤回消息的时限
+    pub fn with_retract_window(mut self, retract_window: Duration) -> Self {
+        self.retract_window = retract_window;
+        self
+    }
+
+    /// 设置本地密钥库文件的位置
+    pub fn with_keystore_path(mut self, keystore_path: PathBuf) -> Self {
+        self.keystore_path = Some(keystore_path);
+        self
+    }
+
+    /// 用口令解锁本地密钥库，返回本设备的标识
+    ///
+    /// 会话密钥不随密钥库保存，首次使用时按记录的派生关系重新计算。
+    pub async fn unlock_keystore(&self, passphrase: &str) -> Result<String, Error> {
+        let path = self.keystore_path.as_deref()
+            .ok_or_else(|| Error::Internal("Keystore path is not configured".to_string()))?;
+        
+        self.key_manager.unlock(path, passphrase).await
+    }
+
+    /// 锁定本地密钥库并清除内存中的会话密钥
+    pub async fn lock_keystore(&self) -> Result<(), Error> {
+        self.key_manager.lock().await?;
+        self.session_keys.clear()
+    }
+
+    /// 把用户在本设备上的公钥包发布到公钥目录，签名预共享密钥到期时顺带轮换
+    pub async fn publish_key_bundle(&self, user_id: &str) -> Result<KeyBundle, Error> {
+        let bundle = self.key_manager.create_key_bundle(user_id).await?;
+        self.db.publish_key_bundle(&bundle).await?;
+        
+        debug!("Published key bundle for user {} on device {}", user_id, bundle.device_id);
+        Ok(bundle)
+    }
+
+    /// 从公钥目录获取用户最近发布的公钥包，签名无效时拒绝使用
+    pub async fn get_key_bundle(&self, user_id: &str) -> Result<KeyBundle, Error> {
+        let bundle = self.db.get_key_bundle(user_id).await?
+            .ok_or_else(|| Error::NotFound(format!("No key bundle published for user {}", user_id)))?;
+        
+        encryption::verify_prekey_signature(
+            &bundle.signing_key,
+            &bundle.identity_key,
+            bundle.signed_prekey.key_id,
+            &bundle.signed_prekey.public_key,
+            &bundle.signed_prekey.signature,
+        )?;
+        
+        Ok(bundle)
+    }
+
+    /// 获取用户与会话中另一成员的安全码
+    pub async fn get_safety_number(&self, conversation_id: &str, user_id: &str, peer_id: &str) -> Result<SafetyNumber, Error> {
+        let (safety_number, _) = self.compute_safety_number(conversation_id, user_id, peer_id).await?;
+        Ok(safety_number)
+    }
+
+    /// 确认安全码比对一致，把对方当前的身份公钥记为已验证
+    ///
+    /// safety_number 是用户比对过的数字安全码或扫描到的二维码内容，必须与对方当前的身份公钥对应，
+    /// 避免比对之后对方的密钥恰好发生变化而被误记为已验证。
+    pub async fn mark_peer_verified(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        peer_id: &str, 
+        safety_number: &str
+    ) -> Result<SafetyNumber, Error> {
+        debug!("User {} verifying peer {} in conversation {}", user_id, peer_id, conversation_id);
+        
+        let (mut result, peer_identity_key) = self.compute_safety_number(conversation_id, user_id, peer_id).await?;
+        
+        if !encryption::safety_number_matches(&result.numeric_code, &result.qr_payload, safety_number) {
+            return Err(Error::Validation("Safety number does not match".to_string()));
+        }
+        
+        self.key_manager.set_verified_identity(user_id, peer_id, &peer_identity_key).await?;
+        result.verified = true;
+        result.key_changed = false;
+        
+        Ok(result)
+    }
+
+    /// 计算安全码，同时返回对方当前的身份公钥
+    async fn compute_safety_number(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        peer_id: &str
+    ) -> Result<(SafetyNumber, Vec<u8>), Error> {
+        if user_id == peer_id {
+            return Err(Error::Validation("Cannot verify your own identity".to_string()));
+        }
+        
+        let conversation = self.get_conversation_for_participant(conversation_id, user_id).await?;
+        if !conversation.participants.contains(&peer_id.to_string()) {
+            return Err(Error::Validation(
+                format!("User {} is not a participant in conversation {}", peer_id, conversation_id)
+            ));
+        }
+        
+        let (identity_key, created) = self.key_manager.get_or_create_key_pair(user_id).await?;
+        if created {
+            self.publish_key_bundle(user_id).await?;
+        }
+        let peer_bundle = self.get_key_bundle(peer_id).await?;
+        
+        let (numeric_code, qr_payload) = encryption::safety_number(
+            user_id, 
+            identity_key.as_bytes(), 
+            peer_id, 
+            &peer_bundle.identity_key
+        );
+        
+        let verified_key = self.key_manager.verified_identity(user_id, peer_id).await?;
+        let verified = verified_key.as_deref() == Some(peer_bundle.identity_key.as_slice());
+        
+        Ok((SafetyNumber {
+            user_id: user_id.to_string(),
+            peer_id: peer_id.to_string(),
+            numeric_code,
+            qr_payload,
+            verified,
+            key_changed: verified_key.is_some() && !verified,
+        }, peer_bundle.identity_key))
+    }
+
+    /// 用户验证过、但身份公钥随后发生变化的会话成员
+    async fn identity_key_changes(&self, conversation: &Conversation, user_id: &str) -> Result<Vec<String>, Error> {
+        if !conversation.encryption_enabled {
+            return Ok(Vec::new());
+        }
+        
+        let mut verified = Vec::new();
+        for peer_id in conversation.participants.iter().filter(|p| p.as_str() != user_id) {
+            if let Some(verified_key) = self.key_manager.verified_identity(user_id, peer_id).await? {
+                verified.push((peer_id.clone(), verified_key));
+            }
+        }
+        if verified.is_empty() {
+            return Ok(Vec::new());
         }
+        
+        let peer_ids: Vec<String> = verified.iter().map(|(peer_id, _)| peer_id.clone()).collect();
+        let bundles = self.db.get_key_bundles(&peer_ids).await?;
+        
+        // 公钥包被删除同样无法确认对方身份，按密钥变化处理
+        Ok(verified.into_iter()
+            .filter(|(peer_id, verified_key)| {
+                bundles.get(peer_id).map_or(true, |bundle| &bundle.identity_key != verified_key)
+            })
+            .map(|(peer_id, _)| peer_id)
+            .collect())
     }
 
     /// 创建新的聊天会话
@@ -174,12 +786,55 @@ impl ChatManager {
             return Err(Error::Validation("Conversation must have at least 2 participants".to_string()));
         }
         
-        // 创建会话
-        let conversation = self.db.create_conversation(new_conversation).await?;
+        // 同一对用户只有一个私聊
+        if new_conversation.conversation_type == ConversationType::Direct {
+            return match new_conversation.participants.as_slice() {
+                [user_a, peer_id] => {
+                    self.get_or_create_direct_conversation(user_a, peer_id, new_conversation.encryption_enabled).await
+                }
+                _ => Err(Error::Validation("Direct conversations must have exactly 2 participants".to_string())),
+            };
+        }
+        
+        // 创建会话，加密会话的密钥在各成员首次收发消息时派生
+        self.db.create_conversation(new_conversation).await
+    }
+
+    /// 获取与另一用户的私聊，不存在时创建
+    ///
+    /// 已存在的私聊原样返回，encryption_enabled 只在新建时生效；
+    /// 调用者之前删除（隐藏）过该私聊时，重新打开会让它回到会话列表。
+    pub async fn get_or_create_direct_conversation(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+        encryption_enabled: bool,
+    ) -> Result<Conversation, Error> {
+        debug!("Getting or creating direct conversation between {} and {}", user_id, peer_id);
+        
+        if user_id == peer_id {
+            return Err(Error::Validation("Cannot start a direct conversation with yourself".to_string()));
+        }
+        
+        if let Some(conversation) = self.db.find_direct_conversation(user_id, peer_id).await? {
+            self.db.unhide_conversation_for_user(&conversation.id, user_id).await?;
+            return Ok(conversation);
+        }
+        
+        let new_conversation = NewConversation {
+            name: None,
+            conversation_type: ConversationType::Direct,
+            participants: vec![user_id.to_string(), peer_id.to_string()],
+            encryption_enabled,
+            roles: HashMap::new(),
+        };
+        
+        // 并发创建时数据库返回已有的私聊
+        let conversation = self.db.create_direct_conversation(new_conversation).await?;
+        self.db.unhide_conversation_for_user(&conversation.id, user_id).await?;
         
-        // 初始化会话密钥（如果启用加密）
         if conversation.encryption_enabled {
-            self.initialize_conversation_keys(&conversation).await?;
+            self.ensure_session_key(&conversation.id, user_id).await?;
         }
         
         Ok(conversation)
@@ -190,13 +845,36 @@ impl ChatManager {
         self.db.get_conversation(conversation_id).await
     }
 
-    /// 获取用户的所有会话
-    pub async fn get_user_conversations(&self, user_id: &str) -> Result<Vec<Conversation>, Error> {
-        self.db.get_conversations_for_user(user_id).await
+    /// 获取用户的会话，按偏好设置过滤和排序
+    pub async fn get_user_conversations(&self, user_id: &str, filter: &ConversationFilter) -> Result<Vec<Conversation>, Error> {
+        let mut all_preferences = self.db.get_conversation_preferences(user_id).await?;
+        let now = Utc::now();
+        
+        let mut conversations: Vec<Conversation> = self.db.get_conversations_for_user(user_id).await?
+            .into_iter()
+            .filter(|c| filter.matches(all_preferences.get(&c.id), now))
+            .collect();
+        
+        for conversation in conversations.iter_mut() {
+            conversation.preferences = all_preferences.remove(&conversation.id);
+        }
+        preferences::sort_conversations(&mut conversations);
+        
+        // 附上每个会话的未读数和草稿标记
+        let conversation_ids: Vec<String> = conversations.iter().map(|c| c.id.clone()).collect();
+        let unread_counts = self.db.get_unread_counts(user_id, &conversation_ids).await?;
+        let draft_conversation_ids = self.db.get_draft_conversation_ids(user_id).await?;
+        
+        for conversation in conversations.iter_mut() {
+            conversation.unread_count = unread_counts.get(&conversation.id).copied().unwrap_or(0);
+            conversation.has_draft = draft_conversation_ids.contains(&conversation.id);
+        }
+        
+        Ok(conversations)
     }
 
     /// 发送消息
-    pub async fn send_message(&self, new_message: NewMessage, user_id: &str) -> Result<Message, Error> {
+    pub async fn send_message(&self, mut new_message: NewMessage, user_id: &str) -> Result<Message, Error> {
         debug!("Sending message from user {} to conversation {}", 
                user_id, new_message.conversation_id);
         
@@ -207,6 +885,8 @@ impl ChatManager {
             ));
         }
         
+        ensure_not_system_message(&new_message)?;
+        
         // 获取会话
         let conversation = self.db.get_conversation(&new_message.conversation_id).await?
             .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", new_message.conversation_id)))?;
@@ -219,53 +899,1245 @@ impl ChatManager {
             ));
         }
         
-        // 处理加密
-        let processed_message = if conversation.encryption_enabled {
-            self.process_outgoing_encrypted_message(new_message, &conversation).await?
+        // 验证引用和话题
+        self.validate_reply_reference(&new_message).await?;
+        
+        // 已验证成员的身份密钥发生变化时提示用户，严格验证模式下禁止发送
+        let key_changes = self.identity_key_changes(&conversation, user_id).await?;
+        if !key_changes.is_empty() {
+            let strict = self.db.get_conversation_preference(user_id, &conversation.id).await?
+                .map_or(false, |p| p.strict_verification);
+            if strict {
+                return Err(Error::Encryption(format!(
+                    "Identity key changed for verified users: {}; verify again before sending", 
+                    key_changes.join(", ")
+                )));
+            }
+        }
+        
+        // 在加密前从明文解析 @提及，只保留会话参与者
+        new_message.mentions = if new_message.content_type == MessageType::Text {
+            mentions::parse_mentions(&new_message.content, &conversation.participants, user_id)
+        } else {
+            Vec::new()
+        };
+        
+        // 处理加密，保留明文用于本地搜索索引
+        let (processed_message, plaintext) = if conversation.encryption_enabled {
+            let plaintext = new_message.content.clone();
+            (self.process_outgoing_encrypted_message(new_message, &conversation).await?, Some(plaintext))
         } else {
-            new_message
+            (new_message, None)
         };
         
         // 保存消息
         let message = self.db.save_message(processed_message).await?;
         
-        // 更新会话的最后一条消息
-        self.db.update_conversation_last_message(&message.conversation_id, &message).await?;
+        // 消息密钥用后即丢弃，发送者之后从本地缓存读取自己的消息
+        if let Some(plaintext) = &plaintext {
+            self.key_manager.cache_plaintext(user_id, &message, &message.content, plaintext).await?;
+        }
+        
+        // 新消息让归档的会话重新出现在列表中，选择保持归档的用户除外
+        self.db.unarchive_conversation(&message.conversation_id).await?;
+        self.db.unhide_conversation(&message.conversation_id).await?;
         
-        Ok(message)
+        if let Some(plaintext) = plaintext {
+            let mut indexed = message.clone();
+            indexed.content = plaintext;
+            self.search_index.index_messages(&[indexed])?;
+        }
+        
+        if let Some(root_id) = &message.thread_root_id {
+            // 话题回复不进入主时间线，只更新根消息的回复数
+            self.db.increment_thread_reply_count(root_id).await?;
+        } else {
+            // 更新会话的最后一条消息
+            self.db.update_conversation_last_message(&message.conversation_id, &message).await?;
+        }
+        
+        Ok(Message { key_changes, ..message })
     }
 
-    /// 获取会话消息历史
-    pub async fn get_messages(
-        &self,
-        conversation_id: &str,
-        user_id: &str,
-        limit: Option<u32>,
-        before_id: Option<&str>,
-    ) -> Result<Vec<Message>, Error> {
+    /// 验证引用的消息和话题根消息属于同一会话
+    async fn validate_reply_reference(&self, new_message: &NewMessage) -> Result<(), Error> {
+        if let Some(root_id) = &new_message.thread_root_id {
+            let root = self.db.get_message(root_id).await?
+                .ok_or_else(|| Error::NotFound(format!("Thread root message not found: {}", root_id)))?;
+            
+            if root.conversation_id != new_message.conversation_id {
+                return Err(Error::Validation("Thread root belongs to another conversation".to_string()));
+            }
+            
+            // 话题不能嵌套
+            if root.thread_root_id.is_some() {
+                return Err(Error::Validation("Thread root cannot itself be a thread reply".to_string()));
+            }
+        }
+        
+        if let Some(reply_to) = &new_message.reply_to {
+            let target = self.db.get_message(reply_to).await?
+                .ok_or_else(|| Error::NotFound(format!("Replied message not found: {}", reply_to)))?;
+            
+            if target.conversation_id != new_message.conversation_id {
+                return Err(Error::Validation("Replied message belongs to another conversation".to_string()));
+            }
+            
+            // 话题内只能引用同一话题的消息
+            if let Some(root_id) = &new_message.thread_root_id {
+                if &target.id != root_id && target.thread_root_id.as_ref() != Some(root_id) {
+                    return Err(Error::Validation("Replied message is not part of this thread".to_string()));
+                }
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// 安排定时消息，到达 send_at 后由调度器发送
+    pub async fn schedule_message(
+        &self,
+        new_message: NewMessage,
+        user_id: &str,
+        send_at: chrono::DateTime<Utc>,
+    ) -> Result<ScheduledMessage, Error> {
+        debug!("Scheduling message from user {} to conversation {} at {}", 
+               user_id, new_message.conversation_id, send_at);
+        
+        if new_message.sender_id != user_id {
+            return Err(Error::Authentication(
+                "Sender ID does not match authenticated user".to_string()
+            ));
+        }
+        
+        ensure_not_system_message(&new_message)?;
+        
+        let now = Utc::now();
+        if send_at <= now {
+            return Err(Error::Validation("Scheduled time must be in the future".to_string()));
+        }
+        
+        let conversation = self.db.get_conversation(&new_message.conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", new_message.conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation.id)
+            ));
+        }
+        
+        // 尽早发现无效的引用，发送时还会再次校验
+        self.validate_reply_reference(&new_message).await?;
+        
+        let plaintext = new_message.content.clone();
+        
+        // 加密会话中待发送的内容同样不以明文保存，发送时再按会话类型加密
+        let stored_message = if conversation.encryption_enabled {
+            let content = self.encrypt_own_content(&new_message.content, user_id, &conversation.id).await?;
+            NewMessage { content, encrypted: true, ..new_message }
+        } else {
+            NewMessage { encrypted: false, ..new_message }
+        };
+        
+        let mut scheduled = ScheduledMessage {
+            id: Uuid::new_v4().to_string(),
+            sender_id: user_id.to_string(),
+            conversation_id: conversation.id.clone(),
+            message: stored_message,
+            send_at,
+            created_at: now,
+            updated_at: now,
+            status: ScheduledMessageStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            sent_message_id: None,
+        };
+        
+        self.db.save_scheduled_message(&scheduled).await?;
+        
+        // 返回给调用者的是明文内容
+        scheduled.message.content = plaintext;
+        scheduled.message.encrypted = false;
+        Ok(scheduled)
+    }
+
+    /// 获取用户尚未发送的定时消息
+    pub async fn get_scheduled_messages(
+        &self,
+        user_id: &str,
+        conversation_id: Option<&str>,
+    ) -> Result<Vec<ScheduledMessage>, Error> {
+        let scheduled = self.db.get_scheduled_messages_for_user(user_id, conversation_id).await?;
+        
+        let mut decrypted = Vec::with_capacity(scheduled.len());
+        for item in scheduled {
+            decrypted.push(self.decrypt_scheduled_message(item).await?);
+        }
+        
+        Ok(decrypted)
+    }
+
+    /// 修改定时消息的内容或发送时间，只能修改仍在等待发送的消息
+    pub async fn update_scheduled_message(
+        &self,
+        scheduled_id: &str,
+        user_id: &str,
+        content: Option<String>,
+        send_at: Option<chrono::DateTime<Utc>>,
+    ) -> Result<ScheduledMessage, Error> {
+        debug!("Updating scheduled message {} by user {}", scheduled_id, user_id);
+        
+        let scheduled = self.get_own_scheduled_message(scheduled_id, user_id).await?;
+        
+        if !scheduled.status.is_editable() {
+            return Err(Error::Validation("Only pending scheduled messages can be updated".to_string()));
+        }
+        
+        if let Some(send_at) = send_at {
+            if send_at <= Utc::now() {
+                return Err(Error::Validation("Scheduled time must be in the future".to_string()));
+            }
+        }
+        
+        if let Some(content) = &content {
+            if content.trim().is_empty() {
+                return Err(Error::Validation("Message content cannot be empty".to_string()));
+            }
+        }
+        
+        let mut scheduled = self.decrypt_scheduled_message(scheduled).await?;
+        let plaintext = content.unwrap_or_else(|| scheduled.message.content.clone());
+        let send_at = send_at.unwrap_or(scheduled.send_at);
+        
+        let conversation = self.db.get_conversation(&scheduled.conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", scheduled.conversation_id)))?;
+        
+        let stored_content = if conversation.encryption_enabled {
+            self.encrypt_own_content(&plaintext, user_id, &conversation.id).await?
+        } else {
+            plaintext.clone()
+        };
+        
+        // 调度器可能已在此期间取走该消息
+        if !self.db.update_scheduled_message(scheduled_id, &stored_content, send_at).await? {
+            return Err(Error::Validation("Scheduled message is no longer pending".to_string()));
+        }
+        
+        scheduled.message.content = plaintext;
+        scheduled.send_at = send_at;
+        scheduled.updated_at = Utc::now();
+        Ok(scheduled)
+    }
+
+    /// 取消尚未发送的定时消息
+    pub async fn cancel_scheduled_message(&self, scheduled_id: &str, user_id: &str) -> Result<(), Error> {
+        debug!("Cancelling scheduled message {} by user {}", scheduled_id, user_id);
+        
+        self.get_own_scheduled_message(scheduled_id, user_id).await?;
+        
+        if !self.db.cancel_scheduled_message(scheduled_id).await? {
+            return Err(Error::Validation("Scheduled message has already been sent or cancelled".to_string()));
+        }
+        
+        Ok(())
+    }
+
+    /// 发送所有到期的定时消息，返回成功发送的消息
+    ///
+    /// 每条消息先被原子地标记为发送中，多个调度器同时运行也不会重复发送。
+    /// 发送失败时按递增间隔重试，超过最大次数后标记为失败，用户可在列表中看到原因。
+    pub async fn dispatch_due_scheduled_messages(&self) -> Result<Vec<Message>, Error> {
+        let mut sent = Vec::new();
+        
+        while let Some(scheduled) = self.db.claim_due_scheduled_message(Utc::now()).await? {
+            debug!("Dispatching scheduled message {}", scheduled.id);
+            
+            let scheduled_id = scheduled.id.clone();
+            let attempts = scheduled.attempts;
+            
+            let result = match self.decrypt_scheduled_message(scheduled).await {
+                Ok(scheduled) => self.send_message(scheduled.message, &scheduled.sender_id).await,
+                Err(e) => Err(e),
+            };
+            
+            match result {
+                Ok(message) => {
+                    self.db.mark_scheduled_message_sent(&scheduled_id, &message.id).await?;
+                    sent.push(message);
+                }
+                Err(e) => {
+                    warn!("Failed to send scheduled message {}: {}", scheduled_id, e);
+                    
+                    let retry_at = (attempts < MAX_SCHEDULED_SEND_ATTEMPTS)
+                        .then(|| Utc::now() + Duration::seconds(SCHEDULED_RETRY_DELAY_SECS * attempts as i64));
+                    self.db.mark_scheduled_message_failed(&scheduled_id, &e.to_string(), retry_at).await?;
+                }
+            }
+        }
+        
+        Ok(sent)
+    }
+
+    /// 把上次退出时正在发送的定时消息重新排队，调度器启动时调用
+    pub async fn requeue_interrupted_scheduled_messages(&self) -> Result<u64, Error> {
+        self.db.requeue_interrupted_scheduled_messages().await
+    }
+
+    /// 为没有回执的旧消息补建回执，模块启动时调用
+    pub async fn migrate_legacy_receipts(&self) -> Result<u64, Error> {
+        self.db.migrate_legacy_receipts().await
+    }
+
+    /// 获取属于用户的定时消息
+    async fn get_own_scheduled_message(&self, scheduled_id: &str, user_id: &str) -> Result<ScheduledMessage, Error> {
+        let scheduled = self.db.get_scheduled_message(scheduled_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Scheduled message not found: {}", scheduled_id)))?;
+        
+        if scheduled.sender_id != user_id {
+            return Err(Error::Authentication(
+                format!("User {} is not the sender of scheduled message {}", user_id, scheduled_id)
+            ));
+        }
+        
+        Ok(scheduled)
+    }
+
+    /// 用发送者的会话密钥解密定时消息的内容
+    async fn decrypt_scheduled_message(&self, mut scheduled: ScheduledMessage) -> Result<ScheduledMessage, Error> {
+        if scheduled.message.encrypted {
+            self.ensure_session_key(&scheduled.conversation_id, &scheduled.sender_id).await?;
+            scheduled.message.content = self.decrypt_content(
+                &scheduled.message.content,
+                &scheduled.sender_id,
+                &scheduled.conversation_id,
+            )?;
+            scheduled.message.encrypted = false;
+        }
+        
+        Ok(scheduled)
+    }
+
+    /// 归档或取消归档会话，keep_archived 为真时收到新消息也不自动取消归档
+    pub async fn set_conversation_archived(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        archived: bool,
+        keep_archived: Option<bool>,
+    ) -> Result<ConversationPreferences, Error> {
+        debug!("Setting archived={} for conversation {} by user {}", archived, conversation_id, user_id);
+        
+        self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        let mut set = doc! { "archived": archived };
+        if let Some(keep_archived) = keep_archived {
+            set.insert("keep_archived", keep_archived);
+        }
+        
+        self.db.update_conversation_preferences(user_id, conversation_id, set).await
+    }
+
+    /// 静音会话，muted_until 为空表示一直静音；静音期间只有 @提及会通知
+    pub async fn mute_conversation(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        muted_until: Option<chrono::DateTime<Utc>>,
+    ) -> Result<ConversationPreferences, Error> {
+        debug!("Muting conversation {} for user {} until {:?}", conversation_id, user_id, muted_until);
+        
+        if let Some(until) = muted_until {
+            if until <= Utc::now() {
+                return Err(Error::Validation("Mute end time must be in the future".to_string()));
+            }
+        }
+        
+        self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        let muted_until = match muted_until {
+            Some(until) => mongodb::bson::to_bson(&until)
+                .map_err(|e| Error::Internal(format!("Failed to serialize mute end time: {}", e)))?,
+            None => mongodb::bson::Bson::Null,
+        };
+        let set = doc! { "muted": true, "muted_until": muted_until };
+        
+        self.db.update_conversation_preferences(user_id, conversation_id, set).await
+    }
+
+    /// 取消静音
+    pub async fn unmute_conversation(&self, conversation_id: &str, user_id: &str) -> Result<ConversationPreferences, Error> {
+        debug!("Unmuting conversation {} for user {}", conversation_id, user_id);
+        
+        self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        let set = doc! { "muted": false, "muted_until": mongodb::bson::Bson::Null };
+        self.db.update_conversation_preferences(user_id, conversation_id, set).await
+    }
+
+    /// 把会话置顶到列表顶部或取消置顶
+    pub async fn pin_conversation(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        pinned: bool,
+    ) -> Result<ConversationPreferences, Error> {
+        debug!("Setting pinned={} for conversation {} by user {}", pinned, conversation_id, user_id);
+        
+        self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        self.db.update_conversation_preferences(user_id, conversation_id, doc! { "pinned": pinned }).await
+    }
+
+    /// 设置会话的自定义排序值，为空表示按最近更新时间排序
+    pub async fn set_conversation_sort_order(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        sort_order: Option<i64>,
+    ) -> Result<ConversationPreferences, Error> {
+        debug!("Setting sort order {:?} for conversation {} by user {}", sort_order, conversation_id, user_id);
+        
+        self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        self.db.update_conversation_preferences(user_id, conversation_id, doc! { "sort_order": sort_order }).await
+    }
+
+    /// 开启或关闭严格验证，开启后已验证成员的身份密钥变化时禁止发送消息
+    pub async fn set_strict_verification(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        enabled: bool,
+    ) -> Result<ConversationPreferences, Error> {
+        debug!("Setting strict verification={} for conversation {} by user {}", enabled, conversation_id, user_id);
+        
+        self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        self.db.update_conversation_preferences(user_id, conversation_id, doc! { "strict_verification": enabled }).await
+    }
+
+    /// 判断是否应就某条消息通知用户，静音会话中只有 @提及会通知
+    pub async fn should_notify(&self, message_id: &str, user_id: &str) -> Result<bool, Error> {
+        let (message, conversation) = self.db.get_message_for_participant(message_id, user_id).await?;
+        
+        if message.deleted_for.iter().any(|u| u == user_id) || message.retracted_at.is_some() {
+            return Ok(false);
+        }
+        
+        let all_preferences = self.db.get_conversation_preferences(user_id).await?;
+        
+        Ok(preferences::should_notify(all_preferences.get(&conversation.id), &message, user_id, Utc::now()))
+    }
+
+    /// 获取会话并确保用户是会话参与者
+    async fn get_conversation_for_participant(&self, conversation_id: &str, user_id: &str) -> Result<Conversation, Error> {
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation_id)
+            ));
+        }
+        
+        Ok(conversation)
+    }
+
+    /// 保存用户在会话中的草稿，内容和媒体都为空时等同于清除草稿
+    pub async fn save_draft(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        content: String,
+        media_urls: Vec<String>,
+    ) -> Result<Option<Draft>, Error> {
+        debug!("Saving draft for user {} in conversation {}", user_id, conversation_id);
+        
+        if content.trim().is_empty() && media_urls.is_empty() {
+            self.clear_draft(conversation_id, user_id).await?;
+            return Ok(None);
+        }
+        
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation_id)
+            ));
+        }
+        
+        let mut draft = Draft {
+            user_id: user_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            content,
+            media_urls,
+            encrypted: false,
+            updated_at: Utc::now(),
+        };
+        
+        // 加密会话的草稿不以明文保存
+        if conversation.encryption_enabled {
+            let mut stored = draft.clone();
+            stored.content = self.encrypt_own_content(&draft.content, user_id, conversation_id).await?;
+            stored.media_urls = draft.media_urls.iter()
+                .map(|url| self.encrypt_content(url, user_id, conversation_id))
+                .collect::<Result<_, _>>()?;
+            stored.encrypted = true;
+            self.db.save_draft(&stored).await?;
+        } else {
+            self.db.save_draft(&draft).await?;
+        }
+        
+        draft.encrypted = false;
+        Ok(Some(draft))
+    }
+
+    /// 获取用户在会话中的草稿
+    pub async fn get_draft(&self, conversation_id: &str, user_id: &str) -> Result<Option<Draft>, Error> {
+        let Some(mut draft) = self.db.get_draft(user_id, conversation_id).await? else {
+            return Ok(None);
+        };
+        
+        if draft.encrypted {
+            self.ensure_session_key(conversation_id, user_id).await?;
+            draft.content = self.decrypt_content(&draft.content, user_id, conversation_id)?;
+            draft.media_urls = draft.media_urls.iter()
+                .map(|url| self.decrypt_content(url, user_id, conversation_id))
+                .collect::<Result<_, _>>()?;
+            draft.encrypted = false;
+        }
+        
+        Ok(Some(draft))
+    }
+
+    /// 清除用户在会话中的草稿
+    pub async fn clear_draft(&self, conversation_id: &str, user_id: &str) -> Result<(), Error> {
+        debug!("Clearing draft for user {} in conversation {}", user_id, conversation_id);
+        
+        self.db.delete_draft(user_id, conversation_id).await
+    }
+
+    /// 获取会话消息历史
+    pub async fn get_messages(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        limit: Option<u32>,
+        before_id: Option<&str>,
+    ) -> Result<MessagesPage, Error> {
         debug!("Retrieving messages for conversation {} by user {}", 
                conversation_id, user_id);
         
-        // 验证用户是会话参与者
-        let conversation = self.db.get_conversation(conversation_id).await?
-            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        // 验证用户是会话参与者
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation_id)
+            ));
+        }
+        
+        // 获取消息
+        let messages = self.db.get_messages(conversation_id, user_id, limit, before_id).await?;
+        
+        let messages = self.prepare_messages_for_user(messages, user_id, &conversation).await?;
+        
+        // 身份密钥变化的提示放在整页上，没有消息的页也能提示
+        let key_changes = self.identity_key_changes(&conversation, user_id).await?;
+        
+        Ok(MessagesPage { messages, key_changes })
+    }
+
+    /// 为请求用户准备消息：解密、生成引用预览、汇总表情回应
+    async fn prepare_messages_for_user(
+        &self,
+        messages: Vec<Message>,
+        user_id: &str,
+        conversation: &Conversation,
+    ) -> Result<Vec<Message>, Error> {
+        // 已过期但尚未被清理的消息不返回
+        let now = Utc::now();
+        let messages: Vec<Message> = messages.into_iter()
+            .filter(|m| !m.is_expired(now))
+            .collect();
+        
+        // 如果会话启用了加密，解密消息并加入本地搜索索引
+        let mut messages = if conversation.encryption_enabled {
+            let decrypted = self.process_incoming_encrypted_messages(messages, user_id).await?;
+            self.search_index.index_messages(&decrypted)?;
+            decrypted
+        } else {
+            messages
+        };
+        
+        self.attach_reply_previews(&mut messages, user_id).await?;
+        
+        for message in messages.iter_mut() {
+            message.status = Some(aggregate_status(message, &conversation.participants));
+            message.reaction_counts = aggregate_reactions(&message.reactions);
+        }
+        
+        Ok(messages)
+    }
+
+    /// 获取提及用户的消息，跨用户当前所在的所有会话
+    pub async fn get_mentions(
+        &self,
+        user_id: &str,
+        limit: Option<u32>,
+        before_id: Option<&str>,
+    ) -> Result<Vec<Message>, Error> {
+        debug!("Retrieving mentions for user {}", user_id);
+        
+        let conversations: HashMap<String, Conversation> = self.db.get_conversations_for_user(user_id).await?
+            .into_iter()
+            .map(|c| (c.id.clone(), c))
+            .collect();
+        let conversation_ids: Vec<String> = conversations.keys().cloned().collect();
+        
+        let messages = self.db.get_mentions(user_id, &conversation_ids, limit, before_id).await?;
+        
+        // 消息来自不同会话，按各自会话解密
+        let mut prepared = Vec::with_capacity(messages.len());
+        for message in messages {
+            let Some(conversation) = conversations.get(&message.conversation_id) else { continue };
+            prepared.extend(self.prepare_messages_for_user(vec![message], user_id, conversation).await?);
+        }
+        
+        Ok(prepared)
+    }
+
+    /// 获取话题：根消息及分页的回复
+    pub async fn get_thread(
+        &self,
+        root_id: &str,
+        user_id: &str,
+        limit: Option<u32>,
+        before_id: Option<&str>,
+    ) -> Result<MessageThread, Error> {
+        debug!("Retrieving thread {} by user {}", root_id, user_id);
+        
+        let root = self.db.get_message(root_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Message not found: {}", root_id)))?;
+        
+        if root.thread_root_id.is_some() {
+            return Err(Error::Validation(format!("Message {} is not a thread root", root_id)));
+        }
+        
+        let conversation = self.db.get_conversation(&root.conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", root.conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation.id)
+            ));
+        }
+        
+        let replies = self.db.get_thread_replies(root_id, user_id, limit, before_id).await?;
+        
+        // 根消息与回复一起解密并生成引用预览
+        let mut messages = Vec::with_capacity(replies.len() + 1);
+        messages.push(root);
+        messages.extend(replies);
+        
+        let mut messages = self.prepare_messages_for_user(messages, user_id, &conversation).await?;
+        
+        let root = messages.remove(0);
+        Ok(MessageThread { root, replies: messages })
+    }
+
+    /// 为引用了其他消息的消息生成预览，加密内容按请求用户解密
+    async fn attach_reply_previews(
+        &self,
+        messages: &mut [Message],
+        user_id: &str,
+    ) -> Result<(), Error> {
+        let reply_ids: Vec<String> = messages.iter()
+            .filter_map(|m| m.reply_to.clone())
+            .collect();
+        
+        if reply_ids.is_empty() {
+            return Ok(());
+        }
+        
+        let quoted = self.db.get_messages_by_ids(&reply_ids).await?;
+        let quoted = self.process_incoming_encrypted_messages(quoted, user_id).await?;
+        let quoted: HashMap<String, Message> = quoted.into_iter()
+            .map(|m| (m.id.clone(), m))
+            .collect();
+        
+        for message in messages.iter_mut() {
+            let Some(reply_to) = &message.reply_to else { continue };
+            
+            message.reply_preview = quoted.get(reply_to).map(|target| ReplyPreview {
+                message_id: target.id.clone(),
+                sender_id: target.sender_id.clone(),
+                content: target.content.chars().take(REPLY_PREVIEW_MAX_CHARS).collect(),
+                content_type: target.content_type.clone(),
+                retracted: target.retracted_at.is_some(),
+            });
+        }
+        
+        Ok(())
+    }
+
+    /// 置顶消息，返回所在会话和置顶记录
+    pub async fn pin_message(
+        &self,
+        message_id: &str,
+        user_id: &str,
+    ) -> Result<(Conversation, PinnedMessage), Error> {
+        debug!("Pinning message {} by user {}", message_id, user_id);
+        
+        let (message, conversation) = self.db.get_message_for_participant(message_id, user_id).await?;
+        self.ensure_can_pin(&conversation, user_id)?;
+        
+        if message.retracted_at.is_some() {
+            return Err(Error::Validation("Cannot pin a retracted message".to_string()));
+        }
+        
+        if conversation.pinned_messages.iter().any(|p| p.message_id == message_id) {
+            return Err(Error::Validation(format!("Message {} is already pinned", message_id)));
+        }
+        
+        let pin = PinnedMessage {
+            message_id: message_id.to_string(),
+            pinned_by: user_id.to_string(),
+            pinned_at: Utc::now(),
+            message: None,
+        };
+        
+        if !self.db.pin_message(&conversation.id, &pin, MAX_PINNED_MESSAGES).await? {
+            return Err(Error::Validation(format!(
+                "A conversation can have at most {} pinned messages", MAX_PINNED_MESSAGES
+            )));
+        }
+        
+        Ok((conversation, pin))
+    }
+
+    /// 取消置顶消息，返回所在会话
+    pub async fn unpin_message(
+        &self,
+        message_id: &str,
+        user_id: &str,
+    ) -> Result<Conversation, Error> {
+        debug!("Unpinning message {} by user {}", message_id, user_id);
+        
+        let (_, conversation) = self.db.get_message_for_participant(message_id, user_id).await?;
+        self.ensure_can_pin(&conversation, user_id)?;
+        
+        if !self.db.unpin_message(&conversation.id, message_id).await? {
+            return Err(Error::NotFound(format!("Message {} is not pinned", message_id)));
+        }
+        
+        Ok(conversation)
+    }
+
+    /// 获取会话中置顶的消息，最新置顶的在前
+    pub async fn get_pinned_messages(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+    ) -> Result<Vec<PinnedMessage>, Error> {
+        debug!("Getting pinned messages in conversation {} for user {}", conversation_id, user_id);
+        
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation_id)
+            ));
+        }
+        
+        let message_ids: Vec<String> = conversation.pinned_messages.iter()
+            .map(|p| p.message_id.clone())
+            .collect();
+        let messages = self.db.get_messages_by_ids(&message_ids).await?;
+        let messages: HashMap<String, Message> = self.prepare_messages_for_user(messages, user_id, &conversation).await?
+            .into_iter()
+            .map(|m| (m.id.clone(), m))
+            .collect();
+        
+        let mut pins: Vec<PinnedMessage> = conversation.pinned_messages.iter()
+            .cloned()
+            .map(|mut pin| {
+                pin.message = messages.get(&pin.message_id).cloned();
+                pin
+            })
+            .collect();
+        pins.sort_by(|a, b| b.pinned_at.cmp(&a.pinned_at));
+        
+        Ok(pins)
+    }
+
+    /// 设置会话的阅后即焚时长，为空表示关闭；只影响之后发送的消息
+    pub async fn set_message_ttl(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        message_ttl: Option<u64>,
+    ) -> Result<Conversation, Error> {
+        debug!("Setting message TTL of conversation {} to {:?} by user {}", conversation_id, message_ttl, user_id);
+        
+        if let Some(ttl) = message_ttl {
+            if ttl == 0 || ttl > MAX_MESSAGE_TTL_SECS {
+                return Err(Error::Validation(format!(
+                    "Message TTL must be between 1 and {} seconds", MAX_MESSAGE_TTL_SECS
+                )));
+            }
+        }
+        
+        let mut conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        permissions::check_permission(&conversation, user_id, GroupAction::ChangeSettings)?;
+        
+        if conversation.message_ttl == message_ttl {
+            return Ok(conversation);
+        }
+        
+        self.db.set_message_ttl(conversation_id, message_ttl).await?;
+        conversation.message_ttl = message_ttl;
+        
+        // 在时间线中记录此次修改
+        let event = SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MessageTtlChanged,
+            target_ids: Vec::new(),
+            value: Some(serde_json::json!(message_ttl)),
+        };
+        let message = self.record_system_event(&conversation, event).await?;
+        conversation.last_message = Some(message);
+        
+        Ok(conversation)
+    }
+
+    /// 清理所有已过期的消息，包括本地搜索索引中的副本，返回删除的数量
+    pub async fn purge_expired_messages(&self) -> Result<usize, Error> {
+        let mut purged = 0;
+        
+        loop {
+            let expired = self.db.delete_expired_messages(Utc::now(), EXPIRED_PURGE_BATCH_SIZE).await?;
+            if expired.is_empty() {
+                break;
+            }
+            
+            let mut conversation_ids: Vec<&str> = Vec::new();
+            for message in &expired {
+                self.search_index.remove_message(&message.conversation_id, &message.id)?;
+                if !conversation_ids.contains(&message.conversation_id.as_str()) {
+                    conversation_ids.push(&message.conversation_id);
+                }
+            }
+            
+            // 会话摘要中可能保存着已过期的内容
+            for conversation_id in conversation_ids {
+                self.db.recalculate_conversation_last_message(conversation_id).await?;
+            }
+            
+            purged += expired.len();
+            if (expired.len() as i64) < EXPIRED_PURGE_BATCH_SIZE {
+                break;
+            }
+        }
+        
+        if purged > 0 {
+            debug!("Purged {} expired messages", purged);
+        }
+        
+        Ok(purged)
+    }
+
+    /// 在会话时间线中写入一条系统消息
+    ///
+    /// 系统消息不加密，内容为供旧客户端显示的文字说明，结构化信息在 system_event 中。
+    async fn record_system_event(&self, conversation: &Conversation, event: SystemEvent) -> Result<Message, Error> {
+        let new_message = NewMessage {
+            conversation_id: conversation.id.clone(),
+            sender_id: event.actor_id.clone(),
+            content: describe_system_event(&event),
+            content_type: MessageType::System,
+            media_url: None,
+            encrypted: false,
+            reply_to: None,
+            thread_root_id: None,
+            forwarded_from: None,
+            mentions: Vec::new(),
+            system_event: Some(event),
+        };
+        
+        let message = self.db.save_message(new_message).await?;
+        self.db.update_conversation_last_message(&conversation.id, &message).await?;
+        
+        Ok(message)
+    }
+
+    /// 检查用户是否可以置顶/取消置顶会话中的消息
+    fn ensure_can_pin(&self, conversation: &Conversation, user_id: &str) -> Result<(), Error> {
+        permissions::check_permission(conversation, user_id, GroupAction::PinMessages)
+    }
+
+    /// 转发消息到调用者所在的其他会话
+    ///
+    /// 会话密钥按会话区分，因此内容先用来源会话的密钥解密，
+    /// 再经 send_message 按目标会话重新加密。媒体消息复用原有的 media_url。
+    pub async fn forward_messages(
+        &self,
+        user_id: &str,
+        message_ids: &[String],
+        target_conversation_ids: &[String],
+    ) -> Result<Vec<Message>, Error> {
+        debug!("Forwarding {} messages to {} conversations by user {}", 
+               message_ids.len(), target_conversation_ids.len(), user_id);
+        
+        if message_ids.is_empty() || target_conversation_ids.is_empty() {
+            return Err(Error::Validation("Nothing to forward".to_string()));
+        }
+        
+        if message_ids.len() > MAX_FORWARD_MESSAGES {
+            return Err(Error::Validation(format!(
+                "At most {} messages can be forwarded at once", MAX_FORWARD_MESSAGES
+            )));
+        }
+        
+        // 先验证所有目标会话，避免只转发了一部分
+        for target_id in target_conversation_ids {
+            let target = self.db.get_conversation(target_id).await?
+                .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", target_id)))?;
+            
+            if !target.participants.contains(&user_id.to_string()) {
+                return Err(Error::Authentication(
+                    format!("User {} is not a participant in conversation {}", 
+                           user_id, target_id)
+                ));
+            }
+        }
+        
+        // 读取并解密来源消息
+        let mut sources = Vec::with_capacity(message_ids.len());
+        for message_id in message_ids {
+            let (mut message, _) = self.db.get_message_for_participant(message_id, user_id).await?;
+            
+            if message.retracted_at.is_some() || message.deleted_for.iter().any(|u| u == user_id) {
+                return Err(Error::NotFound(format!("Message not found: {}", message_id)));
+            }
+            
+            if message.content_type == MessageType::System {
+                return Err(Error::Validation("System messages cannot be forwarded".to_string()));
+            }
+            
+            if message.encrypted {
+                message.content = self.read_message_content(&message, &message.content, user_id).await?
+                    .ok_or_else(|| Error::Encryption(format!("Message {} cannot be decrypted by user {}", message_id, user_id)))?;
+            }
+            
+            sources.push(message);
+        }
+        sources.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        
+        let mut forwarded = Vec::with_capacity(sources.len() * target_conversation_ids.len());
+        for target_id in target_conversation_ids {
+            for source in &sources {
+                let origin = source.forwarded_from.clone().unwrap_or_else(|| ForwardedFrom {
+                    message_id: source.id.clone(),
+                    conversation_id: source.conversation_id.clone(),
+                    sender_id: source.sender_id.clone(),
+                    timestamp: source.timestamp,
+                });
+                
+                let new_message = NewMessage {
+                    conversation_id: target_id.clone(),
+                    sender_id: user_id.to_string(),
+                    content: source.content.clone(),
+                    content_type: source.content_type.clone(),
+                    media_url: source.media_url.clone(),
+                    encrypted: false,
+                    reply_to: None,
+                    thread_root_id: None,
+                    forwarded_from: Some(origin),
+                    mentions: Vec::new(),
+                    system_event: None,
+                };
+                
+                forwarded.push(self.send_message(new_message, user_id).await?);
+            }
+        }
+        
+        Ok(forwarded)
+    }
+
+    /// 在用户的会话中搜索消息
+    ///
+    /// 未加密会话由数据库检索；加密会话在服务器上只有密文，
+    /// 只能从本地已解密消息的索引中检索。
+    pub async fn search_messages(
+        &self,
+        user_id: &str,
+        query: SearchQuery,
+    ) -> Result<SearchResults, Error> {
+        debug!("Searching messages for user {}: {:?}", user_id, query);
+        
+        let conversations: Vec<Conversation> = self.db.get_conversations_for_user(user_id).await?
+            .into_iter()
+            .filter(|c| query.conversation_id.as_ref().map_or(true, |id| &c.id == id))
+            .collect();
+        
+        let (encrypted, plain): (Vec<Conversation>, Vec<Conversation>) = conversations
+            .into_iter()
+            .partition(|c| c.encryption_enabled);
+        let plain_ids: Vec<String> = plain.into_iter().map(|c| c.id).collect();
+        let encrypted_ids: Vec<String> = encrypted.into_iter().map(|c| c.id).collect();
+        
+        let mut candidates = Vec::new();
+        if !plain_ids.is_empty() {
+            candidates.extend(self.db.search_messages(&plain_ids, user_id, &query, MAX_SEARCH_CANDIDATES).await?);
+        }
+        candidates.extend(self.search_index.candidates(&encrypted_ids, user_id)?);
+        
+        let hits = search::rank_messages(candidates, &query);
+        let total = hits.len();
+        
+        let offset = query.offset.unwrap_or(0) as usize;
+        let limit = query.limit.unwrap_or(20) as usize;
+        let hits = hits.into_iter().skip(offset).take(limit).collect();
+        
+        Ok(SearchResults { hits, total })
+    }
+
+    /// 对消息添加表情回应，仅会话参与者可以操作
+    pub async fn react_to_message(
+        &self,
+        message_id: &str,
+        user_id: &str,
+        emoji: &str,
+    ) -> Result<Message, Error> {
+        debug!("User {} reacting to message {} with {}", user_id, message_id, emoji);
+        
+        let emoji = emoji.trim();
+        if emoji.is_empty() || emoji.chars().count() > MAX_REACTION_CHARS {
+            return Err(Error::Validation("Invalid reaction emoji".to_string()));
+        }
+        
+        let (message, _) = self.db.get_message_for_participant(message_id, user_id).await?;
+        
+        if message.retracted_at.is_some() {
+            return Err(Error::Validation("Cannot react to a retracted message".to_string()));
+        }
+        
+        let reaction = Reaction {
+            user_id: user_id.to_string(),
+            emoji: emoji.to_string(),
+            timestamp: Utc::now(),
+        };
+        self.db.add_reaction(message_id, &reaction).await?;
+        
+        self.get_message_with_reactions(message_id).await
+    }
+
+    /// 移除用户自己的表情回应
+    pub async fn remove_reaction(
+        &self,
+        message_id: &str,
+        user_id: &str,
+        emoji: &str,
+    ) -> Result<Message, Error> {
+        debug!("User {} removing reaction {} from message {}", user_id, emoji, message_id);
+        
+        self.db.get_message_for_participant(message_id, user_id).await?;
+        
+        if !self.db.remove_reaction(message_id, user_id, emoji.trim()).await? {
+            return Err(Error::NotFound(format!(
+                "Reaction {} by user {} not found on message {}", emoji, user_id, message_id
+            )));
+        }
+        
+        self.get_message_with_reactions(message_id).await
+    }
+
+    /// 重新读取消息并汇总表情回应
+    async fn get_message_with_reactions(&self, message_id: &str) -> Result<Message, Error> {
+        let mut message = self.db.get_message(message_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Message not found: {}", message_id)))?;
+        
+        message.reaction_counts = aggregate_reactions(&message.reactions);
+        Ok(message)
+    }
+
+    /// 编辑消息内容，仅允许原发送者修改文本消息
+    pub async fn edit_message(
+        &self,
+        message_id: &str,
+        user_id: &str,
+        new_content: String,
+    ) -> Result<Message, Error> {
+        debug!("Editing message {} by user {}", message_id, user_id);
+        
+        if new_content.trim().is_empty() {
+            return Err(Error::Validation("Message content cannot be empty".to_string()));
+        }
+        
+        let mut message = self.db.get_message(message_id).await?
+            .filter(|m| !m.is_expired(Utc::now()))
+            .ok_or_else(|| Error::NotFound(format!("Message not found: {}", message_id)))?;
+        
+        // 只有发送者可以编辑自己的消息
+        if message.sender_id != user_id {
+            return Err(Error::Authentication(
+                format!("User {} is not the sender of message {}", user_id, message_id)
+            ));
+        }
+        
+        if message.retracted_at.is_some() {
+            return Err(Error::Validation("Retracted messages cannot be edited".to_string()));
+        }
+        
+        if message.forwarded_from.is_some() {
+            return Err(Error::Validation("Forwarded messages cannot be edited".to_string()));
+        }
+        
+        if !matches!(message.content_type, MessageType::Text) {
+            return Err(Error::Validation("Only text messages can be edited".to_string()));
+        }
+        
+        let conversation = self.db.get_conversation(&message.conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", message.conversation_id)))?;
         
         if !conversation.participants.contains(&user_id.to_string()) {
             return Err(Error::Authentication(
                 format!("User {} is not a participant in conversation {}", 
-                       user_id, conversation_id)
+                       user_id, conversation.id)
             ));
         }
         
-        // 获取消息
-        let messages = self.db.get_messages(conversation_id, limit, before_id).await?;
+        // 加密会话中的新内容同样需要加密后再存储
+        let stored_content = if conversation.encryption_enabled {
+            let draft = NewMessage {
+                conversation_id: conversation.id.clone(),
+                sender_id: user_id.to_string(),
+                content: new_content.clone(),
+                content_type: message.content_type.clone(),
+                media_url: message.media_url.clone(),
+                encrypted: false,
+                reply_to: None,
+                thread_root_id: None,
+                forwarded_from: None,
+                mentions: Vec::new(),
+                system_event: None,
+            };
+            self.process_outgoing_encrypted_message(draft, &conversation).await?.content
+        } else {
+            new_content.clone()
+        };
+        
+        let new_mentions = mentions::parse_mentions(&new_content, &conversation.participants, user_id);
+        
+        // 修订历史保存的是被替换的存储内容（加密会话中仍为密文）
+        let revision = MessageRevision {
+            content: std::mem::replace(&mut message.content, stored_content),
+            edited_at: Utc::now(),
+        };
+        
+        self.db.update_message_content(message_id, &message.content, &new_mentions, &revision).await?;
         
-        // 如果会话启用了加密，解密消息
         if conversation.encryption_enabled {
-            self.process_incoming_encrypted_messages(messages, user_id, conversation_id).await
-        } else {
-            Ok(messages)
+            self.key_manager.cache_plaintext(user_id, &message, &message.content, &new_content).await?;
+        }
+        
+        message.mentions = new_mentions;
+        message.edited_at = Some(revision.edited_at);
+        message.encrypted = message.encrypted || conversation.encryption_enabled;
+        message.revisions.push(revision);
+        
+        // 如果编辑的是最新一条消息，同步更新会话摘要
+        self.db.refresh_conversation_last_message(&conversation.id, &message).await?;
+        
+        // 返回给调用者的是明文内容
+        self.prepare_messages_for_user(vec![message], user_id, &conversation).await?
+            .pop()
+            .ok_or_else(|| Error::Internal("Failed to prepare edited message".to_string()))
+    }
+
+    /// 删除消息：仅对自己隐藏，或由发送者在时限内为所有人撤回
+    pub async fn delete_message(
+        &self,
+        message_id: &str,
+        user_id: &str,
+        mode: DeleteMode,
+    ) -> Result<Message, Error> {
+        debug!("Deleting message {} by user {} ({:?})", message_id, user_id, mode);
+        
+        let message = self.db.get_message(message_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Message not found: {}", message_id)))?;
+        
+        let conversation = self.db.get_conversation(&message.conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", message.conversation_id)))?;
+        
+        if !conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Authentication(
+                format!("User {} is not a participant in conversation {}", 
+                       user_id, conversation.id)
+            ));
+        }
+        
+        match mode {
+            DeleteMode::ForMe => {
+                self.db.hide_message_for_user(message_id, user_id).await?;
+                self.search_index.remove_message(&conversation.id, message_id)?;
+            }
+            DeleteMode::ForEveryone => {
+                // 只有发送者可以撤回
+                if message.sender_id != user_id {
+                    return Err(Error::Authentication(
+                        format!("User {} is not the sender of message {}", user_id, message_id)
+                    ));
+                }
+                
+                if message.retracted_at.is_some() {
+                    return Err(Error::Validation("Message has already been retracted".to_string()));
+                }
+                
+                // 系统消息记录的是会话历史，不能撤回
+                if message.content_type == MessageType::System {
+                    return Err(Error::Validation("System messages cannot be retracted".to_string()));
+                }
+                
+                if Utc::now() - message.timestamp > self.retract_window {
+                    return Err(Error::Validation(format!(
+                        "Messages can only be retracted within {} minutes of sending",
+                        self.retract_window.num_minutes()
+                    )));
+                }
+                
+                self.db.retract_message(message_id, Utc::now()).await?;
+                self.search_index.remove_message(&conversation.id, message_id)?;
+                if conversation.encryption_enabled {
+                    self.key_manager.forget_plaintexts(message_id).await?;
+                }
+                
+                // 撤回的消息不再保留置顶
+                self.db.unpin_message(&conversation.id, message_id).await?;
+                
+                // 撤回的是最后一条消息时，重新计算会话摘要
+                let was_last_message = conversation.last_message.as_ref()
+                    .map_or(false, |last| last.id == message_id);
+                if was_last_message {
+                    self.db.recalculate_conversation_last_message(&conversation.id).await?;
+                }
+            }
         }
+        
+        self.db.get_message(message_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Message not found: {}", message_id)))
     }
 
     /// 更新消息状态（已读/已送达）
@@ -295,7 +2167,14 @@ impl ChatManager {
 
     /// 获取用户未读消息数
     pub async fn get_unread_count(&self, user_id: &str) -> Result<u64, Error> {
-        self.db.get_unread_message_count(user_id).await
+        // 只统计用户当前所在的会话，退出的群聊不再计入
+        let conversation_ids: Vec<String> = self.db.get_conversations_for_user(user_id).await?
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        
+        let unread_counts = self.db.get_unread_counts(user_id, &conversation_ids).await?;
+        Ok(unread_counts.values().sum())
     }
 
     /// 创建群聊
@@ -322,9 +2201,23 @@ impl ChatManager {
             conversation_type: ConversationType::Group,
             participants,
             encryption_enabled,
+            roles: HashMap::from([(creator_id.to_string(), GroupRole::Owner)]),
         };
         
-        self.create_conversation(new_conversation).await
+        let mut conversation = self.create_conversation(new_conversation).await?;
+        
+        let event = SystemEvent {
+            actor_id: creator_id.to_string(),
+            action: SystemAction::GroupCreated,
+            target_ids: conversation.participants.iter()
+                .filter(|p| p.as_str() != creator_id)
+                .cloned()
+                .collect(),
+            value: Some(serde_json::json!(name)),
+        };
+        conversation.last_message = Some(self.record_system_event(&conversation, event).await?);
+        
+        Ok(conversation)
     }
 
     /// 添加成员到群聊
@@ -341,159 +2234,629 @@ impl ChatManager {
         let conversation = self.db.get_conversation(conversation_id).await?
             .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
         
-        // 验证是群聊
-        if conversation.conversation_type != ConversationType::Group {
-            return Err(Error::Validation("Not a group conversation".to_string()));
-        }
+        // 验证是群聊
+        if conversation.conversation_type != ConversationType::Group {
+            return Err(Error::Validation("Not a group conversation".to_string()));
+        }
+        
+        // 验证操作者有权添加成员
+        permissions::check_permission(&conversation, user_id, GroupAction::AddMembers)?;
+        
+        // 检查新成员是否已在群中
+        if conversation.participants.contains(&new_member_id.to_string()) {
+            return Err(Error::Validation(
+                format!("User {} is already a member of group {}", 
+                    new_member_id, conversation_id)
+            ));
+        }
+        
+        self.add_member_to_group(&conversation, new_member_id, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MemberAdded,
+            target_ids: vec![new_member_id.to_string()],
+            value: None,
+        }).await?;
+        
+        Ok(())
+    }
+
+    /// 把用户加入群聊：更新参与者列表，为加密群聊建立密钥，并写入系统消息
+    ///
+    /// 用户已在群中时（包括并发加入时被另一请求抢先）返回错误，不写入系统消息。
+    async fn add_member_to_group(
+        &self,
+        conversation: &Conversation,
+        new_member_id: &str,
+        event: SystemEvent,
+    ) -> Result<Message, Error> {
+        // 更新会话的参与者列表
+        if !self.db.add_participant(&conversation.id, new_member_id).await? {
+            return Err(Error::Validation(
+                format!("User {} is already a member of group {}", new_member_id, conversation.id)
+            ));
+        }
+        
+        // 加密群聊的新成员在首次收发消息时用自己的私钥派生密钥
+        self.record_system_event(conversation, event).await
+    }
+
+    /// 主动退出群聊，返回群聊是否因此解散
+    ///
+    /// 群主退出前把群主转让给 new_owner_id，未指定时由管理员或最早加入的成员接任；
+    /// 最后一名成员退出时群聊解散。
+    pub async fn leave_group(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        new_owner_id: Option<&str>,
+    ) -> Result<bool, Error> {
+        debug!("User {} leaving group {}", user_id, conversation_id);
+        
+        let conversation = self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        if conversation.conversation_type != ConversationType::Group {
+            return Err(Error::Validation("Not a group conversation".to_string()));
+        }
+        
+        if conversation.participants.len() == 1 {
+            self.dissolve_group(&conversation).await?;
+            return Ok(true);
+        }
+        
+        if permissions::owner_of(&conversation) == Some(user_id) {
+            let successor = match new_owner_id {
+                Some(new_owner_id) if new_owner_id == user_id => {
+                    return Err(Error::Validation("User is already the owner".to_string()));
+                }
+                Some(new_owner_id) if !conversation.participants.iter().any(|p| p == new_owner_id) => {
+                    return Err(Error::Validation(format!("User {} is not a member of group {}", new_owner_id, conversation_id)));
+                }
+                Some(new_owner_id) => new_owner_id,
+                None => permissions::successor_of(&conversation, user_id)
+                    .ok_or_else(|| Error::Internal(format!("No successor found for group {}", conversation_id)))?,
+            };
+            
+            self.db.transfer_ownership(conversation_id, user_id, successor).await?;
+            self.record_system_event(&conversation, SystemEvent {
+                actor_id: user_id.to_string(),
+                action: SystemAction::OwnershipTransferred,
+                target_ids: vec![successor.to_string()],
+                value: None,
+            }).await?;
+        }
+        
+        self.db.remove_participant(conversation_id, user_id).await?;
+        self.db.delete_draft(user_id, conversation_id).await?;
+        
+        if conversation.encryption_enabled {
+            self.session_keys.remove_user_keys(conversation_id, user_id)?;
+            self.key_manager.remove_sessions(conversation_id, Some(user_id)).await?;
+        }
+        
+        self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MemberLeft,
+            target_ids: Vec::new(),
+            value: None,
+        }).await?;
+        
+        Ok(false)
+    }
+
+    /// 删除会话，返回群聊是否被解散
+    ///
+    /// 私聊只对调用者隐藏并清空其可见的历史和缓存的明文，对方可以继续发送，
+    /// 收到新消息后会话重新出现，因此会话密钥保留。群聊只有群主可以删除，删除即解散，所有数据和密钥一并清除。
+    pub async fn delete_conversation(&self, conversation_id: &str, user_id: &str) -> Result<bool, Error> {
+        debug!("User {} deleting conversation {}", user_id, conversation_id);
+        
+        let conversation = self.get_conversation_for_participant(conversation_id, user_id).await?;
+        
+        match conversation.conversation_type {
+            ConversationType::Direct => {
+                // 系统消息先写入，随后和历史一起对调用者隐藏，只有对方能看到
+                self.record_system_event(&conversation, SystemEvent {
+                    actor_id: user_id.to_string(),
+                    action: SystemAction::ConversationDeleted,
+                    target_ids: Vec::new(),
+                    value: None,
+                }).await?;
+                
+                self.db.hide_conversation_messages_for_user(conversation_id, user_id).await?;
+                self.db.delete_draft(user_id, conversation_id).await?;
+                self.db.update_conversation_preferences(user_id, conversation_id, doc! { "hidden": true }).await?;
+                
+                // 隐藏可以恢复，且对端不知道这次删除，仍会用现有的 Double Ratchet 会话发消息，
+                // 因此只删除缓存的明文，会话密钥保留
+                if conversation.encryption_enabled {
+                    self.key_manager.forget_conversation_plaintexts(conversation_id, user_id).await?;
+                }
+                
+                Ok(false)
+            }
+            ConversationType::Group => {
+                // 没有群主的旧群聊需要先通过 transfer_ownership 指定群主
+                if permissions::owner_of(&conversation) != Some(user_id) {
+                    return Err(Error::Authentication(format!(
+                        "Only the owner can dissolve group {}", conversation_id
+                    )));
+                }
+                
+                self.dissolve_group(&conversation).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// 解散群聊：删除会话数据，清除会话密钥和本地搜索索引
+    ///
+    /// 时间线随会话一起删除，因此不写系统消息，其他成员通过 GroupDissolved 事件得知。
+    async fn dissolve_group(&self, conversation: &Conversation) -> Result<(), Error> {
+        debug!("Dissolving group {}", conversation.id);
+        
+        self.db.delete_conversation(&conversation.id).await?;
+        self.session_keys.remove_conversation_keys(&conversation.id)?;
+        self.key_manager.remove_sessions(&conversation.id, None).await?;
+        self.search_index.remove_conversation(&conversation.id)?;
+        
+        Ok(())
+    }
+
+    /// 创建群邀请链接，需要群权限策略允许添加成员
+    pub async fn create_group_invite(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        expires_in_secs: Option<u64>,
+        max_uses: Option<u32>,
+    ) -> Result<GroupInvite, Error> {
+        debug!("Creating invite for group {} by user {}", conversation_id, user_id);
+        
+        if let Some(secs) = expires_in_secs {
+            if secs == 0 || secs > MAX_INVITE_TTL_SECS {
+                return Err(Error::Validation(format!(
+                    "Invite expiry must be between 1 and {} seconds", MAX_INVITE_TTL_SECS
+                )));
+            }
+        }
+        if max_uses == Some(0) {
+            return Err(Error::Validation("Invite must allow at least one use".to_string()));
+        }
+        
+        self.get_group_for_invites(conversation_id, user_id).await?;
+        
+        let now = Utc::now();
+        let invite = GroupInvite {
+            id: Uuid::new_v4().to_string(),
+            conversation_id: conversation_id.to_string(),
+            created_by: user_id.to_string(),
+            token: generate_invite_token(),
+            created_at: now,
+            expires_at: expires_in_secs.map(|secs| now + Duration::seconds(secs as i64)),
+            max_uses,
+            use_count: 0,
+            revoked_at: None,
+        };
+        
+        self.db.save_group_invite(&invite).await?;
+        
+        Ok(invite)
+    }
+
+    /// 获取群聊中仍可使用的邀请
+    pub async fn get_group_invites(&self, conversation_id: &str, user_id: &str) -> Result<Vec<GroupInvite>, Error> {
+        self.get_group_for_invites(conversation_id, user_id).await?;
+        
+        let now = Utc::now();
+        let invites = self.db.get_group_invites(conversation_id).await?
+            .into_iter()
+            .filter(|invite| invite.is_active(now))
+            .collect();
+        
+        Ok(invites)
+    }
+
+    /// 撤销邀请，撤销后链接立即失效
+    pub async fn revoke_group_invite(&self, conversation_id: &str, invite_id: &str, user_id: &str) -> Result<(), Error> {
+        debug!("Revoking invite {} of group {} by user {}", invite_id, conversation_id, user_id);
+        
+        self.get_group_for_invites(conversation_id, user_id).await?;
+        
+        if !self.db.revoke_group_invite(conversation_id, invite_id).await? {
+            return Err(Error::NotFound(format!("Active invite not found: {}", invite_id)));
+        }
+        
+        Ok(())
+    }
+
+    /// 通过邀请链接加入群聊
+    ///
+    /// 邀请创建者在加入时仍需有添加成员的权限，被降级或离开群聊后其创建的邀请随之失效。
+    pub async fn join_group_via_invite(&self, token: &str, user_id: &str) -> Result<Conversation, Error> {
+        debug!("User {} joining group via invite", user_id);
+        
+        let invite = self.db.get_group_invite_by_token(token).await?
+            .ok_or_else(|| Error::NotFound("Invite not found".to_string()))?;
+        
+        if !invite.is_active(Utc::now()) {
+            return Err(Error::Validation("Invite has expired or been revoked".to_string()));
+        }
+        
+        let mut conversation = self.db.get_conversation(&invite.conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", invite.conversation_id)))?;
+        
+        if conversation.participants.contains(&user_id.to_string()) {
+            return Err(Error::Validation(
+                format!("User {} is already a member of group {}", user_id, conversation.id)
+            ));
+        }
+        
+        if permissions::check_permission(&conversation, &invite.created_by, GroupAction::AddMembers).is_err() {
+            return Err(Error::Validation("Invite is no longer valid".to_string()));
+        }
+        
+        // 并发加入时由数据库保证不超过使用次数
+        if self.db.claim_group_invite(token).await?.is_none() {
+            return Err(Error::Validation("Invite has expired or been revoked".to_string()));
+        }
+        
+        let joined = self.add_member_to_group(&conversation, user_id, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MemberJoined,
+            target_ids: Vec::new(),
+            value: Some(serde_json::json!({ "invitedBy": invite.created_by })),
+        }).await;
+        
+        // 加入失败（包括并发请求已让用户入群）时归还占用的使用次数，保留原来的错误
+        let message = match joined {
+            Ok(message) => message,
+            Err(e) => {
+                if let Err(release_error) = self.db.release_group_invite(token).await {
+                    warn!("Failed to release invite use for group {}: {}", conversation.id, release_error);
+                }
+                return Err(e);
+            }
+        };
+        
+        conversation.participants.push(user_id.to_string());
+        conversation.last_message = Some(message);
+        
+        Ok(conversation)
+    }
+
+    /// 获取群聊并确保用户可以管理邀请
+    async fn get_group_for_invites(&self, conversation_id: &str, user_id: &str) -> Result<Conversation, Error> {
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        if conversation.conversation_type != ConversationType::Group {
+            return Err(Error::Validation("Not a group conversation".to_string()));
+        }
+        
+        permissions::check_permission(&conversation, user_id, GroupAction::AddMembers)?;
+        
+        Ok(conversation)
+    }
+
+    /// 从群聊中移除成员
+    pub async fn remove_group_member(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        member_to_remove: &str,
+    ) -> Result<(), Error> {
+        debug!("Removing member {} from group {} by user {}", 
+            member_to_remove, conversation_id, user_id);
+        
+        // 获取会话
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+        
+        // 验证是群聊
+        if conversation.conversation_type != ConversationType::Group {
+            return Err(Error::Validation("Not a group conversation".to_string()));
+        }
+        
+        // 检查要移除的成员是否在群中
+        if !conversation.participants.contains(&member_to_remove.to_string()) {
+            return Err(Error::Validation(
+                format!("User {} is not a member of group {}", 
+                    member_to_remove, conversation_id)
+            ));
+        }
+        
+        // 验证操作者有权移除该成员（群主不能被移除，管理员只能由群主移除）
+        permissions::check_permission(&conversation, user_id, GroupAction::RemoveMember(member_to_remove))?;
+        
+        // 更新会话的参与者列表
+        self.db.remove_participant(conversation_id, member_to_remove).await?;
+        
+        // 如果启用了加密，移除该成员在此会话中的所有密钥；
+        // remove_participant 已增加发送者密钥代数，其余成员下次发送前轮换密钥
+        if conversation.encryption_enabled {
+            self.session_keys.remove_user_keys(conversation_id, member_to_remove)?;
+            self.key_manager.remove_sessions(conversation_id, Some(member_to_remove)).await?;
+        }
+        
+        self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MemberRemoved,
+            target_ids: vec![member_to_remove.to_string()],
+            value: None,
+        }).await?;
+        
+        Ok(())
+    }
+
+    /// 把普通成员设为管理员，只有群主可以操作
+    pub async fn promote_member(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        member_id: &str,
+    ) -> Result<Conversation, Error> {
+        debug!("Promoting member {} in group {} by user {}", member_id, conversation_id, user_id);
+        
+        let mut conversation = self.get_group_for_role_change(conversation_id, user_id).await?;
+        
+        if permissions::role_of(&conversation, member_id) != Some(GroupRole::Member) {
+            return Err(Error::Validation(format!("User {} is not a regular member of group {}", member_id, conversation_id)));
+        }
+        
+        self.db.set_member_role(conversation_id, member_id, Some(GroupRole::Admin)).await?;
+        conversation.roles.insert(member_id.to_string(), GroupRole::Admin);
+        
+        self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MemberPromoted,
+            target_ids: vec![member_id.to_string()],
+            value: None,
+        }).await?;
+        
+        Ok(conversation)
+    }
+
+    /// 把管理员降为普通成员，只有群主可以操作
+    pub async fn demote_member(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        member_id: &str,
+    ) -> Result<Conversation, Error> {
+        debug!("Demoting member {} in group {} by user {}", member_id, conversation_id, user_id);
+        
+        let mut conversation = self.get_group_for_role_change(conversation_id, user_id).await?;
+        
+        if permissions::role_of(&conversation, member_id) != Some(GroupRole::Admin) {
+            return Err(Error::Validation(format!("User {} is not an admin of group {}", member_id, conversation_id)));
+        }
+        
+        self.db.set_member_role(conversation_id, member_id, None).await?;
+        conversation.roles.remove(member_id);
+        
+        self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::MemberDemoted,
+            target_ids: vec![member_id.to_string()],
+            value: None,
+        }).await?;
+        
+        Ok(conversation)
+    }
+
+    /// 把群主转让给其他成员，原群主成为管理员
+    pub async fn transfer_ownership(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        new_owner_id: &str,
+    ) -> Result<Conversation, Error> {
+        debug!("Transferring ownership of group {} from {} to {}", conversation_id, user_id, new_owner_id);
+        
+        let mut conversation = self.get_group_for_role_change(conversation_id, user_id).await?;
         
-        // 验证操作者是群成员
-        if !conversation.participants.contains(&user_id.to_string()) {
-            return Err(Error::Authentication(
-                format!("User {} is not a participant in group {}", 
-                    user_id, conversation_id)
-            ));
+        if user_id == new_owner_id {
+            return Err(Error::Validation("User is already the owner".to_string()));
         }
         
-        // 检查新成员是否已在群中
-        if conversation.participants.contains(&new_member_id.to_string()) {
-            return Err(Error::Validation(
-                format!("User {} is already a member of group {}", 
-                    new_member_id, conversation_id)
-            ));
+        if !conversation.participants.contains(&new_owner_id.to_string()) {
+            return Err(Error::Validation(format!("User {} is not a member of group {}", new_owner_id, conversation_id)));
         }
         
-        // 更新会话的参与者列表
-        let filter = doc! { "id": conversation_id };
+        // 没有群主的旧群聊中，转让同时确立群主，原操作者保持为管理员
+        self.db.transfer_ownership(conversation_id, user_id, new_owner_id).await?;
+        conversation.roles.insert(user_id.to_string(), GroupRole::Admin);
+        conversation.roles.insert(new_owner_id.to_string(), GroupRole::Owner);
         
-        // 直接使用 MongoDB 的 BSON 日期时间类型
-        let current_time = mongodb::bson::DateTime::from_system_time(std::time::SystemTime::now());
+        self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::OwnershipTransferred,
+            target_ids: vec![new_owner_id.to_string()],
+            value: None,
+        }).await?;
         
-        let update = doc! {
-            "$push": { "participants": new_member_id },
-            "$set": { "updated_at": current_time }
-        };
+        Ok(conversation)
+    }
+
+    /// 修改群权限策略，只有群主可以操作
+    pub async fn update_group_policy(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        policy: GroupPolicy,
+    ) -> Result<Conversation, Error> {
+        debug!("Updating policy of group {} by user {}: {:?}", conversation_id, user_id, policy);
         
-        self.db.conversations_collection
-            .update_one(filter, update, None)
-            .await
-            .map_err(|e| Error::Database(format!("Failed to update conversation participants: {}", e)))?;
+        let mut conversation = self.get_group_for_role_change(conversation_id, user_id).await?;
         
-        // 如果启用了加密，需要为新成员建立密钥
-        if conversation.encryption_enabled {
-            // 为新成员与群中每个现有成员建立密钥
-            for existing_member in &conversation.participants {
-                if existing_member != new_member_id {
-                    self.establish_secure_channel(existing_member, new_member_id, conversation_id).await?;
-                }
-            }
+        if conversation.group_policy == policy {
+            return Ok(conversation);
         }
         
-        Ok(())
+        self.db.set_group_policy(conversation_id, &policy).await?;
+        conversation.group_policy = policy;
+        
+        self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::GroupPolicyChanged,
+            target_ids: Vec::new(),
+            value: Some(serde_json::to_value(&conversation.group_policy)
+                .map_err(|e| Error::Internal(format!("Failed to serialize group policy: {}", e)))?),
+        }).await?;
+        
+        Ok(conversation)
     }
 
-    /// 从群聊中移除成员
-    pub async fn remove_group_member(
+    /// 修改群名称、简介和头像，需要群权限策略允许修改群资料
+    pub async fn update_group_info(
         &self,
         conversation_id: &str,
         user_id: &str,
-        member_to_remove: &str,
-    ) -> Result<(), Error> {
-        debug!("Removing member {} from group {} by user {}", 
-            member_to_remove, conversation_id, user_id);
+        update: GroupInfoUpdate,
+    ) -> Result<Conversation, Error> {
+        debug!("Updating info of group {} by user {}: {:?}", conversation_id, user_id, update);
+        
+        let name = update.name.map(|n| n.trim().to_string());
+        if let Some(name) = &name {
+            if name.is_empty() || name.chars().count() > MAX_GROUP_NAME_CHARS {
+                return Err(Error::Validation(format!(
+                    "Group name must be between 1 and {} characters", MAX_GROUP_NAME_CHARS
+                )));
+            }
+        }
         
-        // 获取会话
-        let conversation = self.db.get_conversation(conversation_id).await?
+        // 空字符串表示清除
+        let description = update.description.map(|d| Some(d.trim().to_string()).filter(|d| !d.is_empty()));
+        if let Some(Some(description)) = &description {
+            if description.chars().count() > MAX_GROUP_DESCRIPTION_CHARS {
+                return Err(Error::Validation(format!(
+                    "Group description must be at most {} characters", MAX_GROUP_DESCRIPTION_CHARS
+                )));
+            }
+        }
+        let avatar_url = update.avatar_url.map(|url| Some(url.trim().to_string()).filter(|url| !url.is_empty()));
+        
+        let mut conversation = self.db.get_conversation(conversation_id).await?
             .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
         
-        // 验证是群聊
         if conversation.conversation_type != ConversationType::Group {
             return Err(Error::Validation("Not a group conversation".to_string()));
         }
         
-        // 验证操作者是群成员
-        if !conversation.participants.contains(&user_id.to_string()) {
-            return Err(Error::Authentication(
-                format!("User {} is not a participant in group {}", 
-                    user_id, conversation_id)
-            ));
+        permissions::check_permission(&conversation, user_id, GroupAction::EditInfo)?;
+        
+        // 只记录实际发生变化的字段
+        let mut changes = serde_json::Map::new();
+        if let Some(name) = name.filter(|n| conversation.name.as_ref() != Some(n)) {
+            changes.insert("name".to_string(), serde_json::json!(name));
+            conversation.name = Some(name);
+        }
+        if let Some(description) = description.filter(|d| *d != conversation.description) {
+            changes.insert("description".to_string(), serde_json::json!(description));
+            conversation.description = description;
+        }
+        if let Some(avatar_url) = avatar_url.filter(|url| *url != conversation.avatar_url) {
+            changes.insert("avatarUrl".to_string(), serde_json::json!(avatar_url));
+            conversation.avatar_url = avatar_url;
         }
         
-        // 检查要移除的成员是否在群中
-        if !conversation.participants.contains(&member_to_remove.to_string()) {
-            return Err(Error::Validation(
-                format!("User {} is not a member of group {}", 
-                    member_to_remove, conversation_id)
-            ));
+        if changes.is_empty() {
+            return Ok(conversation);
         }
         
-        // 更新会话的参与者列表
-        let filter = doc! { "id": conversation_id };
-        let update = doc! {
-            "$pull": { "participants": member_to_remove },
-            "$set": { "updated_at": chrono_to_bson_datetime(Utc::now()) }
-        };
+        self.db.set_group_info(
+            conversation_id,
+            conversation.name.as_deref(),
+            conversation.description.as_deref(),
+            conversation.avatar_url.as_deref(),
+        ).await?;
+        
+        let message = self.record_system_event(&conversation, SystemEvent {
+            actor_id: user_id.to_string(),
+            action: SystemAction::GroupInfoUpdated,
+            target_ids: Vec::new(),
+            value: Some(serde_json::Value::Object(changes)),
+        }).await?;
+        conversation.last_message = Some(message);
         
-        self.db.conversations_collection
-            .update_one(filter, update, None)
-            .await
-            .map_err(|e| Error::Database(format!("Failed to update conversation participants: {}", e)))?;
+        Ok(conversation)
+    }
+
+    /// 获取群聊并确保用户可以管理角色
+    async fn get_group_for_role_change(&self, conversation_id: &str, user_id: &str) -> Result<Conversation, Error> {
+        let conversation = self.db.get_conversation(conversation_id).await?
+            .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
         
-        // 如果启用了加密，吊销该成员的密钥
-        if conversation.encryption_enabled {
-            // 移除该用户在此会话中的所有密钥
-            self.session_keys.remove_user_keys(conversation_id, member_to_remove)?;
-            
-            // 为安全起见，在实际应用中应该为剩余成员重新生成密钥
-            // 这需要密钥轮换机制，更复杂的实现会超出示例范围
+        if conversation.conversation_type != ConversationType::Group {
+            return Err(Error::Validation("Not a group conversation".to_string()));
         }
         
-        Ok(())
+        permissions::check_permission(&conversation, user_id, GroupAction::ManageRoles)?;
+        
+        Ok(conversation)
     }
 
-    /// 初始化会话密钥
-    async fn initialize_conversation_keys(&self, conversation: &Conversation) -> Result<(), Error> {
-        debug!("Initializing encryption keys for conversation {}", conversation.id);
+    /// 确保用户在会话中有可用的会话密钥，没有时用本设备上的私钥和对端发布的公钥派生
+    ///
+    /// 群聊消息使用发送者密钥，群聊的会话密钥只加密本人读取的草稿和定时消息，
+    /// 因此用自己的身份密钥派生；与某位成员派生的密钥其他成员无法得到。
+    async fn ensure_session_key(&self, conversation_id: &str, user_id: &str) -> Result<(), Error> {
+        if self.session_keys.get_key(conversation_id, user_id)?.is_some() {
+            return Ok(());
+        }
         
-        // 为会话中的每对用户创建共享密钥
-        for (i, user_a) in conversation.participants.iter().enumerate() {
-            for user_b in conversation.participants.iter().skip(i + 1) {
-                // 为每对用户创建共享密钥
-                self.establish_secure_channel(user_a, user_b, &conversation.id).await?;
+        // 优先沿用之前记录的对端，保证重启后派生出相同的密钥
+        let peer_id = match self.key_manager.session_peer(conversation_id, user_id).await? {
+            Some(peer_id) => peer_id,
+            None => {
+                let conversation = self.db.get_conversation(conversation_id).await?
+                    .ok_or_else(|| Error::NotFound(format!("Conversation not found: {}", conversation_id)))?;
+                
+                match conversation.conversation_type {
+                    ConversationType::Direct => conversation.participants.iter()
+                        .find(|p| p.as_str() != user_id)
+                        .cloned()
+                        .ok_or_else(|| Error::Encryption(format!(
+                            "No peer to derive a session key with in conversation {}", conversation_id
+                        )))?,
+                    ConversationType::Group => user_id.to_string(),
+                }
             }
-        }
+        };
         
-        Ok(())
+        self.establish_secure_channel(user_id, &peer_id, conversation_id).await
     }
 
-    /// 为两个用户建立安全通道
+    /// 为本设备上的用户建立与对端的安全通道
+    ///
+    /// 只使用对端在公钥目录中发布的身份公钥，对端用自己的私钥和本用户发布的公钥得到相同的密钥。
     async fn establish_secure_channel(
         &self, 
-        user_a: &str, 
-        user_b: &str, 
+        user_id: &str, 
+        peer_id: &str, 
         conversation_id: &str
     ) -> Result<(), Error> {
         debug!("Establishing secure channel between {} and {} for conversation {}", 
-               user_a, user_b, conversation_id);
-        
-        // 获取或创建用户A的密钥对
-        let (public_key_a, _) = self.key_manager.get_or_create_key_pair(user_a).await?;
-        
-        // 获取或创建用户B的密钥对
-        let (public_key_b, _) = self.key_manager.get_or_create_key_pair(user_b).await?;
+               user_id, peer_id, conversation_id);
         
-        // 计算用户A -> 用户B的共享密钥
-        let shared_secret_a = self.key_manager.derive_shared_secret(user_a, &public_key_b).await?;
-        
-        // 存储用户A的会话密钥
-        self.session_keys.store_key(conversation_id, user_a, shared_secret_a)?;
+        // 首次生成身份密钥时立即发布，对端才能派生出相同的密钥
+        let (identity_key, created) = self.key_manager.get_or_create_key_pair(user_id).await?;
+        if created {
+            self.publish_key_bundle(user_id).await?;
+        }
         
-        // 计算用户B -> 用户A的共享密钥
-        let shared_secret_b = self.key_manager.derive_shared_secret(user_b, &public_key_a).await?;
+        // 只供本人使用的密钥用本设备上的身份公钥，不受其他设备发布的公钥包影响
+        let peer_public_key = if peer_id == user_id {
+            identity_key
+        } else {
+            public_key_from_bytes(&self.get_key_bundle(peer_id).await?.identity_key)?
+        };
         
-        // 存储用户B的会话密钥
-        self.session_keys.store_key(conversation_id, user_b, shared_secret_b)?;
+        let shared_secret = self.key_manager.derive_shared_secret(user_id, &peer_public_key).await?;
+        self.session_keys.store_key(conversation_id, user_id, shared_secret)?;
         
-        Ok(())
+        // 记录派生关系，重启后解锁密钥库即可重新计算
+        self.key_manager.bind_session(conversation_id, user_id, peer_id).await
     }
 
     /// 处理传出的加密消息
@@ -502,58 +2865,196 @@ impl ChatManager {
         new_message: NewMessage, 
         conversation: &Conversation
     ) -> Result<NewMessage, Error> {
-        let sender_id = &new_message.sender_id;
-        let content = &new_message.content;
+        // 私聊使用 Double Ratchet，群聊使用发送者密钥
+        let encrypted_json = match conversation.conversation_type {
+            ConversationType::Direct => {
+                self.encrypt_direct_message(&new_message.content, &new_message.sender_id, conversation).await?
+            }
+            ConversationType::Group => {
+                self.encrypt_group_message(&new_message.content, &new_message.sender_id, conversation).await?
+            }
+        };
+        
+        // 创建含加密内容的新消息
+        let mut encrypted_message = new_message;
+        encrypted_message.content = encrypted_json;
+        encrypted_message.encrypted = true;
+        
+        Ok(encrypted_message)
+    }
+
+    /// 用 Double Ratchet 加密私聊消息，还没有会话时用对端的公钥包发起 X3DH
+    async fn encrypt_direct_message(
+        &self, 
+        content: &str, 
+        user_id: &str, 
+        conversation: &Conversation
+    ) -> Result<String, Error> {
+        if !self.key_manager.has_ratchet_session(&conversation.id, user_id, None).await? {
+            let peer_id = conversation.participants.iter()
+                .find(|p| p.as_str() != user_id)
+                .ok_or_else(|| Error::Encryption(format!("No peer in conversation {}", conversation.id)))?;
+            
+            let (_, created) = self.key_manager.get_or_create_key_pair(user_id).await?;
+            if created {
+                self.publish_key_bundle(user_id).await?;
+            }
+            
+            let peer_bundle = self.get_key_bundle(peer_id).await?;
+            self.key_manager.start_ratchet_session(&conversation.id, user_id, &peer_bundle).await?;
+        }
+        
+        self.key_manager.ratchet_encrypt(&conversation.id, user_id, content).await
+    }
+
+    /// 用发送者密钥加密群聊消息，每条消息只加密一次
+    ///
+    /// 有成员离开后会话的发送者密钥代数增加，发送前先轮换自己的密钥；
+    /// 新密钥和新成员都通过成对通道补发，离开的成员拿不到新密钥。
+    async fn encrypt_group_message(
+        &self, 
+        content: &str, 
+        user_id: &str, 
+        conversation: &Conversation
+    ) -> Result<String, Error> {
+        let (share, pending) = self.key_manager.prepare_sender_key(
+            &conversation.id, 
+            user_id, 
+            conversation.sender_key_epoch, 
+            &conversation.participants
+        ).await?;
+        
+        if !pending.is_empty() {
+            self.distribute_sender_key(&conversation.id, user_id, &share, &pending).await?;
+        }
+        
+        self.key_manager.sender_key_encrypt(&conversation.id, user_id, content).await
+    }
+
+    /// 通过成对通道把发送者密钥分发给其他成员
+    ///
+    /// 分发内容用发送者私钥和接收者发布的身份公钥派生的共享密钥加密；
+    /// 尚未发布公钥包的成员暂时跳过，下次发送时再补发。
+    async fn distribute_sender_key(
+        &self, 
+        conversation_id: &str, 
+        user_id: &str, 
+        share: &SenderKeyShare, 
+        recipients: &[String]
+    ) -> Result<(), Error> {
+        let (_, created) = self.key_manager.get_or_create_key_pair(user_id).await?;
+        if created {
+            self.publish_key_bundle(user_id).await?;
+        }
+        
+        let plaintext = serde_json::to_string(share)
+            .map_err(|e| Error::Internal(format!("Failed to serialize sender key: {}", e)))?;
         
+        let mut distributed = Vec::with_capacity(recipients.len());
+        for recipient_id in recipients {
+            let bundle = match self.get_key_bundle(recipient_id).await {
+                Ok(bundle) => bundle,
+                Err(Error::NotFound(_)) => {
+                    warn!("User {} has no key bundle, sender key not distributed yet", recipient_id);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            
+            let shared_secret = self.key_manager
+                .derive_shared_secret(user_id, &public_key_from_bytes(&bundle.identity_key)?)
+                .await?;
+            let encrypted = self.key_manager.encrypt_message(&plaintext, &shared_secret)?;
+            let ciphertext = serde_json::to_string(&encrypted)
+                .map_err(|e| Error::Internal(format!("Failed to serialize encrypted message: {}", e)))?;
+            
+            self.db.save_sender_key_distribution(&SenderKeyDistribution {
+                conversation_id: conversation_id.to_string(),
+                sender_id: user_id.to_string(),
+                recipient_id: recipient_id.clone(),
+                key_id: share.key_id,
+                ciphertext,
+                created_at: Utc::now(),
+            }).await?;
+            distributed.push(recipient_id.clone());
+        }
+        
+        self.key_manager.mark_sender_key_distributed(conversation_id, user_id, share.key_id, &distributed).await
+    }
+
+    /// 用会话密钥加密只由自己读取的内容（草稿、待发送的定时消息）
+    async fn encrypt_own_content(&self, content: &str, user_id: &str, conversation_id: &str) -> Result<String, Error> {
+        self.ensure_session_key(conversation_id, user_id).await?;
+        self.encrypt_content(content, user_id, conversation_id)
+    }
+
+    /// 使用用户的会话密钥加密单条内容，返回序列化后的密文
+    fn encrypt_content(
+        &self, 
+        content: &str, 
+        user_id: &str, 
+        conversation_id: &str
+    ) -> Result<String, Error> {
         // 获取发送者的会话密钥
-        let shared_secret = self.session_keys.get_key(&conversation.id, sender_id)?
+        let shared_secret = self.session_keys.get_key(conversation_id, user_id)?
             .ok_or_else(|| Error::Encryption(format!(
                 "No session key found for user {} in conversation {}", 
-                sender_id, conversation.id
+                user_id, conversation_id
             )))?;
         
         // 加密消息
         let encrypted = self.key_manager.encrypt_message(content, &shared_secret)?;
         
         // 序列化加密消息
-        let encrypted_json = serde_json::to_string(&encrypted)
-            .map_err(|e| Error::Internal(format!("Failed to serialize encrypted message: {}", e)))?;
-        
-        // 创建含加密内容的新消息
-        let mut encrypted_message = new_message;
-        encrypted_message.content = encrypted_json;
-        encrypted_message.encrypted = true;
-        
-        Ok(encrypted_message)
+        serde_json::to_string(&encrypted)
+            .map_err(|e| Error::Internal(format!("Failed to serialize encrypted message: {}", e)))
     }
 
     /// 处理传入的加密消息
     async fn process_incoming_encrypted_messages(
         &self, 
         messages: Vec<Message>, 
-        user_id: &str, 
-        conversation_id: &str
+        user_id: &str
     ) -> Result<Vec<Message>, Error> {
+        if !messages.iter().any(|m| m.encrypted) {
+            return Ok(messages);
+        }
+        
+        // 密钥库未解锁时整页都无法解密，直接返回错误
+        self.key_manager.ensure_unlocked().await?;
+        
+        // 整页消息推进的棘轮状态和缓存的明文在最后一次写回密钥库
+        self.key_manager.begin_batch().await;
+        let result = self.decrypt_messages_for_user(messages, user_id).await;
+        self.key_manager.end_batch().await?;
+        
+        result
+    }
+
+    async fn decrypt_messages_for_user(&self, messages: Vec<Message>, user_id: &str) -> Result<Vec<Message>, Error> {
         let mut processed_messages = Vec::with_capacity(messages.len());
         
         for mut message in messages {
-            if message.encrypted {
-                // 获取接收者的会话密钥
-                let shared_secret = self.session_keys.get_key(conversation_id, user_id)?
-                    .ok_or_else(|| Error::Encryption(format!(
-                        "No session key found for user {} in conversation {}", 
-                        user_id, conversation_id
-                    )))?;
-                
-                // 反序列化加密消息
-                let encrypted: EncryptedMessage = serde_json::from_str(&message.content)
-                    .map_err(|e| Error::Internal(format!("Failed to deserialize encrypted message: {}", e)))?;
-                
-                // 解密消息
-                let decrypted_content = self.key_manager.decrypt_message(&encrypted, &shared_secret)?;
+            if message.encrypted && message.retracted_at.is_some() {
+                // 撤回的消息内容已清空，本地缓存的明文一并删除
+                self.key_manager.forget_plaintexts(&message.id).await?;
+            } else if message.encrypted {
+                // 更新消息内容为解密后的文本，无法解密的消息内容置空，不影响同一页的其他消息
+                match self.try_decrypt_message_content(&message, &message.content, user_id).await? {
+                    Some(content) => message.content = content,
+                    None => {
+                        message.content = String::new();
+                        message.undecryptable = true;
+                    }
+                }
                 
-                // 更新消息内容为解密后的文本
-                message.content = decrypted_content;
+                // 历史版本同样以密文存储
+                let mut revisions = std::mem::take(&mut message.revisions);
+                for revision in revisions.iter_mut() {
+                    revision.content = self.try_decrypt_message_content(&message, &revision.content, user_id).await?
+                        .unwrap_or_default();
+                }
+                message.revisions = revisions;
             }
             
             processed_messages.push(message);
@@ -562,6 +3063,124 @@ impl ChatManager {
         Ok(processed_messages)
     }
 
+    /// 解密单条消息内容用于展示，解密失败时记录日志并返回 None，只有数据库错误才向上返回
+    async fn try_decrypt_message_content(&self, message: &Message, content: &str, user_id: &str) -> Result<Option<String>, Error> {
+        match self.read_message_content(message, content, user_id).await {
+            Err(Error::Database(e)) => Err(Error::Database(e)),
+            Err(e) => {
+                warn!("Message {} cannot be decrypted by user {}: {}", message.id, user_id, e);
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// 读取消息（或其历史版本）的明文
+    ///
+    /// 一次性消息密钥用后即丢弃，发送或读过的消息从密钥库缓存的明文中读取，首次读到时缓存。
+    async fn read_message_content(&self, message: &Message, content: &str, user_id: &str) -> Result<Option<String>, Error> {
+        if let Some(plaintext) = self.key_manager.cached_plaintext(user_id, message, content).await? {
+            return Ok(Some(plaintext));
+        }
+        
+        let plaintext = self.decrypt_message_content(content, user_id, &message.conversation_id, &message.sender_id).await?;
+        if let Some(plaintext) = &plaintext {
+            self.key_manager.cache_plaintext(user_id, message, content, plaintext).await?;
+        }
+        
+        Ok(plaintext)
+    }
+
+    /// 解密消息内容：私聊消息用 Double Ratchet，群聊消息用发送者密钥，更早的消息用会话密钥
+    ///
+    /// 用户没有这条消息的密钥时（例如加入群聊之前的消息）返回 None。
+    async fn decrypt_message_content(
+        &self, 
+        content: &str, 
+        user_id: &str, 
+        conversation_id: &str, 
+        sender_id: &str
+    ) -> Result<Option<String>, Error> {
+        if let Ok(message) = serde_json::from_str::<SenderKeyMessage>(content) {
+            return self.decrypt_group_message(&message, user_id, conversation_id, sender_id).await;
+        }
+        
+        let Ok(envelope) = serde_json::from_str::<RatchetEnvelope>(content) else {
+            self.ensure_session_key(conversation_id, user_id).await?;
+            return self.decrypt_content(content, user_id, conversation_id).map(Some);
+        };
+        
+        // 首次收到对端发起的会话时，确认其身份公钥与公钥目录中发布的一致
+        if let Some(init) = &envelope.prekey {
+            if sender_id != user_id
+                && !self.key_manager.has_ratchet_session(conversation_id, user_id, Some(&envelope.session_id)).await?
+            {
+                let sender_bundle = self.get_key_bundle(sender_id).await?;
+                if sender_bundle.identity_key != init.identity_key {
+                    return Err(Error::Encryption(format!(
+                        "Identity key of user {} does not match the published key bundle", sender_id
+                    )));
+                }
+            }
+        }
+        
+        self.key_manager.ratchet_decrypt(conversation_id, user_id, sender_id, &envelope).await.map(Some)
+    }
+
+    /// 解密群聊消息，首次遇到某位成员的发送者密钥时从分发记录中取回
+    async fn decrypt_group_message(
+        &self, 
+        message: &SenderKeyMessage, 
+        user_id: &str, 
+        conversation_id: &str, 
+        sender_id: &str
+    ) -> Result<Option<String>, Error> {
+        if sender_id != user_id && !self.key_manager.has_sender_key(conversation_id, user_id, sender_id, message.key_id).await? {
+            // 没有分发给该用户的密钥，例如加入群聊之前或离开群聊之后的消息
+            let Some(distribution) = self.db.get_sender_key_distribution(conversation_id, sender_id, user_id, message.key_id).await? else {
+                return Ok(None);
+            };
+            
+            let sender_bundle = self.get_key_bundle(sender_id).await?;
+            let shared_secret = self.key_manager
+                .derive_shared_secret(user_id, &public_key_from_bytes(&sender_bundle.identity_key)?)
+                .await?;
+            let encrypted: EncryptedMessage = serde_json::from_str(&distribution.ciphertext)
+                .map_err(|e| Error::Internal(format!("Failed to deserialize encrypted message: {}", e)))?;
+            let share: SenderKeyShare = serde_json::from_str(&self.key_manager.decrypt_message(&encrypted, &shared_secret)?)
+                .map_err(|e| Error::Internal(format!("Failed to deserialize sender key: {}", e)))?;
+            
+            if share.key_id != message.key_id {
+                return Err(Error::Encryption(format!("Sender key from user {} does not match", sender_id)));
+            }
+            self.key_manager.accept_sender_key(conversation_id, user_id, sender_id, share).await?;
+        }
+        
+        self.key_manager.sender_key_decrypt(conversation_id, user_id, sender_id, message).await
+    }
+
+    /// 使用用户的会话密钥解密单条存储内容
+    fn decrypt_content(
+        &self, 
+        content: &str, 
+        user_id: &str, 
+        conversation_id: &str
+    ) -> Result<String, Error> {
+        // 获取接收者的会话密钥
+        let shared_secret = self.session_keys.get_key(conversation_id, user_id)?
+            .ok_or_else(|| Error::Encryption(format!(
+                "No session key found for user {} in conversation {}", 
+                user_id, conversation_id
+            )))?;
+        
+        // 反序列化加密消息
+        let encrypted: EncryptedMessage = serde_json::from_str(content)
+            .map_err(|e| Error::Internal(format!("Failed to deserialize encrypted message: {}", e)))?;
+        
+        // 解密消息
+        self.key_manager.decrypt_message(&encrypted, &shared_secret)
+    }
+
     /// 将消息标记为已读
     pub async fn mark_message_read(
         &self, 
@@ -594,28 +3213,32 @@ impl ChatManager {
             ));
         }
         
-        // 更新所有未读消息的状态
-        let filter = doc! {
-            "conversation_id": conversation_id,
-            "sender_id": { "$ne": user_id }, // 只处理不是用户自己发送的消息
-            "$or": [
-                { "status": "Sent" },
-                { "status": "Delivered" }
-            ]
-        };
+        // 只更新调用者自己的回执
+        self.db.mark_messages_as_read(conversation_id, user_id).await
+    }
+
+    /// 获取消息的回执（谁已送达、谁已读）
+    pub async fn get_message_receipts(
+        &self,
+        message_id: &str,
+        user_id: &str,
+    ) -> Result<Vec<MessageReceipt>, Error> {
+        debug!("Getting receipts for message {} by user {}", message_id, user_id);
         
-        let update = doc! {
-            "$set": { "status": MessageStatus::Read.to_string() }
-        };
+        let (message, _) = self.db.get_message_for_participant(message_id, user_id).await?;
         
-        let options = UpdateOptions::builder().build();
+        let mut receipts: Vec<MessageReceipt> = message.receipts.into_iter()
+            .map(|(user_id, entry)| MessageReceipt {
+                user_id,
+                status: entry.status,
+                updated_at: entry.updated_at,
+            })
+            .collect();
         
-        let result = self.db.messages_collection
-            .update_many(filter, update, Some(options))
-            .await
-            .map_err(|e| Error::Database(format!("Failed to mark messages as read: {}", e)))?;
+        // 已读的排在前面，同状态按时间先后
+        receipts.sort_by(|a, b| b.status.cmp(&a.status).then(a.updated_at.cmp(&b.updated_at)));
         
-        Ok(result.modified_count)
+        Ok(receipts)
     }
 
 
@@ -648,9 +3271,154 @@ impl ChatManager {
     }
 }
 
-fn chrono_to_bson_datetime(dt: chrono::DateTime<Utc>) -> DateTime {
-    let system_time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(dt.timestamp() as u64)
-        + std::time::Duration::from_nanos(dt.timestamp_subsec_nanos() as u64);
+// 密钥库尚未解锁
+fn keystore_locked() -> Error {
+    Error::Encryption("Keystore is locked, unlock it with the passphrase first".to_string())
+}
+
+// 以接收方身份，用自己的身份私钥和签名预共享私钥建立会话，由调用方在解密成功后保存
+fn accept_ratchet_session(
+    key_store: &KeyStore,
+    conversation_id: &str,
+    user_id: &str,
+    peer_id: &str,
+    session_id: &str,
+    init: &X3dhInit,
+) -> Result<RatchetSession, Error> {
+    let identity = key_store.identity(user_id).ok_or_else(|| 
+        Error::Internal(format!("No key pair found for user {}", user_id)))?;
+    let signed_prekey = key_store.signed_prekey(user_id, init.signed_prekey_id).ok_or_else(|| 
+        Error::Encryption(format!("Signed prekey {} is no longer available", init.signed_prekey_id)))?;
+    let peer_identity_key = public_key_from_bytes(&init.identity_key)?;
+    let peer_ephemeral_key = public_key_from_bytes(&init.ephemeral_key)?;
+    
+    let shared_secret = encryption::x3dh_respond(&identity, &signed_prekey, &peer_identity_key, &peer_ephemeral_key);
+    let associated_data = [init.identity_key.as_slice(), PublicKey::from(&identity).as_bytes()].concat();
+    
+    Ok(RatchetSession {
+        conversation_id: conversation_id.to_string(),
+        user_id: user_id.to_string(),
+        peer_id: peer_id.to_string(),
+        session_id: session_id.to_string(),
+        state: RatchetState::respond(shared_secret, &signed_prekey, associated_data),
+        pending_prekey: None,
+        updated_at: Utc::now(),
+    })
+}
+
+// 使用一次性消息密钥的密文（Double Ratchet 或发送者密钥）的标识，会话密钥加密的内容返回 None
+fn one_time_message_ref(content: &str, sender_id: &str) -> Option<String> {
+    if let Ok(message) = serde_json::from_str::<SenderKeyMessage>(content) {
+        return Some(message.message_ref(sender_id));
+    }
+    serde_json::from_str::<RatchetEnvelope>(content).ok().map(|envelope| envelope.message_ref())
+}
+
+// 从公钥包中的字节恢复 X25519 公钥
+fn public_key_from_bytes(bytes: &[u8]) -> Result<PublicKey, Error> {
+    let bytes: [u8; 32] = bytes.try_into()
+        .map_err(|_| Error::Encryption("Invalid identity key length".to_string()))?;
+    Ok(PublicKey::from(bytes))
+}
+
+// 系统消息只能由 ChatManager 生成
+fn ensure_not_system_message(new_message: &NewMessage) -> Result<(), Error> {
+    if new_message.content_type == MessageType::System || new_message.system_event.is_some() {
+        return Err(Error::Validation("System messages cannot be sent by clients".to_string()));
+    }
+    
+    Ok(())
+}
+
+// 系统消息的文字说明
+fn describe_system_event(event: &SystemEvent) -> String {
+    let targets = event.target_ids.join(", ");
+    
+    match event.action {
+        SystemAction::GroupCreated => {
+            match event.value.as_ref().and_then(|v| v.as_str()) {
+                Some(name) => format!("{} created the group \"{}\"", event.actor_id, name),
+                None => format!("{} created the group", event.actor_id),
+            }
+        }
+        SystemAction::MemberAdded => format!("{} added {}", event.actor_id, targets),
+        SystemAction::MemberRemoved => format!("{} removed {}", event.actor_id, targets),
+        SystemAction::MemberPromoted => format!("{} made {} an admin", event.actor_id, targets),
+        SystemAction::MemberDemoted => format!("{} removed {} as admin", event.actor_id, targets),
+        SystemAction::OwnershipTransferred => format!("{} transferred group ownership to {}", event.actor_id, targets),
+        SystemAction::GroupPolicyChanged => format!("{} changed the group permissions", event.actor_id),
+        SystemAction::GroupInfoUpdated => {
+            let changes = event.value.as_ref().and_then(|v| v.as_object());
+            let renamed_to = changes.and_then(|c| c.get("name")).and_then(|v| v.as_str());
+            match (renamed_to, changes.map_or(0, |c| c.len())) {
+                (Some(name), 1) => format!("{} renamed the group to \"{}\"", event.actor_id, name),
+                (Some(name), _) => format!("{} renamed the group to \"{}\" and updated the group info", event.actor_id, name),
+                (None, _) => format!("{} updated the group info", event.actor_id),
+            }
+        }
+        SystemAction::MemberJoined => format!("{} joined the group via invite link", event.actor_id),
+        SystemAction::MemberLeft => format!("{} left the group", event.actor_id),
+        SystemAction::ConversationDeleted => format!("{} deleted the conversation", event.actor_id),
+        SystemAction::MessageTtlChanged => {
+            match event.value.as_ref().and_then(|v| v.as_u64()) {
+                Some(ttl) => format!("{} set disappearing messages to {}", event.actor_id, format_duration(ttl)),
+                None => format!("{} turned off disappearing messages", event.actor_id),
+            }
+        }
+    }
+}
+
+// 生成 URL 安全的随机邀请 token
+fn generate_invite_token() -> String {
+    let mut bytes = [0u8; INVITE_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// 把秒数格式化为最大的整单位，例如 "1 day"、"90 minutes"
+fn format_duration(secs: u64) -> String {
+    const UNITS: [(u64, &str); 4] = [(86400, "day"), (3600, "hour"), (60, "minute"), (1, "second")];
+    
+    let (size, unit) = UNITS.iter()
+        .copied()
+        .find(|(size, _)| secs % size == 0)
+        .unwrap_or((1, "second"));
+    let count = secs / size;
+    
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// 汇总消息状态：取当前参与者中最落后的回执，单聊即为对方的状态
+fn aggregate_status(message: &Message, participants: &[String]) -> MessageStatus {
+    // 回执补建之前的旧消息只有共享的状态字段
+    if message.receipts.is_empty() {
+        return message.status.unwrap_or(MessageStatus::Sent);
+    }
+    
+    message.receipts.iter()
+        .filter(|(user_id, _)| participants.contains(user_id))
+        .map(|(_, entry)| entry.status)
+        .min()
+        .unwrap_or(MessageStatus::Sent)
+}
+
+/// 按表情汇总回应，保持表情首次出现的顺序
+fn aggregate_reactions(reactions: &[Reaction]) -> Vec<ReactionCount> {
+    let mut counts: Vec<ReactionCount> = Vec::new();
+    
+    for reaction in reactions {
+        match counts.iter_mut().find(|c| c.emoji == reaction.emoji) {
+            Some(count) => {
+                count.count += 1;
+                count.user_ids.push(reaction.user_id.clone());
+            }
+            None => counts.push(ReactionCount {
+                emoji: reaction.emoji.clone(),
+                count: 1,
+                user_ids: vec![reaction.user_id.clone()],
+            }),
+        }
+    }
     
-    DateTime::from_system_time(system_time)
-}
\ No newline at end of file
+    counts
+}
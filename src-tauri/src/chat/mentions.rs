@@ -0,0 +1,102 @@
+use super::models::Mention;
+
+/// 用户ID中可能出现的字符，用于判断提及的边界
+fn is_id_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// 解析文本中的 @提及，只保留会话参与者（不含发送者本人）
+///
+/// @ 后按最长匹配查找参与者ID，因此中文等紧跟在ID后的文字不影响识别。
+/// 偏移和长度按字符计算，包含开头的 @。
+pub fn parse_mentions(content: &str, participants: &[String], sender_id: &str) -> Vec<Mention> {
+    let mut candidates: Vec<&String> = participants.iter()
+        .filter(|p| !p.is_empty() && p.as_str() != sender_id)
+        .collect();
+    candidates.sort_by(|a, b| b.len().cmp(&a.len()));
+
+    let mut mentions = Vec::new();
+    let mut previous: Option<char> = None;
+    let mut char_offset = 0;
+
+    for (byte_offset, c) in content.char_indices() {
+        // 邮箱等紧跟在ID字符后的 @ 不算提及
+        if c == '@' && !previous.map_or(false, is_id_char) {
+            let rest = &content[byte_offset + 1..];
+            let matched = candidates.iter().find(|p| {
+                rest.starts_with(p.as_str())
+                    && !rest[p.len()..].chars().next().map_or(false, is_id_char)
+            });
+
+            if let Some(user_id) = matched {
+                mentions.push(Mention {
+                    user_id: user_id.to_string(),
+                    offset: char_offset,
+                    length: user_id.chars().count() + 1,
+                });
+            }
+        }
+
+        previous = Some(c);
+        char_offset += 1;
+    }
+
+    mentions
+}
+
+/// 去重后的被提及用户
+pub fn mentioned_user_ids(mentions: &[Mention]) -> Vec<String> {
+    let mut user_ids: Vec<String> = Vec::new();
+    for mention in mentions {
+        if !user_ids.contains(&mention.user_id) {
+            user_ids.push(mention.user_id.clone());
+        }
+    }
+    user_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participants() -> Vec<String> {
+        vec!["alice".to_string(), "bob-2".to_string(), "carol".to_string()]
+    }
+
+    // 测试只保留会话参与者并计算字符偏移
+    #[test]
+    fn test_parse_mentions() {
+        let mentions = parse_mentions("你好 @bob-2，请看 @dave 和 @carol", &participants(), "alice");
+
+        assert_eq!(mentions.len(), 2);
+        assert_eq!(mentions[0].user_id, "bob-2");
+        assert_eq!(mentions[0].offset, 3);
+        assert_eq!(mentions[0].length, 6);
+        assert_eq!(mentions[1].user_id, "carol");
+        assert_eq!(mentions[1].offset, 21);
+    }
+
+    // 测试忽略发送者本人和邮箱地址
+    #[test]
+    fn test_ignore_sender_and_emails() {
+        let mentions = parse_mentions("@alice mail me at me@carol", &participants(), "alice");
+        assert!(mentions.is_empty());
+    }
+
+    // 测试中文紧跟在ID之后
+    #[test]
+    fn test_mention_followed_by_cjk() {
+        let mentions = parse_mentions("@carol请确认", &participants(), "alice");
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].user_id, "carol");
+        assert_eq!(mentions[0].length, 6);
+    }
+
+    // 测试重复提及只通知一次
+    #[test]
+    fn test_mentioned_user_ids_dedup() {
+        let mentions = parse_mentions("@bob-2 @bob-2 @carol", &participants(), "alice");
+        assert_eq!(mentions.len(), 3);
+        assert_eq!(mentioned_user_ids(&mentions), vec!["bob-2".to_string(), "carol".to_string()]);
+    }
+}
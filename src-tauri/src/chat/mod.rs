@@ -2,6 +2,12 @@
 pub mod commands;
 pub mod db;
 pub mod encryption;
+pub mod keystore;
 pub mod manager;
+pub mod mentions;
 pub mod models;
+pub mod permissions;
+pub mod plaintexts;
+pub mod preferences;
+pub mod search;
 pub mod websocket;
\ No newline at end of file
@@ -1,16 +1,20 @@
 // models.rs
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum MessageType {
     Text,
     Image,
     File,
     Voice,
+    // 由 ChatManager 自动生成，不能由客户端发送
+    System,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+// 声明顺序即状态先后：Sent < Delivered < Read
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MessageStatus {
     Sent,
     Delivered,
@@ -25,9 +29,186 @@ pub struct Message {
     pub content: String,
     pub content_type: MessageType,
     pub timestamp: DateTime<Utc>,
+    // 汇总状态：所有接收者中最落后的状态，读取时由 receipts 计算
     pub status: Option<MessageStatus>,
+    // 每个接收者的送达/已读状态，键为用户ID
+    #[serde(default)]
+    pub receipts: HashMap<String, ReceiptEntry>,
     pub encrypted: bool,
     pub media_url: Option<String>,
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub revisions: Vec<MessageRevision>,
+    // 选择“仅对我删除”的用户
+    #[serde(default)]
+    pub deleted_for: Vec<String>,
+    // 发送者撤回后保留为墓碑记录，内容被清空
+    #[serde(default)]
+    pub retracted_at: Option<DateTime<Utc>>,
+    // 引用回复的消息ID
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    // 所属话题的根消息ID，为空表示在主时间线中
+    #[serde(default)]
+    pub thread_root_id: Option<String>,
+    // 作为话题根消息时的回复数
+    #[serde(default)]
+    pub thread_reply_count: u32,
+    // 被引用消息的预览，读取时按请求用户生成，不持久化
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_preview: Option<ReplyPreview>,
+    // 转发来源，多次转发时保留最初的来源
+    #[serde(default)]
+    pub forwarded_from: Option<ForwardedFrom>,
+    // 文本中 @提及的会话参与者
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // 系统消息的结构化内容
+    #[serde(default)]
+    pub system_event: Option<SystemEvent>,
+    // 阅后即焚的过期时间，由发送时会话的 message_ttl 决定
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    // 按表情汇总的回应数，读取时生成，不持久化
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reaction_counts: Vec<ReactionCount>,
+    // 请求用户没有这条加密消息的密钥（例如加入群聊之前的消息），内容为空；读取时设置，不持久化
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub undecryptable: bool,
+    // 发送者验证过、但身份密钥随后发生变化的会话成员；只在 send_message 的返回值中设置，不持久化
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_changes: Vec<String>,
+}
+
+impl Message {
+    /// 消息是否已过期，过期的消息在被清理前也不应返回给用户
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+// 系统事件的动作
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SystemAction {
+    GroupCreated,
+    MemberAdded,
+    MemberRemoved,
+    MemberPromoted,
+    MemberDemoted,
+    OwnershipTransferred,
+    GroupPolicyChanged,
+    MessageTtlChanged,
+    GroupInfoUpdated,
+    MemberJoined,
+    MemberLeft,
+    ConversationDeleted,
+}
+
+// 系统消息的结构化内容：操作者、动作和作用对象
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SystemEvent {
+    pub actor_id: String,
+    pub action: SystemAction,
+    #[serde(default)]
+    pub target_ids: Vec<String>,
+    // 动作相关的取值，例如新的消息保留时长
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
+}
+
+// 转发消息的来源
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardedFrom {
+    pub message_id: String,
+    pub conversation_id: String,
+    pub sender_id: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+// 消息中的 @提及，偏移和长度按字符计算
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Mention {
+    pub user_id: String,
+    pub offset: usize,
+    pub length: usize,
+}
+
+// 用户对消息的表情回应
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    pub user_id: String,
+    pub emoji: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+// 单个表情的回应汇总
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: u32,
+    pub user_ids: Vec<String>,
+}
+
+// 被引用消息的预览
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplyPreview {
+    pub message_id: String,
+    pub sender_id: String,
+    pub content: String,
+    pub content_type: MessageType,
+    pub retracted: bool,
+}
+
+// 一页会话消息，以及请求用户验证过、但身份密钥随后发生变化的会话成员
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagesPage {
+    pub messages: Vec<Message>,
+    pub key_changes: Vec<String>,
+}
+
+// 话题：根消息及其分页的回复
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageThread {
+    pub root: Message,
+    pub replies: Vec<Message>,
+}
+
+// 单个接收者的消息状态
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReceiptEntry {
+    pub status: MessageStatus,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 消息回执，用于查询谁已送达/已读
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageReceipt {
+    pub user_id: String,
+    pub status: MessageStatus,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 消息的历史版本，编辑时保存被替换的内容
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageRevision {
+    pub content: String,
+    pub edited_at: DateTime<Utc>,
+}
+
+// 删除消息的方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteMode {
+    // 仅对自己隐藏
+    ForMe,
+    // 发送者在时限内为所有人撤回
+    ForEveryone,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -47,10 +228,182 @@ pub struct Conversation {
     pub updated_at: DateTime<Utc>,
     pub last_message: Option<Message>,
     pub encryption_enabled: bool,
+    // 请求用户在该会话中的未读消息数，读取时计算
+    #[serde(default)]
+    pub unread_count: u64,
+    #[serde(default)]
+    pub pinned_messages: Vec<PinnedMessage>,
+    // 阅后即焚：新消息在发送后保留的秒数，为空表示不过期
+    #[serde(default)]
+    pub message_ttl: Option<u64>,
+    // 请求用户在该会话中是否有未发送的草稿，读取时计算
+    #[serde(default)]
+    pub has_draft: bool,
+    // 请求用户对该会话的偏好设置，读取时填充，不持久化
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferences: Option<ConversationPreferences>,
+    // 群角色，只记录群主和管理员，未记录的参与者为普通成员
+    #[serde(default)]
+    pub roles: HashMap<String, GroupRole>,
+    // 群内各项操作需要的权限
+    #[serde(default)]
+    pub group_policy: GroupPolicy,
+    // 群简介
+    #[serde(default)]
+    pub description: Option<String>,
+    // 群头像的媒体地址
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    // 私聊双方的无序标识，由唯一索引保证同一对用户只有一个私聊
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct_key: Option<String>,
+    // 加密群聊的发送者密钥代数，每次有成员离开都会增加，成员发现自己的密钥落后时轮换
+    #[serde(default)]
+    pub sender_key_epoch: u32,
+}
+
+impl Conversation {
+    /// 两个用户之间私聊的标识，与参数顺序无关
+    pub fn direct_key(user_a: &str, user_b: &str) -> String {
+        let mut pair = [user_a, user_b];
+        pair.sort();
+        // 序列化为 JSON 数组，用户ID中包含分隔符也不会产生歧义
+        serde_json::to_string(&pair).expect("serializing a pair of strings cannot fail")
+    }
+}
+
+// 群成员角色，声明顺序即权限高低：Member < Admin < Owner
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+// 执行某项群操作所需的最低角色
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum PermissionLevel {
+    Everyone,
+    AdminsOnly,
+    OwnerOnly,
+}
+
+impl PermissionLevel {
+    pub fn allows(&self, role: GroupRole) -> bool {
+        match self {
+            PermissionLevel::Everyone => true,
+            PermissionLevel::AdminsOnly => role >= GroupRole::Admin,
+            PermissionLevel::OwnerOnly => role == GroupRole::Owner,
+        }
+    }
+}
+
+// 群权限策略，由群主修改
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupPolicy {
+    pub add_members: PermissionLevel,
+    pub remove_members: PermissionLevel,
+    pub edit_info: PermissionLevel,
+    pub pin_messages: PermissionLevel,
+    pub change_settings: PermissionLevel,
+}
+
+impl Default for GroupPolicy {
+    fn default() -> Self {
+        Self {
+            add_members: PermissionLevel::AdminsOnly,
+            remove_members: PermissionLevel::AdminsOnly,
+            edit_info: PermissionLevel::AdminsOnly,
+            pin_messages: PermissionLevel::Everyone,
+            change_settings: PermissionLevel::AdminsOnly,
+        }
+    }
+}
+
+// 用户对会话的个人偏好，按 (user_id, conversation_id) 单独存储
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConversationPreferences {
+    pub user_id: String,
+    pub conversation_id: String,
+    #[serde(default)]
+    pub archived: bool,
+    // 收到新消息时保持归档，不自动取消
+    #[serde(default)]
+    pub keep_archived: bool,
+    #[serde(default)]
+    pub muted: bool,
+    // 静音截止时间，为空表示一直静音
+    #[serde(default)]
+    pub muted_until: Option<DateTime<Utc>>,
+    // 置顶到会话列表顶部
+    #[serde(default)]
+    pub pinned: bool,
+    // 自定义排序值，越小越靠前
+    #[serde(default)]
+    pub sort_order: Option<i64>,
+    // 用户删除的私聊，收到新消息后重新出现
+    #[serde(default)]
+    pub hidden: bool,
+    // 严格验证：已验证成员的身份密钥变化后禁止发送，直到重新验证
+    #[serde(default)]
+    pub strict_verification: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ConversationPreferences {
+    pub fn new(user_id: &str, conversation_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            archived: false,
+            keep_archived: false,
+            muted: false,
+            muted_until: None,
+            pinned: false,
+            sort_order: None,
+            hidden: false,
+            strict_verification: false,
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// 当前是否处于静音状态，到达截止时间后自动解除
+    pub fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        self.muted && self.muted_until.map_or(true, |until| until > now)
+    }
+}
+
+// 用户在会话中未发送的草稿，每个用户每个会话一份
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Draft {
+    pub user_id: String,
+    pub conversation_id: String,
+    pub content: String,
+    // 已上传但尚未发送的媒体地址
+    #[serde(default)]
+    pub media_urls: Vec<String>,
+    // 加密会话中 content 和 media_urls 以用户的会话密钥加密保存
+    pub encrypted: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 会话中被置顶的消息
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PinnedMessage {
+    pub message_id: String,
+    pub pinned_by: String,
+    pub pinned_at: DateTime<Utc>,
+    // 被置顶的消息内容，读取时按请求用户填充，不持久化
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
 }
 
 // 用于创建新消息的简化结构
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct NewMessage {
     pub conversation_id: String,
@@ -59,6 +412,161 @@ pub struct NewMessage {
     pub content_type: MessageType,
     pub media_url: Option<String>,
     pub encrypted: bool,
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    #[serde(default)]
+    pub thread_root_id: Option<String>,
+    #[serde(default)]
+    pub forwarded_from: Option<ForwardedFrom>,
+    // 由 ChatManager 在发送时从文本中解析
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+    // 仅 ChatManager 生成系统消息时设置
+    #[serde(default)]
+    pub system_event: Option<SystemEvent>,
+}
+
+// 定时消息的调度状态
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduledMessageStatus {
+    Pending,
+    Sending,
+    Sent,
+    Failed,
+    Cancelled,
+}
+
+impl ScheduledMessageStatus {
+    pub const ALL: [ScheduledMessageStatus; 5] = [
+        Self::Pending, Self::Sending, Self::Sent, Self::Failed, Self::Cancelled,
+    ];
+
+    /// 定时消息能否从当前状态变为 next
+    ///
+    /// 待发送的消息由调度器取出后变为发送中，发送中的消息成功后为已发送，
+    /// 失败后重新排队或标记为失败；待发送和失败的消息可以取消。
+    pub fn can_transition_to(self, next: ScheduledMessageStatus) -> bool {
+        use ScheduledMessageStatus::*;
+        
+        matches!(
+            (self, next),
+            (Pending, Sending)
+                | (Pending, Cancelled)
+                | (Sending, Sent)
+                | (Sending, Pending)
+                | (Sending, Failed)
+                | (Failed, Cancelled)
+        )
+    }
+
+    /// 只有待发送的定时消息可以修改内容和发送时间
+    pub fn is_editable(self) -> bool {
+        self == Self::Pending
+    }
+
+    /// 已发送和已取消的定时消息不会再变化
+    pub fn is_final(self) -> bool {
+        Self::ALL.into_iter().all(|next| !self.can_transition_to(next))
+    }
+}
+
+// 定时消息：到达 send_at 后由调度器通过 ChatManager::send_message 发送
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduledMessage {
+    pub id: String,
+    pub sender_id: String,
+    pub conversation_id: String,
+    // 加密会话中 content 以发送者的会话密钥加密保存
+    pub message: NewMessage,
+    pub send_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub status: ScheduledMessageStatus,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    // 发送成功后生成的消息ID
+    #[serde(default)]
+    pub sent_message_id: Option<String>,
+}
+
+// 群邀请链接，持有 token 的用户可以自行加入群聊
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupInvite {
+    pub id: String,
+    pub conversation_id: String,
+    pub created_by: String,
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+    // 为空表示不过期
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    // 为空表示不限次数
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub use_count: u32,
+    #[serde(default)]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl GroupInvite {
+    /// 邀请是否仍可使用：未撤销、未过期且未用完
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.map_or(true, |expires_at| expires_at > now)
+            && self.max_uses.map_or(true, |max_uses| self.use_count < max_uses)
+    }
+}
+
+// 用户在一台设备上发布的公钥包，其他用户只凭其中的公钥与该用户协商会话密钥
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeyBundle {
+    pub user_id: String,
+    pub device_id: String,
+    // X25519 身份公钥
+    pub identity_key: Vec<u8>,
+    // 为预共享公钥签名的 Ed25519 公钥
+    pub signing_key: Vec<u8>,
+    pub signed_prekey: SignedPreKey,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 由身份签名密钥签名的 X25519 预共享公钥，定期轮换
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignedPreKey {
+    pub key_id: u32,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+// 两个用户之间的安全码，由双方的身份公钥计算，当面或通过其他渠道比对一致即可确认没有中间人
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SafetyNumber {
+    pub user_id: String,
+    pub peer_id: String,
+    // 60 位数字，每 5 位一组
+    pub numeric_code: String,
+    // 二维码内容，扫描对方的二维码即可比对
+    pub qr_payload: String,
+    // 用户已验证对方当前的身份公钥
+    pub verified: bool,
+    // 用户验证过对方，但对方的身份公钥随后发生了变化
+    pub key_changed: bool,
+}
+
+// 群成员通过成对通道发给另一成员的发送者密钥，只有接收者能解密
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SenderKeyDistribution {
+    pub conversation_id: String,
+    pub sender_id: String,
+    pub recipient_id: String,
+    pub key_id: u32,
+    // 用发送者和接收者身份密钥派生的共享密钥加密的分发内容
+    pub ciphertext: String,
+    pub created_at: DateTime<Utc>,
 }
 
 // 用于创建新会话的简化结构
@@ -69,6 +577,18 @@ pub struct NewConversation {
     pub conversation_type: ConversationType,
     pub participants: Vec<String>,
     pub encryption_enabled: bool,
+    // 群聊创建时记录群主
+    #[serde(default)]
+    pub roles: HashMap<String, GroupRole>,
+}
+
+// 修改群资料的请求，字段为空表示不修改；简介和头像传空字符串表示清除
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupInfoUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
 }
 
 // 用于更新消息状态的结构
@@ -88,4 +608,74 @@ impl std::fmt::Display for MessageStatus {
             Self::Read => write!(f, "Read"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn invite(expires_at: Option<DateTime<Utc>>, max_uses: Option<u32>, use_count: u32) -> GroupInvite {
+        GroupInvite {
+            id: "i1".to_string(),
+            conversation_id: "g1".to_string(),
+            created_by: "alice".to_string(),
+            token: "token".to_string(),
+            created_at: Utc::now(),
+            expires_at,
+            max_uses,
+            use_count,
+            revoked_at: None,
+        }
+    }
+
+    // 测试私聊标识与参数顺序无关，且用户ID中的分隔符不会造成冲突
+    #[test]
+    fn test_direct_key() {
+        assert_eq!(Conversation::direct_key("alice", "bob"), Conversation::direct_key("bob", "alice"));
+        assert_eq!(Conversation::direct_key("alice", "bob"), r#"["alice","bob"]"#);
+        assert_ne!(Conversation::direct_key("alice", "bob"), Conversation::direct_key("alice", "carol"));
+        assert_ne!(Conversation::direct_key("a\nb", "c"), Conversation::direct_key("a", "b\nc"));
+        assert_ne!(Conversation::direct_key("a\",\"b", "c"), Conversation::direct_key("a", "b\",\"c"));
+    }
+
+    // 测试邀请在撤销、过期或用完后失效
+    #[test]
+    fn test_group_invite_is_active() {
+        let now = Utc::now();
+
+        assert!(invite(None, None, 100).is_active(now));
+        assert!(invite(Some(now + Duration::hours(1)), Some(2), 1).is_active(now));
+
+        assert!(!invite(Some(now), None, 0).is_active(now));
+        assert!(!invite(Some(now - Duration::seconds(1)), None, 0).is_active(now));
+        assert!(!invite(None, Some(2), 2).is_active(now));
+
+        let revoked = GroupInvite { revoked_at: Some(now), ..invite(None, None, 0) };
+        assert!(!revoked.is_active(now));
+    }
+
+    // 测试定时消息的状态流转
+    #[test]
+    fn test_scheduled_status_transitions() {
+        use ScheduledMessageStatus::*;
+
+        assert!(Pending.can_transition_to(Sending));
+        assert!(Pending.can_transition_to(Cancelled));
+        assert!(Sending.can_transition_to(Sent));
+        assert!(Sending.can_transition_to(Pending));
+        assert!(Sending.can_transition_to(Failed));
+        assert!(Failed.can_transition_to(Cancelled));
+
+        // 发送中的消息不能取消，失败的消息不会自动重试
+        assert!(!Sending.can_transition_to(Cancelled));
+        assert!(!Failed.can_transition_to(Pending));
+        assert!(!Pending.can_transition_to(Sent));
+
+        let finals: Vec<_> = ScheduledMessageStatus::ALL.into_iter().filter(|s| s.is_final()).collect();
+        assert_eq!(finals, vec![Sent, Cancelled]);
+
+        let editable: Vec<_> = ScheduledMessageStatus::ALL.into_iter().filter(|s| s.is_editable()).collect();
+        assert_eq!(editable, vec![Pending]);
+    }
+}
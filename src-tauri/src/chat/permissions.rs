@@ -0,0 +1,176 @@
+use super::models::{Conversation, ConversationType, GroupRole};
+use crate::error::Error;
+
+/// 需要按群角色检查权限的操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupAction<'a> {
+    AddMembers,
+    RemoveMember(&'a str),
+    EditInfo,
+    PinMessages,
+    ChangeSettings,
+    // 设置管理员、转让群主、修改权限策略，只有群主可以执行
+    ManageRoles,
+}
+
+impl GroupAction<'_> {
+    fn describe(&self) -> &'static str {
+        match self {
+            GroupAction::AddMembers => "add members",
+            GroupAction::RemoveMember(_) => "remove this member",
+            GroupAction::EditInfo => "edit group info",
+            GroupAction::PinMessages => "pin messages",
+            GroupAction::ChangeSettings => "change settings",
+            GroupAction::ManageRoles => "manage roles",
+        }
+    }
+}
+
+/// 用户在会话中的角色，不是参与者时返回 None
+pub fn role_of(conversation: &Conversation, user_id: &str) -> Option<GroupRole> {
+    if !conversation.participants.iter().any(|p| p == user_id) {
+        return None;
+    }
+
+    Some(conversation.roles.get(user_id).copied().unwrap_or(GroupRole::Member))
+}
+
+/// 群主的用户ID
+pub fn owner_of(conversation: &Conversation) -> Option<&str> {
+    conversation.roles.iter()
+        .find(|(user_id, role)| **role == GroupRole::Owner && conversation.participants.contains(user_id))
+        .map(|(user_id, _)| user_id.as_str())
+}
+
+/// 群主离开时自动接任的成员：优先管理员，其次普通成员，同级按加入顺序
+pub fn successor_of<'a>(conversation: &'a Conversation, leaving_user_id: &str) -> Option<&'a str> {
+    let candidates = || conversation.participants.iter().filter(|p| p.as_str() != leaving_user_id);
+    
+    candidates()
+        .find(|p| conversation.roles.get(p.as_str()) == Some(&GroupRole::Admin))
+        .or_else(|| candidates().next())
+        .map(|p| p.as_str())
+}
+
+/// 检查用户是否可以在会话中执行操作
+///
+/// 私聊没有角色，参与者都可以执行。角色功能之前创建的群聊没有记录群主，
+/// 保持原有规则，任何参与者都可以执行，直到群主被指定为止。
+pub fn check_permission(conversation: &Conversation, user_id: &str, action: GroupAction) -> Result<(), Error> {
+    let role = role_of(conversation, user_id).ok_or_else(|| Error::Authentication(
+        format!("User {} is not a participant in conversation {}", user_id, conversation.id)
+    ))?;
+
+    if conversation.conversation_type == ConversationType::Direct || owner_of(conversation).is_none() {
+        return Ok(());
+    }
+
+    let policy = &conversation.group_policy;
+    let allowed = match action {
+        GroupAction::AddMembers => policy.add_members.allows(role),
+        GroupAction::RemoveMember(target_id) => {
+            // 群主不能被移除，管理员只能由群主移除
+            let target_role = role_of(conversation, target_id).unwrap_or(GroupRole::Member);
+            policy.remove_members.allows(role)
+                && (target_role == GroupRole::Member || (target_role == GroupRole::Admin && role == GroupRole::Owner))
+        }
+        GroupAction::EditInfo => policy.edit_info.allows(role),
+        GroupAction::PinMessages => policy.pin_messages.allows(role),
+        GroupAction::ChangeSettings => policy.change_settings.allows(role),
+        GroupAction::ManageRoles => role == GroupRole::Owner,
+    };
+
+    if !allowed {
+        return Err(Error::Authentication(format!(
+            "User {} is not allowed to {} in group {}", user_id, action.describe(), conversation.id
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::models::{GroupPolicy, PermissionLevel};
+    use chrono::Utc;
+    use std::collections::HashMap;
+
+    fn group(roles: &[(&str, GroupRole)]) -> Conversation {
+        Conversation {
+            id: "g1".to_string(),
+            name: Some("team".to_string()),
+            conversation_type: ConversationType::Group,
+            participants: vec!["owner".to_string(), "admin".to_string(), "alice".to_string(), "bob".to_string()],
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            last_message: None,
+            encryption_enabled: false,
+            unread_count: 0,
+            pinned_messages: Vec::new(),
+            message_ttl: None,
+            has_draft: false,
+            preferences: None,
+            roles: roles.iter().map(|(u, r)| (u.to_string(), *r)).collect::<HashMap<_, _>>(),
+            group_policy: GroupPolicy::default(),
+            description: None,
+            avatar_url: None,
+            direct_key: None,
+            sender_key_epoch: 0,
+        }
+    }
+
+    fn team() -> Conversation {
+        group(&[("owner", GroupRole::Owner), ("admin", GroupRole::Admin)])
+    }
+
+    // 测试默认策略下只有管理员可以添加成员，所有人都可以置顶
+    #[test]
+    fn test_default_policy() {
+        let conversation = team();
+
+        assert!(check_permission(&conversation, "admin", GroupAction::AddMembers).is_ok());
+        assert!(check_permission(&conversation, "alice", GroupAction::AddMembers).is_err());
+        assert!(check_permission(&conversation, "alice", GroupAction::PinMessages).is_ok());
+        assert!(check_permission(&conversation, "admin", GroupAction::ManageRoles).is_err());
+        assert!(check_permission(&conversation, "owner", GroupAction::ManageRoles).is_ok());
+        assert!(check_permission(&conversation, "mallory", GroupAction::PinMessages).is_err());
+    }
+
+    // 测试移除成员的层级：管理员不能移除管理员，任何人都不能移除群主
+    #[test]
+    fn test_remove_member_hierarchy() {
+        let conversation = team();
+
+        assert!(check_permission(&conversation, "admin", GroupAction::RemoveMember("alice")).is_ok());
+        assert!(check_permission(&conversation, "admin", GroupAction::RemoveMember("owner")).is_err());
+        assert!(check_permission(&conversation, "owner", GroupAction::RemoveMember("admin")).is_ok());
+        assert!(check_permission(&conversation, "alice", GroupAction::RemoveMember("bob")).is_err());
+
+        let mut open = team();
+        open.group_policy.remove_members = PermissionLevel::Everyone;
+        assert!(check_permission(&open, "alice", GroupAction::RemoveMember("bob")).is_ok());
+        assert!(check_permission(&open, "alice", GroupAction::RemoveMember("admin")).is_err());
+    }
+
+    // 测试群主离开时优先由管理员接任
+    #[test]
+    fn test_successor_prefers_admins() {
+        assert_eq!(successor_of(&team(), "owner"), Some("admin"));
+        assert_eq!(successor_of(&group(&[("owner", GroupRole::Owner)]), "owner"), Some("admin"));
+        
+        let mut alone = team();
+        alone.participants = vec!["owner".to_string()];
+        assert_eq!(successor_of(&alone, "owner"), None);
+    }
+
+    // 测试没有群主的旧群聊保持原有规则
+    #[test]
+    fn test_legacy_group_without_owner() {
+        let conversation = group(&[]);
+
+        assert!(check_permission(&conversation, "alice", GroupAction::RemoveMember("bob")).is_ok());
+        assert!(check_permission(&conversation, "alice", GroupAction::ManageRoles).is_ok());
+        assert!(check_permission(&conversation, "mallory", GroupAction::AddMembers).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use base64::engine::{general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+
+/// 日志中的记录数超过有效明文数的倍数时重写日志
+const COMPACT_RATIO: usize = 2;
+
+/// 记录数少于此值时不重写日志
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+const NONCE_LEN: usize = 12;
+
+// 用户在本设备上发送或读过的消息明文
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedPlaintext {
+    conversation_id: String,
+    user_id: String,
+    message_id: String,
+    plaintext: String,
+    // 与消息的过期时间一致，阅后即焚的消息过期后一并删除
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    cached_at: DateTime<Utc>,
+}
+
+impl CachedPlaintext {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map_or(false, |at| at <= now)
+    }
+}
+
+// 日志中的一条记录，entry 为空表示删除
+#[derive(Serialize, Deserialize)]
+struct LogRecord {
+    key: String,
+    #[serde(default)]
+    entry: Option<CachedPlaintext>,
+}
+
+/// 本设备上的消息明文缓存
+///
+/// 消息密钥用后即丢弃，重复读取历史消息只能依靠这里的明文，因此不设数量上限，
+/// 只在消息过期、撤回或会话的密钥被删除时移除。
+/// 每次修改作为一条单独加密的记录追加到日志文件，写入开销与缓存大小无关；
+/// 失效的记录过多时重写整个日志。密钥与密钥库相同，每条记录使用新的随机 nonce。
+pub struct PlaintextCache {
+    path: PathBuf,
+    key: [u8; 32],
+    // 键为用户 ID 和消息标识，见 plaintext_key
+    entries: HashMap<String, CachedPlaintext>,
+    // 日志文件中已有的记录数
+    logged_records: usize,
+    // 尚未写入日志的记录，批量修改结束时一次追加
+    pending: Vec<LogRecord>,
+}
+
+impl PlaintextCache {
+    /// 读取日志文件，文件不存在时为空缓存
+    ///
+    /// 最后一条记录不完整说明上次追加时被中断，忽略该记录；其他记录无法解密时返回错误。
+    pub fn open(path: &Path, key: [u8; 32]) -> Result<Self, Error> {
+        let mut cache = Self {
+            path: path.to_path_buf(),
+            key,
+            entries: HashMap::new(),
+            logged_records: 0,
+            pending: Vec::new(),
+        };
+        if !path.exists() {
+            return Ok(cache);
+        }
+
+        let raw = fs::read_to_string(path)?;
+        let lines: Vec<&str> = raw.lines().filter(|line| !line.is_empty()).collect();
+        let complete = raw.ends_with('\n');
+
+        for (i, line) in lines.iter().enumerate() {
+            let record = match cache.decode(line) {
+                Ok(record) => record,
+                Err(_) if !complete && i == lines.len() - 1 => break,
+                Err(e) => return Err(e),
+            };
+            match record.entry {
+                Some(entry) => cache.entries.insert(record.key, entry),
+                None => cache.entries.remove(&record.key),
+            };
+            cache.logged_records += 1;
+        }
+
+        let now = Utc::now();
+        cache.entries.retain(|_, p| !p.is_expired(now));
+        if !complete || cache.needs_compaction(0) {
+            cache.compact()?;
+        }
+
+        Ok(cache)
+    }
+
+    /// 创建空缓存并删除同一路径下的旧日志，旧日志用其他密钥加密，已无法读取
+    pub fn create(path: &Path, key: [u8; 32]) -> Result<Self, Error> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            key,
+            entries: HashMap::new(),
+            logged_records: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    /// 用户之前发送或读过的消息明文，已过期的不返回
+    pub fn get(&self, user_id: &str, message_ref: &str) -> Option<&str> {
+        let now = Utc::now();
+        self.entries.get(&plaintext_key(user_id, message_ref))
+            .filter(|p| !p.is_expired(now))
+            .map(|p| p.plaintext.as_str())
+    }
+
+    /// 缓存消息明文，调用 flush 后写入日志
+    pub fn insert(
+        &mut self,
+        conversation_id: &str,
+        user_id: &str,
+        message_id: &str,
+        message_ref: &str,
+        plaintext: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        let key = plaintext_key(user_id, message_ref);
+        let entry = CachedPlaintext {
+            conversation_id: conversation_id.to_string(),
+            user_id: user_id.to_string(),
+            message_id: message_id.to_string(),
+            plaintext: plaintext.to_string(),
+            expires_at,
+            cached_at: Utc::now(),
+        };
+
+        self.entries.insert(key.clone(), entry.clone());
+        self.pending.push(LogRecord { key, entry: Some(entry) });
+    }
+
+    /// 删除消息的所有缓存明文（包括历史版本），返回是否有删除
+    pub fn forget_message(&mut self, message_id: &str) -> bool {
+        self.remove_where(|p| p.message_id == message_id)
+    }
+
+    /// 删除会话中的缓存明文，user_id 为空时删除所有用户的，返回是否有删除
+    pub fn forget_conversation(&mut self, conversation_id: &str, user_id: Option<&str>) -> bool {
+        self.remove_where(|p| p.conversation_id == conversation_id && user_id.map_or(true, |id| p.user_id == id))
+    }
+
+    /// 缓存的明文数量
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 把尚未写入的记录追加到日志，失效记录过多时改为重写日志
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.needs_compaction(self.pending.len()) {
+            return self.compact();
+        }
+
+        let mut lines = String::new();
+        for record in &self.pending {
+            lines.push_str(&self.encode(record)?);
+            lines.push('\n');
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()?;
+
+        self.logged_records += self.pending.len();
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn remove_where(&mut self, matches: impl Fn(&CachedPlaintext) -> bool) -> bool {
+        let keys: Vec<String> = self.entries.iter()
+            .filter(|(_, p)| matches(p))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &keys {
+            self.entries.remove(key);
+        }
+        let removed = !keys.is_empty();
+        self.pending.extend(keys.into_iter().map(|key| LogRecord { key, entry: None }));
+        removed
+    }
+
+    fn needs_compaction(&self, new_records: usize) -> bool {
+        let records = self.logged_records + new_records;
+        records >= COMPACT_MIN_RECORDS && records > self.entries.len() * COMPACT_RATIO
+    }
+
+    /// 丢弃已过期的明文，只把有效的明文写入新日志，先写临时文件再替换
+    fn compact(&mut self) -> Result<(), Error> {
+        let now = Utc::now();
+        self.entries.retain(|_, p| !p.is_expired(now));
+
+        let mut lines = String::new();
+        for (key, entry) in &self.entries {
+            let record = LogRecord { key: key.clone(), entry: Some(entry.clone()) };
+            lines.push_str(&self.encode(&record)?);
+            lines.push('\n');
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, lines)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.logged_records = self.entries.len();
+        self.pending.clear();
+        Ok(())
+    }
+
+    // 记录加密后编码为一行：base64(nonce || 密文)
+    fn encode(&self, record: &LogRecord) -> Result<String, Error> {
+        let plaintext = serde_json::to_vec(record)
+            .map_err(|e| Error::Internal(format!("Failed to serialize cached plaintext: {}", e)))?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = Aes256Gcm::new_from_slice(&self.key)
+            .map_err(|e| Error::Encrypt(e.to_string()))?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|e| Error::Encrypt(e.to_string()))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+        Ok(STANDARD.encode(bytes))
+    }
+
+    fn decode(&self, line: &str) -> Result<LogRecord, Error> {
+        let bytes = STANDARD.decode(line)
+            .map_err(|e| Error::Decrypt(format!("Invalid cached plaintext record: {}", e)))?;
+        if bytes.len() < NONCE_LEN {
+            return Err(Error::InvalidNonce);
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new_from_slice(&self.key)
+            .map_err(|e| Error::Decrypt(e.to_string()))?;
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::Decrypt("Failed to decrypt cached plaintext record".to_string()))?;
+
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| Error::Internal(format!("Failed to parse cached plaintext: {}", e)))
+    }
+}
+
+fn plaintext_key(user_id: &str, message_ref: &str) -> String {
+    format!("{}/{}", user_id, message_ref)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("plaintexts-test-{}.log", Uuid::new_v4()))
+    }
+
+    // 测试明文在重新打开后保持不变，删除和过期的明文不返回，缓存不设数量上限
+    #[test]
+    fn test_reopen_keeps_plaintexts() {
+        let path = temp_path();
+        let key = [7u8; 32];
+
+        let mut cache = PlaintextCache::open(&path, key).unwrap();
+        for i in 0..6000 {
+            cache.insert("c1", "bob", &format!("m{}", i), &format!("s1:key:{}", i), "text", None);
+        }
+        cache.insert("c2", "bob", "m-old", "s2:key:0", "gone", Some(Utc::now() - Duration::seconds(1)));
+        cache.insert("c2", "alice", "m-hidden", "s3:key:0", "hidden", None);
+        cache.flush().unwrap();
+
+        assert!(cache.forget_message("m0"));
+        assert!(cache.forget_conversation("c2", Some("alice")));
+        assert!(!cache.forget_message("missing"));
+        cache.flush().unwrap();
+        drop(cache);
+
+        let reopened = PlaintextCache::open(&path, key).unwrap();
+        assert_eq!(reopened.len(), 5999);
+        assert!(reopened.get("bob", "s1:key:0").is_none());
+        assert_eq!(reopened.get("bob", "s1:key:5999"), Some("text"));
+        assert!(reopened.get("bob", "s2:key:0").is_none(), "Expired plaintext should not be returned");
+        assert!(reopened.get("alice", "s3:key:0").is_none());
+
+        let raw = fs::read(&path).unwrap();
+        assert!(!raw.windows(b"hidden".len()).any(|w| w == b"hidden"));
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试每次写入只追加新记录，失效记录过多时重写日志
+    #[test]
+    fn test_append_and_compact() {
+        let path = temp_path();
+        let key = [7u8; 32];
+        let mut cache = PlaintextCache::open(&path, key).unwrap();
+
+        cache.insert("c1", "bob", "m1", "s1:key:0", "first", None);
+        cache.flush().unwrap();
+        let before = fs::read(&path).unwrap();
+        cache.insert("c1", "bob", "m2", "s1:key:1", "second", None);
+        cache.flush().unwrap();
+        let after = fs::read(&path).unwrap();
+        assert!(after.starts_with(&before), "Existing records should not be rewritten");
+
+        for i in 0..COMPACT_MIN_RECORDS {
+            cache.insert("c1", "bob", "m3", "s1:key:2", &format!("v{}", i), None);
+            cache.flush().unwrap();
+        }
+        assert!(cache.logged_records <= COMPACT_MIN_RECORDS);
+        assert_eq!(cache.get("bob", "s1:key:2"), Some(format!("v{}", COMPACT_MIN_RECORDS - 1).as_str()));
+        drop(cache);
+
+        let reopened = PlaintextCache::open(&path, key).unwrap();
+        assert_eq!(reopened.len(), 3);
+
+        fs::remove_file(&path).ok();
+    }
+
+    // 测试追加中断留下的不完整记录被忽略，其他损坏的记录返回错误
+    #[test]
+    fn test_truncated_record() {
+        let path = temp_path();
+        let key = [7u8; 32];
+        let mut cache = PlaintextCache::open(&path, key).unwrap();
+
+        cache.insert("c1", "bob", "m1", "s1:key:0", "kept", None);
+        cache.insert("c1", "bob", "m2", "s1:key:1", "lost", None);
+        cache.flush().unwrap();
+        drop(cache);
+
+        let raw = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &raw[..raw.len() - 10]).unwrap();
+        let reopened = PlaintextCache::open(&path, key).unwrap();
+        assert_eq!(reopened.get("bob", "s1:key:0"), Some("kept"));
+        assert!(reopened.get("bob", "s1:key:1").is_none());
+        drop(reopened);
+
+        assert!(PlaintextCache::open(&path, [8u8; 32]).is_err());
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,202 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use super::models::{Conversation, ConversationPreferences, Message};
+
+/// 会话列表的过滤条件，字段为空表示不按该条件过滤
+///
+/// 例如 `archived: Some(true)` 只返回已归档的会话，`muted: Some(false)` 排除静音中的会话。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationFilter {
+    pub archived: Option<bool>,
+    pub muted: Option<bool>,
+    pub pinned: Option<bool>,
+}
+
+impl ConversationFilter {
+    /// 检查会话是否满足过滤条件，没有偏好设置的会话视为未归档、未静音、未置顶
+    ///
+    /// 用户删除（隐藏）的会话不满足任何条件。
+    pub fn matches(&self, preferences: Option<&ConversationPreferences>, now: DateTime<Utc>) -> bool {
+        if preferences.map_or(false, |p| p.hidden) {
+            return false;
+        }
+        
+        let archived = preferences.map_or(false, |p| p.archived);
+        let muted = preferences.map_or(false, |p| p.is_muted(now));
+        let pinned = preferences.map_or(false, |p| p.pinned);
+
+        self.archived.map_or(true, |v| v == archived)
+            && self.muted.map_or(true, |v| v == muted)
+            && self.pinned.map_or(true, |v| v == pinned)
+    }
+}
+
+/// 按用户偏好排序会话列表
+///
+/// 置顶的会话在前；同组内设置了自定义顺序的会话按顺序值升序排在前面，
+/// 其余按最近更新时间倒序。
+pub fn sort_conversations(conversations: &mut [Conversation]) {
+    conversations.sort_by(|a, b| {
+        let pinned = |c: &Conversation| c.preferences.as_ref().map_or(false, |p| p.pinned);
+        let sort_order = |c: &Conversation| c.preferences.as_ref().and_then(|p| p.sort_order);
+
+        pinned(b).cmp(&pinned(a))
+            .then_with(|| match (sort_order(a), sort_order(b)) {
+                (Some(x), Some(y)) => x.cmp(&y),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+            .then_with(|| b.updated_at.cmp(&a.updated_at))
+    });
+}
+
+/// 判断是否应就新消息通知用户：自己发送的不通知，静音中的会话只通知 @提及
+pub fn should_notify(
+    preferences: Option<&ConversationPreferences>,
+    message: &Message,
+    user_id: &str,
+    now: DateTime<Utc>,
+) -> bool {
+    if message.sender_id == user_id {
+        return false;
+    }
+
+    let muted = preferences.map_or(false, |p| p.is_muted(now));
+    !muted || message.mentions.iter().any(|m| m.user_id == user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::models::{ConversationType, Mention, MessageType};
+    use chrono::Duration;
+    use std::collections::HashMap;
+
+    fn conversation(id: &str, updated_minutes_ago: i64) -> Conversation {
+        let updated_at = Utc::now() - Duration::minutes(updated_minutes_ago);
+        Conversation {
+            id: id.to_string(),
+            name: None,
+            conversation_type: ConversationType::Group,
+            participants: vec!["alice".to_string(), "bob".to_string()],
+            created_at: updated_at,
+            updated_at,
+            last_message: None,
+            encryption_enabled: false,
+            unread_count: 0,
+            pinned_messages: Vec::new(),
+            message_ttl: None,
+            has_draft: false,
+            preferences: None,
+            roles: HashMap::new(),
+            group_policy: Default::default(),
+            description: None,
+            avatar_url: None,
+            direct_key: None,
+            sender_key_epoch: 0,
+        }
+    }
+
+    fn preferences(conversation_id: &str) -> ConversationPreferences {
+        ConversationPreferences::new("alice", conversation_id)
+    }
+
+    fn message(sender_id: &str, mentions: &[&str]) -> Message {
+        Message {
+            id: "m1".to_string(),
+            conversation_id: "c1".to_string(),
+            sender_id: sender_id.to_string(),
+            content: "hello".to_string(),
+            content_type: MessageType::Text,
+            timestamp: Utc::now(),
+            status: None,
+            receipts: HashMap::new(),
+            encrypted: false,
+            media_url: None,
+            edited_at: None,
+            revisions: Vec::new(),
+            deleted_for: Vec::new(),
+            retracted_at: None,
+            reply_to: None,
+            thread_root_id: None,
+            thread_reply_count: 0,
+            reply_preview: None,
+            forwarded_from: None,
+            mentions: mentions.iter()
+                .map(|u| Mention { user_id: u.to_string(), offset: 0, length: u.len() + 1 })
+                .collect(),
+            reactions: Vec::new(),
+            system_event: None,
+            expires_at: None,
+            reaction_counts: Vec::new(),
+            undecryptable: false,
+            key_changes: Vec::new(),
+        }
+    }
+
+    // 测试置顶优先，其次自定义顺序，最后按更新时间
+    #[test]
+    fn test_sort_conversations() {
+        let mut pinned = conversation("pinned", 60);
+        pinned.preferences = Some(ConversationPreferences { pinned: true, ..preferences("pinned") });
+
+        let mut ordered = conversation("ordered", 30);
+        ordered.preferences = Some(ConversationPreferences { sort_order: Some(1), ..preferences("ordered") });
+
+        let mut conversations = vec![
+            conversation("older", 20),
+            ordered,
+            conversation("newest", 1),
+            pinned,
+        ];
+        sort_conversations(&mut conversations);
+
+        let ids: Vec<&str> = conversations.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["pinned", "ordered", "newest", "older"]);
+    }
+
+    // 测试归档和静音过滤，过期的静音视为未静音
+    #[test]
+    fn test_filter_matches() {
+        let now = Utc::now();
+        let archived = ConversationPreferences { archived: true, ..preferences("c1") };
+        let muted = ConversationPreferences {
+            muted: true,
+            muted_until: Some(now + Duration::hours(1)),
+            ..preferences("c2")
+        };
+        let mute_expired = ConversationPreferences {
+            muted: true,
+            muted_until: Some(now - Duration::hours(1)),
+            ..preferences("c3")
+        };
+
+        let archived_only = ConversationFilter { archived: Some(true), ..Default::default() };
+        assert!(archived_only.matches(Some(&archived), now));
+        assert!(!archived_only.matches(None, now));
+
+        let exclude_muted = ConversationFilter { muted: Some(false), ..Default::default() };
+        assert!(!exclude_muted.matches(Some(&muted), now));
+        assert!(exclude_muted.matches(Some(&mute_expired), now));
+        assert!(exclude_muted.matches(None, now));
+        
+        let hidden = ConversationPreferences { hidden: true, ..preferences("c4") };
+        assert!(!ConversationFilter::default().matches(Some(&hidden), now));
+    }
+
+    // 测试静音会话中的 @提及仍然通知
+    #[test]
+    fn test_mentions_bypass_mute() {
+        let now = Utc::now();
+        let muted = ConversationPreferences { muted: true, ..preferences("c1") };
+
+        assert!(should_notify(None, &message("bob", &[]), "alice", now));
+        assert!(!should_notify(Some(&muted), &message("bob", &[]), "alice", now));
+        assert!(should_notify(Some(&muted), &message("bob", &["alice"]), "alice", now));
+        assert!(!should_notify(None, &message("alice", &[]), "alice", now));
+    }
+}
@@ -0,0 +1,296 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::models::{Message, MessageType};
+use crate::error::Error;
+
+/// 片段中关键词前后保留的字符数
+const SNIPPET_CONTEXT_CHARS: usize = 30;
+
+/// 消息搜索条件
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchQuery {
+    pub keyword: Option<String>,
+    pub conversation_id: Option<String>,
+    pub sender_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub content_type: Option<MessageType>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl SearchQuery {
+    /// 关键词按空白拆分为小写词项，所有词项都需命中
+    pub fn terms(&self) -> Vec<String> {
+        self.keyword.as_deref()
+            .unwrap_or("")
+            .split_whitespace()
+            .map(|t| t.to_lowercase())
+            .collect()
+    }
+
+    /// 检查消息是否满足关键词以外的过滤条件
+    pub fn matches_filters(&self, message: &Message) -> bool {
+        if let Some(sender_id) = &self.sender_id {
+            if &message.sender_id != sender_id {
+                return false;
+            }
+        }
+        if let Some(content_type) = &self.content_type {
+            if &message.content_type != content_type {
+                return false;
+            }
+        }
+        if let Some(from) = self.from {
+            if message.timestamp < from {
+                return false;
+            }
+        }
+        if let Some(to) = self.to {
+            if message.timestamp > to {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// 单条搜索结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub message: Message,
+    pub score: f64,
+    pub snippet: String,
+    // 片段中命中位置（字符偏移, 字符长度）
+    pub highlights: Vec<(usize, usize)>,
+}
+
+/// 分页的搜索结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    pub total: usize,
+}
+
+/// 计算内容与词项的相关度，未命中全部词项时返回 None
+pub fn score_content(content: &str, terms: &[String]) -> Option<f64> {
+    if terms.is_empty() {
+        return Some(0.0);
+    }
+
+    let lower = content.to_lowercase();
+    let mut score = 0.0;
+
+    for term in terms {
+        let occurrences = lower.matches(term.as_str()).count();
+        if occurrences == 0 {
+            return None;
+        }
+        // 多次出现有加分，但边际递减
+        score += 1.0 + (occurrences as f64).ln();
+    }
+
+    // 词项按原顺序连续出现时额外加分
+    if terms.len() > 1 && lower.contains(&terms.join(" ")) {
+        score += terms.len() as f64;
+    }
+
+    // 短消息中的命中更相关
+    let length_norm = 1.0 + (lower.chars().count() as f64 / 100.0);
+    Some(score / length_norm)
+}
+
+/// 生成首个命中位置附近的片段及其中的高亮位置
+pub fn build_snippet(content: &str, terms: &[String]) -> (String, Vec<(usize, usize)>) {
+    let chars: Vec<char> = content.chars().collect();
+    let lower: Vec<char> = content.to_lowercase().chars().collect();
+
+    // 大小写转换改变长度时（少数语言）无法对齐位置，退化为不高亮的开头片段
+    if chars.len() != lower.len() || terms.is_empty() {
+        let snippet: String = chars.iter().take(SNIPPET_CONTEXT_CHARS * 2).collect();
+        return (snippet, Vec::new());
+    }
+
+    let term_chars: Vec<Vec<char>> = terms.iter().map(|t| t.chars().collect()).collect();
+    let find_at = |pos: usize| {
+        term_chars.iter()
+            .filter(|t| !t.is_empty() && lower[pos..].starts_with(t))
+            .map(|t| t.len())
+            .max()
+    };
+
+    let first_match = (0..lower.len()).find(|&i| find_at(i).is_some()).unwrap_or(0);
+    let match_len = find_at(first_match).unwrap_or(0);
+    let start = first_match.saturating_sub(SNIPPET_CONTEXT_CHARS);
+    let end = (first_match + match_len + SNIPPET_CONTEXT_CHARS).min(chars.len());
+
+    let prefix = if start > 0 { "…" } else { "" };
+    let suffix = if end < chars.len() { "…" } else { "" };
+    let offset = prefix.chars().count();
+
+    let mut highlights = Vec::new();
+    let mut i = start;
+    while i < end {
+        match find_at(i) {
+            Some(len) => {
+                let len = len.min(end - i);
+                highlights.push((i - start + offset, len));
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+
+    let snippet = format!("{}{}{}", prefix, chars[start..end].iter().collect::<String>(), suffix);
+    (snippet, highlights)
+}
+
+/// 为候选消息打分，生成按相关度排序的结果
+pub fn rank_messages(messages: Vec<Message>, query: &SearchQuery) -> Vec<SearchHit> {
+    let terms = query.terms();
+
+    let mut hits: Vec<SearchHit> = messages.into_iter()
+        .filter(|m| query.matches_filters(m))
+        .filter_map(|message| {
+            let score = score_content(&message.content, &terms)?;
+            let (snippet, highlights) = build_snippet(&message.content, &terms);
+            Some(SearchHit { message, score, snippet, highlights })
+        })
+        .collect();
+
+    // 相关度优先，相同时较新的消息在前
+    hits.sort_by(|a, b| {
+        b.score.partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.message.timestamp.cmp(&a.message.timestamp))
+    });
+
+    hits
+}
+
+/// 本地搜索索引
+///
+/// 加密会话的内容在服务器上只有密文，无法检索。客户端在解密消息后把明文
+/// 放入此索引，仅保存在内存中，不落盘。
+pub struct LocalSearchIndex {
+    // 会话ID -> 消息ID -> 解密后的消息
+    messages: RwLock<HashMap<String, HashMap<String, Message>>>,
+}
+
+impl LocalSearchIndex {
+    pub fn new() -> Self {
+        Self {
+            messages: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// 加入或更新已解密的消息，撤回的消息从索引中移除
+    pub fn index_messages(&self, messages: &[Message]) -> Result<(), Error> {
+        let mut index = self.messages.write().map_err(|_|
+            Error::Internal("Failed to acquire write lock on search index".to_string()))?;
+
+        for message in messages {
+            let conversation = index.entry(message.conversation_id.clone())
+                .or_insert_with(HashMap::new);
+
+            if message.retracted_at.is_some() {
+                conversation.remove(&message.id);
+            } else {
+                conversation.insert(message.id.clone(), message.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_message(&self, conversation_id: &str, message_id: &str) -> Result<(), Error> {
+        let mut index = self.messages.write().map_err(|_|
+            Error::Internal("Failed to acquire write lock on search index".to_string()))?;
+
+        if let Some(conversation) = index.get_mut(conversation_id) {
+            conversation.remove(message_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_conversation(&self, conversation_id: &str) -> Result<(), Error> {
+        let mut index = self.messages.write().map_err(|_|
+            Error::Internal("Failed to acquire write lock on search index".to_string()))?;
+
+        index.remove(conversation_id);
+        Ok(())
+    }
+
+    /// 在指定会话中查找候选消息，排除用户已删除和已过期的消息
+    pub fn candidates(&self, conversation_ids: &[String], user_id: &str) -> Result<Vec<Message>, Error> {
+        let index = self.messages.read().map_err(|_|
+            Error::Internal("Failed to acquire read lock on search index".to_string()))?;
+
+        let now = Utc::now();
+        let candidates = conversation_ids.iter()
+            .filter_map(|id| index.get(id))
+            .flat_map(|conversation| conversation.values())
+            .filter(|m| !m.deleted_for.iter().any(|u| u == user_id) && !m.is_expired(now))
+            .cloned()
+            .collect();
+
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(keyword: &str) -> Vec<String> {
+        SearchQuery { keyword: Some(keyword.to_string()), ..Default::default() }.terms()
+    }
+
+    // 测试所有词项都需命中
+    #[test]
+    fn test_score_requires_all_terms() {
+        assert!(score_content("We decided to ship on Friday", &terms("ship friday")).is_some());
+        assert!(score_content("We decided to ship on Monday", &terms("ship friday")).is_none());
+    }
+
+    // 测试连续短语和短消息得分更高
+    #[test]
+    fn test_score_ranking() {
+        let phrase = score_content("release plan approved", &terms("release plan")).unwrap();
+        let scattered = score_content("plan for the release", &terms("release plan")).unwrap();
+        assert!(phrase > scattered, "Exact phrase should rank higher");
+
+        let short = score_content("budget", &terms("budget")).unwrap();
+        let long = score_content(&format!("budget {}", "x".repeat(300)), &terms("budget")).unwrap();
+        assert!(short > long, "Shorter message should rank higher");
+    }
+
+    // 测试片段截取和高亮位置
+    #[test]
+    fn test_snippet_highlights() {
+        let content = format!("{}最终决定使用 MongoDB 存储{}", "前".repeat(50), "后".repeat(50));
+        let (snippet, highlights) = build_snippet(&content, &terms("mongodb"));
+
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert_eq!(highlights.len(), 1);
+
+        let (start, len) = highlights[0];
+        let highlighted: String = snippet.chars().skip(start).take(len).collect();
+        assert_eq!(highlighted, "MongoDB");
+    }
+
+    // 测试没有关键词时不生成高亮
+    #[test]
+    fn test_snippet_without_terms() {
+        let (snippet, highlights) = build_snippet("hello world", &[]);
+        assert_eq!(snippet, "hello world");
+        assert!(highlights.is_empty());
+    }
+}
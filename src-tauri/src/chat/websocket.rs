@@ -41,6 +41,62 @@ impl Default for WebSocketConfig {
         }
     }
 }
+/// 聊天事件类型，与 newMessage 一起通过 WebSocket 推送
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChatEventType {
+    /// 消息被编辑
+    MessageEdited,
+    /// 消息被发送者撤回
+    MessageRetracted,
+    /// 消息的表情回应变化
+    MessageReaction,
+    /// 消息被置顶
+    MessagePinned,
+    /// 消息被取消置顶
+    MessageUnpinned,
+    /// 用户在消息中被提及（高优先级通知）
+    Mention,
+    /// 定时消息已由调度器发送
+    ScheduledMessageSent,
+    /// 群名称、简介或头像被修改
+    GroupInfoUpdated,
+    /// 群聊被解散，客户端应移除该会话
+    GroupDissolved,
+}
+
+/// 聊天事件，只携带元数据，客户端收到后再通过命令拉取最新内容
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatEvent {
+    pub message_type: ChatEventType,
+    pub sender_id: String,
+    pub conversation_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    pub data: serde_json::Value,
+    pub timestamp: String,
+}
+
+impl ChatEvent {
+    pub fn new(
+        message_type: ChatEventType,
+        sender_id: &str,
+        conversation_id: &str,
+        message_id: Option<&str>,
+        data: serde_json::Value,
+    ) -> Self {
+        Self {
+            message_type,
+            sender_id: sender_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            message_id: message_id.map(|id| id.to_string()),
+            data,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
 /// 本地消息缓存
 #[derive(Debug, Default)]
 pub struct MessageCache {
@@ -285,6 +341,15 @@ impl WebSocketClient {
         // 发送消息
         self.send_message(ws_message).await
     }
+
+    /// 发送聊天事件
+    pub async fn send_chat_event(&self, event: ChatEvent) -> Result<(), String> {
+        let ws_message = serde_json::to_string(&event)
+            .map_err(|e| format!("Failed to serialize chat event: {}", e))?;
+        
+        self.send_message(ws_message).await
+    }
+
     /// 获取当前状态
     pub async fn get_status(&self) -> ConnectionStatus {
         *self.status.read().await
@@ -376,6 +441,15 @@ impl WebSocketState {
             None => Err("WebSocket client not initialized".to_string()),
         }
     }
+
+    /// 发送聊天事件
+    pub async fn send_chat_event(&self, event: ChatEvent) -> Result<(), String> {
+        let client = self.client.lock().await;
+        match &*client {
+            Some(ws_client) => ws_client.send_chat_event(event).await,
+            None => Err("WebSocket client not initialized".to_string()),
+        }
+    }
     
     /// 保存缓存的消息到数据库
     pub async fn save_pending_messages(&self, db: &ChatDatabase) -> Result<(), String> {
@@ -401,6 +475,11 @@ impl WebSocketState {
                 content_type: message.content_type.clone(),
                 encrypted: message.encrypted,
                 media_url: message.media_url.clone(),
+                reply_to: message.reply_to.clone(),
+                thread_root_id: message.thread_root_id.clone(),
+                forwarded_from: message.forwarded_from.clone(),
+                mentions: message.mentions.clone(),
+                system_event: message.system_event.clone(),
             };
 
             if let Err(_) = db.save_message(new_message).await {
@@ -41,6 +41,17 @@ pub async fn ensure_indexes(db: &Database) -> Result<(), Box<dyn std::error::Err
         )
         .await?;
     
+    // Ensure only one direct conversation per pair of users
+    db.collection::<mongodb::bson::Document>("conversations")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "directKey": 1 })
+                .options(mongodb::options::IndexOptions::builder().unique(true).sparse(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    
     // Ensure indexes for the messages collection
     db.collection::<mongodb::bson::Document>("messages")
         .create_index(
@@ -51,6 +62,100 @@ pub async fn ensure_indexes(db: &Database) -> Result<(), Box<dyn std::error::Err
         )
         .await?;
     
+    // Ensure indexes for looking up messages that mention a user
+    db.collection::<mongodb::bson::Document>("messages")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "mentions.user_id": 1, "timestamp": -1 })
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure indexes for the expired message sweeper
+    db.collection::<mongodb::bson::Document>("messages")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "expires_at": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure indexes for the scheduled message dispatch queue
+    db.collection::<mongodb::bson::Document>("scheduled_messages")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "status": 1, "send_at": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure one draft per user per conversation
+    db.collection::<mongodb::bson::Document>("drafts")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "user_id": 1, "conversation_id": 1 })
+                .options(mongodb::options::IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure one preferences document per user per conversation
+    db.collection::<mongodb::bson::Document>("conversation_preferences")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "user_id": 1, "conversation_id": 1 })
+                .options(mongodb::options::IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure invite tokens are unique and invites can be listed per group
+    db.collection::<mongodb::bson::Document>("group_invites")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "token": 1 })
+                .options(mongodb::options::IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    
+    db.collection::<mongodb::bson::Document>("group_invites")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "conversation_id": 1, "created_at": -1 })
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure one key bundle per user per device
+    db.collection::<mongodb::bson::Document>("key_bundles")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "user_id": 1, "device_id": 1 })
+                .options(mongodb::options::IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    
+    // Ensure one sender key distribution per sender, recipient and key
+    db.collection::<mongodb::bson::Document>("sender_key_distributions")
+        .create_index(
+            mongodb::IndexModel::builder()
+                .keys(doc! { "conversation_id": 1, "recipient_id": 1, "sender_id": 1, "key_id": 1 })
+                .options(mongodb::options::IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+    
     // Ensure indexes for the chat_events collection (for offline messages)
     db.collection::<mongodb::bson::Document>("chat_events")
         .create_index(
@@ -72,6 +72,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     
     let db = runtime.block_on(db_init::init_database())?;
+    
+    // 索引创建失败不影响启动，但私聊去重等依赖唯一索引的约束会失效
+    if let Err(e) = runtime.block_on(db_init::ensure_indexes(&db)) {
+        tracing::error!("Failed to ensure database indexes: {}", e);
+    }
     let db_for_setup = db.clone();
     
     let result = tauri::Builder::default()
@@ -119,14 +124,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             chat_commands::get_conversations,
             chat_commands::create_conversation,
             chat_commands::get_conversation,
+            chat_commands::set_conversation_archived,
+            chat_commands::mute_conversation,
+            chat_commands::unmute_conversation,
+            chat_commands::pin_conversation,
+            chat_commands::set_conversation_sort_order,
+            chat_commands::should_notify_message,
+            chat_commands::set_message_ttl,
             chat_commands::send_message,
             chat_commands::get_messages,
+            chat_commands::get_thread,
+            chat_commands::get_mentions,
+            chat_commands::save_draft,
+            chat_commands::get_draft,
+            chat_commands::clear_draft,
+            chat_commands::schedule_message,
+            chat_commands::get_scheduled_messages,
+            chat_commands::update_scheduled_message,
+            chat_commands::cancel_scheduled_message,
+            chat_commands::search_messages,
+            chat_commands::forward_messages,
+            chat_commands::edit_message,
+            chat_commands::delete_message,
+            chat_commands::react_to_message,
+            chat_commands::remove_reaction,
+            chat_commands::pin_message,
+            chat_commands::unpin_message,
+            chat_commands::get_pinned_messages,
             chat_commands::mark_message_read,
             chat_commands::mark_conversation_read,
             chat_commands::mark_conversation_delivered,
+            chat_commands::get_message_receipts,
             chat_commands::create_group_chat,
             chat_commands::add_group_member,
             chat_commands::remove_group_member,
+            chat_commands::promote_member,
+            chat_commands::demote_member,
+            chat_commands::transfer_ownership,
+            chat_commands::update_group_policy,
+            chat_commands::update_group_info,
+            chat_commands::create_group_invite,
+            chat_commands::get_group_invites,
+            chat_commands::revoke_group_invite,
+            chat_commands::join_group_via_invite,
+            chat_commands::leave_group,
+            chat_commands::delete_conversation,
+            chat_commands::get_or_create_direct_conversation,
+            chat_commands::unlock_keystore,
+            chat_commands::lock_keystore,
+            chat_commands::publish_key_bundle,
+            chat_commands::get_key_bundle,
+            chat_commands::get_safety_number,
+            chat_commands::mark_peer_verified,
+            chat_commands::set_strict_verification,
             chat_commands::get_unread_count,
             chat_commands::get_online_participants,
             chat_commands::initialize_websocket,
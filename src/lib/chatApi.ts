@@ -53,6 +53,11 @@ export interface Message {
   media_url?: string;
 }
 
+export interface MessagesPage {
+  messages: Message[];
+  keyChanges: string[];
+}
+
 export interface Conversation {
   id: string;
   conversation_type: ConversationType;
@@ -205,7 +210,7 @@ export async function getMessages(
   conversation_id: string,
   limit?: number,
   before_id?: string
-): Promise<Message[]> {
+): Promise<MessagesPage> {
   const user = await getCurrentUser();
   if (!user) {
     throw new AuthenticationError('用户未登录');
@@ -216,7 +221,7 @@ export async function getMessages(
     throw new AuthenticationError('用户未登录');
   }
   
-  return invoke<Message[]>('get_messages', {
+  return invoke<MessagesPage>('get_messages', {
     token,
     conversation_id,
     user_id: user.id,